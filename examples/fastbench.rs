@@ -0,0 +1,154 @@
+// Copyright 2020 Joyent, Inc.
+
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use clap::{crate_version, value_t, App, Arg, ArgMatches};
+use serde_json::json;
+use tokio::codec::{Decoder, Encoder};
+
+use fast_rpc::protocol::{self, FastMessage, FastMessageData, FastRpc};
+
+static APP: &str = "fastbench";
+const DEFAULT_ITERATIONS: u32 = 1000;
+const BATCH_LEN: u32 = 2000;
+
+pub fn parse_opts<'a>(app: String) -> ArgMatches<'a> {
+    App::new(app)
+        .about("Measure the time taken to encode and decode Fast messages")
+        .version(crate_version!())
+        .arg(
+            Arg::with_name("iterations")
+                .help("Number of times to run each benchmark (Default: 1000)")
+                .long("iterations")
+                .short("n")
+                .takes_value(true),
+        )
+        .get_matches()
+}
+
+/// Returns the mean time taken by `f` over `iterations` calls. `f` is passed
+/// the input made by `setup` for that call, which is not included in the time.
+fn time<T, S, F>(iterations: u32, mut setup: S, mut f: F) -> Duration
+where
+    S: FnMut() -> T,
+    F: FnMut(T),
+{
+    let mut elapsed = Duration::from_secs(0);
+    for _ in 0..iterations {
+        let input = setup();
+        let start = Instant::now();
+        f(input);
+        elapsed += start.elapsed();
+    }
+    elapsed / iterations
+}
+
+/// Encode a response batch of `BATCH_LEN` DATA messages whose payloads range
+/// from a few bytes to a few hundred, as a handler returning the results of
+/// a listing might.
+fn bench_encode_batch(iterations: u32) {
+    let batch: Vec<FastMessage> = (0..BATCH_LEN)
+        .map(|i| {
+            let value = "x".repeat((i % 16 * 16) as usize);
+            FastMessage::data(
+                i,
+                FastMessageData::new(
+                    String::from("getobject"),
+                    json!([{ "id": i, "value": value }]),
+                ),
+            )
+        })
+        .collect();
+    let mut codec = FastRpc::new();
+    let mut buf = BytesMut::new();
+
+    let elapsed = time(
+        iterations,
+        || batch.clone(),
+        |batch| {
+            buf.clear();
+            codec.encode(batch, &mut buf).unwrap();
+        },
+    );
+    println!("encode {} message batch: {:?}", BATCH_LEN, elapsed);
+}
+
+/// Respond to an echo request carrying a few hundred bytes of data, first by
+/// decoding it and encoding a reply as a response handler would, then by
+/// answering with `echo_reply` from a codec that keeps raw payloads, and then
+/// by copying the request frame with `protocol::encode_echo`.
+fn bench_echo(iterations: u32) {
+    let request = FastMessage::data(
+        1,
+        FastMessageData::new(
+            String::from("echo"),
+            json!([{ "key": "/stor/object", "value": "x".repeat(360) }]),
+        ),
+    );
+    let mut codec = FastRpc::new();
+    let mut frame = BytesMut::new();
+    codec.encode(vec![request], &mut frame).unwrap();
+    let mut buf = BytesMut::new();
+
+    let elapsed = time(
+        iterations,
+        || frame.clone(),
+        |mut frame| {
+            buf.clear();
+            let requests = codec.decode(&mut frame).unwrap().unwrap();
+            let responses = requests
+                .iter()
+                .flat_map(|msg| {
+                    vec![
+                        msg.reply(msg.data.d.clone()),
+                        FastMessage::end(msg.id, msg.data.m.name.clone()),
+                    ]
+                })
+                .collect();
+            codec.encode(responses, &mut buf).unwrap();
+        },
+    );
+    println!("echo by decoding and encoding: {:?}", elapsed);
+
+    let mut raw_codec = FastRpc::new().with_raw_payloads(true);
+    let elapsed = time(
+        iterations,
+        || frame.clone(),
+        |mut frame| {
+            buf.clear();
+            let requests = raw_codec.decode(&mut frame).unwrap().unwrap();
+            let responses = requests
+                .iter()
+                .flat_map(|msg| {
+                    vec![
+                        msg.echo_reply(),
+                        FastMessage::end(msg.id, msg.data.m.name.clone()),
+                    ]
+                })
+                .collect();
+            raw_codec.encode(responses, &mut buf).unwrap();
+        },
+    );
+    println!("echo with echo_reply: {:?}", elapsed);
+
+    let elapsed = time(
+        iterations,
+        || (),
+        |()| {
+            buf.clear();
+            protocol::encode_echo(&frame, &mut buf).unwrap();
+        },
+    );
+    println!("echo with encode_echo: {:?}", elapsed);
+}
+
+fn main() {
+    let matches = parse_opts(APP.to_string());
+    let iterations = value_t!(matches, "iterations", u32)
+        .unwrap_or(DEFAULT_ITERATIONS)
+        .max(1);
+
+    bench_encode_batch(iterations);
+    bench_echo(iterations);
+}
@@ -1,7 +1,6 @@
 // Copyright 2020 Joyent, Inc.
 
 use std::io::Error;
-use std::net::{SocketAddr, TcpStream};
 use std::process;
 
 use clap::{crate_version, value_t, App, Arg, ArgMatches};
@@ -10,11 +9,11 @@ use serde_json::Value;
 use fast_rpc::client;
 use fast_rpc::protocol::{FastMessage, FastMessageId};
 
-static APP: &'static str = "fastcall";
-static DEFAULT_HOST: &'static str = "127.0.0.1";
-const DEFAULT_PORT: u32 = 2030;
+static APP: &str = "fastcall";
+static DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 2030;
 
-pub fn parse_opts<'a, 'b>(app: String) -> ArgMatches<'a> {
+pub fn parse_opts<'a>(app: String) -> ArgMatches<'a> {
     App::new(app)
         .about("Command-line tool for making a node-fast RPC method call")
         .version(crate_version!())
@@ -72,7 +71,7 @@ fn response_handler(msg: &FastMessage) -> Result<(), Error> {
         "date" | "echo" | "yes" | "getobject" | "putobject" => {
             stdout_handler(msg)
         }
-        _ => println!("Received {} response", msg.data.m.name),
+        _ => println!("Received {}", msg.describe()),
     }
 
     Ok(())
@@ -81,18 +80,7 @@ fn response_handler(msg: &FastMessage) -> Result<(), Error> {
 fn main() {
     let matches = parse_opts(APP.to_string());
     let host = String::from(matches.value_of("host").unwrap_or(DEFAULT_HOST));
-    let port = value_t!(matches, "port", u32).unwrap_or(DEFAULT_PORT);
-    let addr = [host, String::from(":"), port.to_string()]
-        .concat()
-        .parse::<SocketAddr>()
-        .unwrap_or_else(|e| {
-            eprintln!(
-                "Failed to parse host and port as valid socket address: \
-                 {}",
-                e
-            );
-            process::exit(1)
-        });
+    let port = value_t!(matches, "port", u16).unwrap_or(DEFAULT_PORT);
     let method =
         String::from(matches.value_of("method").unwrap_or_else(|| {
             eprintln!("Failed to parse method argument as String");
@@ -100,10 +88,11 @@ fn main() {
         }));
     let args = value_t!(matches, "args", Value).unwrap_or_else(|e| e.exit());
 
-    let mut stream = TcpStream::connect(&addr).unwrap_or_else(|e| {
-        eprintln!("Failed to connect to server: {}", e);
-        process::exit(1)
-    });
+    let mut stream =
+        client::connect((host.as_str(), port)).unwrap_or_else(|e| {
+            eprintln!("Failed to connect to server: {}", e);
+            process::exit(1)
+        });
 
     let mut msg_id = FastMessageId::new();
 
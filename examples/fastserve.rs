@@ -1,7 +1,7 @@
 // Copyright 2020 Joyent, Inc.
 
 use std::env;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::net::SocketAddr;
 use std::sync::Mutex;
 use std::thread;
@@ -49,7 +49,7 @@ impl DatePayload {
 }
 
 fn other_error(msg: &str) -> Error {
-    Error::new(ErrorKind::Other, String::from(msg))
+    Error::other(String::from(msg))
 }
 
 fn date_handler(
@@ -61,10 +61,7 @@ fn date_handler(
     let date_payload_result = serde_json::to_value(vec![DatePayload::new()]);
     match date_payload_result {
         Ok(date_payload) => {
-            response.push(FastMessage::data(
-                msg.id,
-                FastMessageData::new(msg.data.m.name.clone(), date_payload),
-            ));
+            response.push(msg.reply(date_payload));
             Ok(response)
         }
         Err(_) => Err(other_error(
@@ -79,7 +76,7 @@ fn echo_handler(
     log: &Logger,
 ) -> Result<Vec<FastMessage>, Error> {
     debug!(log, "handling echo function request");
-    response.push(FastMessage::data(msg.id, msg.data.clone()));
+    response.push(msg.reply(msg.data.d.clone()));
     Ok(response)
 }
 
@@ -181,24 +178,6 @@ fn fastbench_handler(
     }
 }
 
-fn msg_handler(
-    msg: &FastMessage,
-    log: &Logger,
-) -> Result<Vec<FastMessage>, Error> {
-    let response: Vec<FastMessage> = vec![];
-
-    match msg.data.m.name.as_str() {
-        "date" => date_handler(msg, response, &log),
-        "echo" => echo_handler(msg, response, &log),
-        "yes" => yes_handler(msg, response, &log),
-        "fastbench" => fastbench_handler(msg, response, &log),
-        _ => Err(Error::new(
-            ErrorKind::Other,
-            format!("Unsupported function: {}", msg.data.m.name),
-        )),
-    }
-}
-
 fn main() {
     let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
     let root_log = Logger::root(
@@ -212,6 +191,12 @@ fn main() {
     let listener = TcpListener::bind(&addr).expect("failed to bind");
     info!(root_log, "listening for fast requests"; "address" => addr);
 
+    let router = server::Router::new()
+        .add("date", |msg, log| date_handler(msg, vec![], log))
+        .add("echo", |msg, log| echo_handler(msg, vec![], log))
+        .add("yes", |msg, log| yes_handler(msg, vec![], log))
+        .add("fastbench", |msg, log| fastbench_handler(msg, vec![], log));
+
     tokio::run({
         let process_log = root_log.clone();
         let err_log = root_log.clone();
@@ -219,7 +204,12 @@ fn main() {
             .incoming()
             .map_err(move |e| error!(&err_log, "failed to accept socket"; "err" => %e))
             .for_each(move |socket| {
-                let task = server::make_task(socket, msg_handler, Some(&process_log));
+                let router = router.clone();
+                let task = server::make_task(
+                    socket,
+                    move |msg: &FastMessage, log: &Logger| router.handle(msg, log),
+                    Some(&process_log),
+                );
                 tokio::spawn(task)
             })
     });
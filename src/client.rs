@@ -2,26 +2,130 @@
 
 //! This module provides the interface for creating Fast clients.
 
+use std::collections::HashMap;
+use std::fmt;
 use std::io::{Error, ErrorKind};
-use std::net::TcpStream;
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use futures::sync::{mpsc, oneshot};
+use num::ToPrimitive;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use tokio::codec::{Decoder, Framed};
 use tokio::prelude::*;
+#[cfg(unix)]
+use tokio_uds::UnixStream;
 
 use crate::protocol;
 use crate::protocol::{
     FastMessage, FastMessageData, FastMessageId, FastMessageServerError,
-    FastMessageStatus, FastParseError,
+    FastMessageStatus, FastMessageType, FastRpc, FrameState, IdAllocator,
 };
 
-enum BufferAction {
-    Keep,
-    Trim(usize),
-    Done,
+/// Connect to a Fast server at `addr`, which may be a host name to be resolved
+/// using DNS. If the name resolves to more than one address, for example to
+/// both an IPv4 and an IPv6 address, each address is tried in turn until a
+/// connection is established. If none of them can be connected to, the error
+/// from the last attempt is returned.
+pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream, Error> {
+    connect_any(addr, TcpStream::connect)
 }
 
-/// Send a message to a Fast server using the provided TCP stream.
+/// Connect to a Fast server at `addr`, waiting at most `connect_timeout` for
+/// the connection to each address `addr` resolves to be established, as
+/// described for `connect`. The returned stream has the provided read and
+/// write timeouts set so that `send` and `receive` fail with an error of kind
+/// `TimedOut` rather than blocking forever on an unresponsive server.
+pub fn connect_with_timeouts<A: ToSocketAddrs>(
+    addr: A,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+) -> Result<TcpStream, Error> {
+    let stream = connect_any(addr, |addr| {
+        TcpStream::connect_timeout(&addr, connect_timeout)
+    })?;
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(write_timeout))?;
+    Ok(stream)
+}
+
+fn connect_any<A, F>(addr: A, mut connect: F) -> Result<TcpStream, Error>
+where
+    A: ToSocketAddrs,
+    F: FnMut(SocketAddr) -> Result<TcpStream, Error>,
+{
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// The error wrapped by an error returned when sending a request fails because
+/// the connection was reset or closed by the server. The request was not
+/// delivered, so it is safe to reconnect and send it again. See
+/// `is_connection_error`.
+#[derive(Debug)]
+pub struct ConnectionError(Error);
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "connection lost: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Returns true if `err` was returned by a send function because the
+/// connection was reset or closed by the server.
+pub fn is_connection_error(err: &Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<ConnectionError>())
+}
+
+/// Returns the error reported by the server if `err` was returned because the
+/// server responded with an `ERROR` message. This allows callers to act on the
+/// error name, for example retrying a `ServiceUnavailable` error but not a
+/// `BucketNotFound` error.
+pub fn server_error(err: &Error) -> Option<&FastMessageServerError> {
+    err.get_ref()?.downcast_ref::<FastMessageServerError>()
+}
+
+/// Wrap errors indicating that the connection was lost in a `ConnectionError`,
+/// preserving the error kind. Other errors are returned unchanged.
+fn map_send_error(err: Error) -> Error {
+    match err.kind() {
+        ErrorKind::BrokenPipe
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted => {
+            Error::new(err.kind(), ConnectionError(err))
+        }
+        _ => err,
+    }
+}
+
+/// Send a message to a Fast server using the provided TCP stream. If the
+/// connection was reset or closed by the server the returned error satisfies
+/// `is_connection_error`.
 pub fn send(
     method: String,
     args: Value,
@@ -31,127 +135,1872 @@ pub fn send(
     // It is safe to call unwrap on the msg_id iterator because the
     // implementation of Iterator for FastMessageId will only ever return
     // Some(id). The Option return type is required by the Iterator trait.
-    let msg = FastMessage::data(
-        msg_id.next().unwrap() as u32,
-        FastMessageData::new(method, args),
-    );
+    let id = Iterator::next(msg_id).unwrap() as u32;
+    send_with_id(method, args, id, stream)
+}
+
+fn send_with_id(
+    method: String,
+    args: Value,
+    id: u32,
+    stream: &mut TcpStream,
+) -> Result<usize, Error> {
+    let msg = FastMessage::data(id, FastMessageData::new(method, args));
+    let mut write_buf = BytesMut::new();
+    protocol::encode_msg(&msg, &mut write_buf)?;
+    stream.write_all(&write_buf).map_err(map_send_error)?;
+    Ok(write_buf.len())
+}
+
+/// Send a request to a Fast server whose `d` array is produced by `items`,
+/// without collecting the items in memory first. The items are sent as they
+/// are produced in one or more `DATA` messages that share a message id, each
+/// holding as many items as fit in `max_payload_size` bytes of encoded JSON
+/// (an item larger than that is sent on its own). All but the last message
+/// are marked as continued, as described for `FastMessage::data_continued`, so
+/// the server handles them as a single request. Returns the number of bytes
+/// sent.
+pub fn send_stream<S>(
+    method: String,
+    items: S,
+    max_payload_size: usize,
+    msg_id: &mut FastMessageId,
+    stream: &mut TcpStream,
+) -> Result<usize, Error>
+where
+    S: Stream<Item = Value, Error = Error>,
+{
+    let id = Iterator::next(msg_id).unwrap() as u32;
+    let mut pending = Vec::new();
+    let mut pending_size = 0;
+    let mut total_bytes = 0;
+
+    for item in items.wait() {
+        let item = item?;
+        let item_size = serde_json::to_vec(&item).map_err(Error::other)?.len();
+        if !pending.is_empty() && pending_size + item_size > max_payload_size {
+            let d = Value::Array(mem::take(&mut pending));
+            let data = FastMessageData::new(method.clone(), d);
+            let msg = FastMessage::data_continued(id, data);
+            total_bytes += send_msg(&msg, stream)?;
+            pending_size = 0;
+        }
+        pending.push(item);
+        pending_size += item_size;
+    }
+
+    let data = FastMessageData::new(method, Value::Array(pending));
+    total_bytes += send_msg(&FastMessage::data(id, data), stream)?;
+    Ok(total_bytes)
+}
+
+fn send_msg(msg: &FastMessage, stream: &mut TcpStream) -> Result<usize, Error> {
     let mut write_buf = BytesMut::new();
-    match protocol::encode_msg(&msg, &mut write_buf) {
-        Ok(_) => stream.write(write_buf.as_ref()),
-        Err(err_str) => Err(Error::new(ErrorKind::Other, err_str)),
+    protocol::encode_msg(msg, &mut write_buf)?;
+    stream.write_all(&write_buf).map_err(map_send_error)?;
+    Ok(write_buf.len())
+}
+
+/// A request whose data payload has been serialized ahead of time so that it
+/// can be sent any number of times, to one or more servers, without being
+/// serialized again. Because the payload is fixed when the request is
+/// prepared, the `m.uts` timestamp of every message sent from it is the time
+/// the request was prepared.
+pub struct PreparedRequest {
+    payload: Bytes,
+    crc: u32,
+}
+
+impl PreparedRequest {
+    /// Creates a new PreparedRequest for an RPC call of `method` with the
+    /// provided arguments.
+    pub fn new(method: String, args: Value) -> Result<Self, Error> {
+        let data = FastMessageData::new(method, args);
+        let payload = serde_json::to_vec(&data).map_err(Error::other)?;
+        let crc = protocol::compute_crc(&payload);
+
+        Ok(PreparedRequest {
+            payload: Bytes::from(payload),
+            crc,
+        })
     }
 }
 
+/// Send a prepared request to a Fast server using the provided TCP stream. Only
+/// the message header is generated, using the next id from `msg_id`.
+pub fn send_prepared(
+    prepared: &PreparedRequest,
+    msg_id: &mut FastMessageId,
+    stream: &mut TcpStream,
+) -> Result<usize, Error> {
+    let id = Iterator::next(msg_id).unwrap() as u32;
+    let frame_len = protocol::FP_HEADER_SZ + prepared.payload.len();
+    let mut write_buf = BytesMut::with_capacity(frame_len);
+    protocol::encode_header(
+        FastMessageType::Json.to_u8().unwrap(),
+        FastMessageStatus::Data.to_u8().unwrap(),
+        id,
+        prepared.crc,
+        prepared.payload.len(),
+        &mut write_buf,
+    );
+    write_buf.extend_from_slice(&prepared.payload);
+
+    stream.write_all(&write_buf).map_err(map_send_error)?;
+    Ok(frame_len)
+}
+
+/// Send a pre-encoded Fast message frame to a Fast server using the provided
+/// TCP stream. The bytes are written verbatim after checking that they contain
+/// exactly one complete frame with a valid header.
+pub fn send_raw(
+    frame_bytes: &[u8],
+    stream: &mut TcpStream,
+) -> Result<usize, Error> {
+    match FastMessage::frame_state(frame_bytes) {
+        FrameState::Complete { len } if len == frame_bytes.len() => {
+            FastMessage::parse_header(frame_bytes)?;
+            stream.write_all(frame_bytes).map_err(map_send_error)?;
+            Ok(len)
+        }
+        FrameState::Complete { .. } => {
+            let msg = "Unable to send frame: buffer contains trailing bytes";
+            Err(Error::new(ErrorKind::InvalidInput, msg))
+        }
+        _ => {
+            let msg = "Unable to send frame: buffer is not a complete frame";
+            Err(Error::new(ErrorKind::InvalidInput, msg))
+        }
+    }
+}
+
+/// The maximum number of bytes requested from the stream in a single read.
+const MAX_READ_SZ: usize = 1024 * 1024;
+
+/// The number of bytes requested from the stream beyond those needed to
+/// complete the message being read, so that the messages following it can be
+/// read in the same call.
+const READ_AHEAD_SZ: usize = 8 * 1024;
+
 /// Receive a message from a Fast server on the provided TCP stream and call
-/// `response_handler` on the response.
+/// `response_handler` on the response. The handler is called for each `DATA`
+/// message and for an `END` message that carries data. As many bytes as are
+/// available are read at once, and any received past the end of the response
+/// are discarded. To keep using the connection after the response handler
+/// fails part way through a response, use `receive_buffered` and
+/// `drain_to_boundary` instead.
 pub fn receive<F>(
     stream: &mut TcpStream,
-    mut response_handler: F,
+    response_handler: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    receive_from(stream, response_handler, false)
+}
+
+/// Receive a message from a Fast server on the provided TCP stream and call
+/// `response_handler` on the response, as for `receive`. Bytes read past the
+/// end of the response, or past the message on which the response handler
+/// failed, are kept in `buf` and are handled before anything more is read
+/// from the stream, so the same buffer must be passed to every call to
+/// `receive_buffered` or `drain_to_boundary` for the stream.
+pub fn receive_buffered<F>(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    response_handler: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    receive_response(stream, buf, response_handler, false, |_| Ok(()))
+        .map(|(total_bytes, _end)| total_bytes)
+}
+
+/// Receive a message from a Fast server on the provided TCP stream and call
+/// `response_handler` on the response. If a message has been partially
+/// received and no further bytes arrive for `stall_timeout`, an error of kind
+/// `TimedOut` is returned rather than waiting indefinitely for the rest of the
+/// message. There is no limit on how long to wait for the start of a message.
+pub fn receive_with_stall_timeout<F>(
+    stream: &mut TcpStream,
+    response_handler: F,
+    stall_timeout: Duration,
+) -> Result<usize, Error>
+where
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    with_read_timeout(stream, stall_timeout, |stream| {
+        receive_from(stream, response_handler, true)
+    })
+}
+
+/// Receive a message from a Fast server on the provided TCP stream and call
+/// `response_handler` on the response. If the complete response has not
+/// arrived within `timeout` of the call, an error of kind `TimedOut` is
+/// returned. The time remaining is worked out again before each read, and the
+/// bytes already received are kept while waiting for more, so a response that
+/// is slow but still arriving is only abandoned once the whole `timeout` has
+/// passed. The stream's own read timeout is restored before returning.
+pub fn receive_timeout<F>(
+    stream: &mut TcpStream,
+    response_handler: F,
+    timeout: Duration,
+) -> Result<usize, Error>
+where
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    let deadline = Instant::now() + timeout;
+    with_read_timeout(stream, timeout, |stream| {
+        let prepare_read = |stream: &mut TcpStream| {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(response_timed_out());
+            }
+            stream.set_read_timeout(Some(deadline - now))
+        };
+        receive_response(
+            stream,
+            &mut Vec::new(),
+            response_handler,
+            false,
+            prepare_read,
+        )
+        .map(|(total_bytes, _end)| total_bytes)
+    })
+}
+
+/// Call `f` with the read timeout of `stream` set to `timeout`, restoring the
+/// stream's own read timeout afterwards.
+fn with_read_timeout<T, F>(
+    stream: &mut TcpStream,
+    timeout: Duration,
+    f: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(&mut TcpStream) -> Result<T, Error>,
+{
+    let read_timeout = stream.read_timeout()?;
+    stream.set_read_timeout(Some(timeout))?;
+    let result = f(stream);
+    stream.set_read_timeout(read_timeout)?;
+    result
+}
+
+fn response_timed_out() -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        "Timed out waiting for a response from server",
+    )
+}
+
+fn is_timeout(err: &Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
+fn receive_from<R, F>(
+    reader: &mut R,
+    response_handler: F,
+    stall_detection: bool,
 ) -> Result<usize, Error>
 where
+    R: Read,
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    receive_response(
+        reader,
+        &mut Vec::new(),
+        response_handler,
+        stall_detection,
+        |_| Ok(()),
+    )
+    .map(|(total_bytes, _end)| total_bytes)
+}
+
+/// Receive a response as for `receive_from`, starting with any messages
+/// already in `buf` and leaving the bytes that follow the response there.
+/// `prepare_read` is called before each read from `reader`, for example to
+/// set its read timeout, and its error ends the response. Also returns the
+/// `END` message that completed the response.
+fn receive_response<R, F, P>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    mut response_handler: F,
+    stall_detection: bool,
+    mut prepare_read: P,
+) -> Result<(usize, Option<FastMessage>), Error>
+where
+    R: Read,
     F: FnMut(&FastMessage) -> Result<(), Error>,
+    P: FnMut(&mut R) -> Result<(), Error>,
 {
-    let mut stream_end = false;
-    let mut msg_buf: Vec<u8> = Vec::new();
     let mut total_bytes = 0;
-    let mut result = Ok(total_bytes);
 
-    while !stream_end {
-        let mut read_buf = [0; 128];
-        match stream.read(&mut read_buf) {
+    loop {
+        let buffered = buf.len();
+        match handle_messages(buf, &mut response_handler) {
+            Ok(Some(end)) => {
+                total_bytes += buffered - buf.len();
+                return Ok((total_bytes, Some(end)));
+            }
+            Ok(None) => total_bytes += buffered - buf.len(),
+            Err(e) => return Err(e),
+        }
+
+        // Ask for the rest of the message being read and then some, so that
+        // a response made up of many small messages takes few reads.
+        let missing = match FastMessage::frame_state(buf) {
+            FrameState::NeedHeader { missing }
+            | FrameState::NeedBody { missing } => missing,
+            FrameState::Complete { .. } => 0,
+        };
+        let read_size = (missing + READ_AHEAD_SZ).min(MAX_READ_SZ);
+        prepare_read(reader)?;
+        let buffered = buf.len();
+        buf.resize(buffered + read_size, 0);
+
+        match reader.read(&mut buf[buffered..]) {
             Ok(0) => {
-                result = Err(Error::new(
+                buf.truncate(buffered);
+                return Err(Error::new(
                     ErrorKind::UnexpectedEof,
                     "Received EOF (0 bytes) from server",
                 ));
-                stream_end = true;
-            }
-            Ok(byte_count) => {
-                total_bytes += byte_count;
-                msg_buf.extend_from_slice(&read_buf[0..byte_count]);
-                match parse_and_handle_messages(
-                    msg_buf.as_slice(),
-                    &mut response_handler,
-                ) {
-                    Ok(BufferAction::Keep) => (),
-                    Ok(BufferAction::Trim(rest_offset)) => {
-                        let truncate_bytes = msg_buf.len() - rest_offset;
-                        msg_buf.rotate_left(rest_offset);
-                        msg_buf.truncate(truncate_bytes);
-                        result = Ok(total_bytes);
-                    }
-                    Ok(BufferAction::Done) => stream_end = true,
-                    Err(e) => {
-                        result = Err(e);
-                        stream_end = true
-                    }
+            }
+            Ok(byte_count) => buf.truncate(buffered + byte_count),
+            Err(ref err) if stall_detection && is_timeout(err) => {
+                // A read timeout only indicates a stall if part of a message
+                // has been received.
+                buf.truncate(buffered);
+                if !buf.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        "StalledFrame: no bytes received from server for a \
+                         partially received message",
+                    ));
                 }
             }
+            Err(ref err) if is_timeout(err) => {
+                // Depending on the platform a read timeout is reported as
+                // either WouldBlock or TimedOut.
+                buf.truncate(buffered);
+                return Err(response_timed_out());
+            }
             Err(err) => {
-                result = Err(err);
-                stream_end = true
+                buf.truncate(buffered);
+                return Err(err);
             }
         }
     }
-    result
 }
 
-fn parse_and_handle_messages<F>(
-    read_buf: &[u8],
-    response_handler: &mut F,
-) -> Result<BufferAction, Error>
+/// Receive a message from a Fast server on the provided TCP stream and call
+/// `response_handler` on the response as for `receive`. Returns the resume
+/// token carried by the `END` message that completed the response, if any,
+/// which can be passed back to the server to continue the response. See
+/// `FastMessage::end_with_resume`.
+pub fn receive_resumable<F>(
+    stream: &mut TcpStream,
+    response_handler: F,
+) -> Result<Option<Value>, Error>
 where
     F: FnMut(&FastMessage) -> Result<(), Error>,
 {
-    let mut offset = 0;
-    let mut done = false;
-
-    let mut result = Ok(BufferAction::Keep);
-
-    while !done {
-        match FastMessage::parse(&read_buf[offset..]) {
-            Ok(ref fm) if fm.status == FastMessageStatus::End => {
-                result = Ok(BufferAction::Done);
-                done = true;
-            }
-            Ok(fm) => {
-                offset += fm.msg_size.unwrap();
-                match fm.status {
-                    FastMessageStatus::Data | FastMessageStatus::End => {
-                        if let Err(e) = response_handler(&fm) {
-                            result = Err(e);
-                            done = true;
-                        } else {
-                            result = Ok(BufferAction::Trim(offset));
-                        }
-                    }
-                    FastMessageStatus::Error => {
-                        result = serde_json::from_value(fm.data.d)
-                            .or_else(|_| Err(unspecified_error().into()))
-                            .and_then(
-                                |e: FastMessageServerError| Err(e.into()),
-                            );
-
-                        done = true;
-                    }
+    let (_, end) = receive_response(
+        stream,
+        &mut Vec::new(),
+        response_handler,
+        false,
+        |_| Ok(()),
+    )?;
+    Ok(end.and_then(|msg| msg.resume_token().cloned()))
+}
+
+/// Read and discard the rest of a response that was abandoned part way through,
+/// for example because a response handler passed to `receive_buffered`
+/// returned an error, up to and including the `END` or `ERROR` message that
+/// completes it. This leaves the stream ready for the next request. `buf` must
+/// be the buffer passed to `receive_buffered` for the stream. Messages already
+/// in it are discarded first, and reads from the stream then stop at the end
+/// of each message, so nothing past the end of the response is read. Returns
+/// the number of messages discarded.
+pub fn drain_to_boundary(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+) -> Result<usize, Error> {
+    let mut discarded = 0;
+    loop {
+        let missing = match FastMessage::frame_state(buf) {
+            FrameState::Complete { len } => {
+                let msg = FastMessage::parse(&buf[..len])?;
+                buf.drain(..len);
+                discarded += 1;
+                if msg.is_terminal() {
+                    return Ok(discarded);
                 }
+                continue;
             }
-            Err(FastParseError::NotEnoughBytes(_bytes)) => {
-                done = true;
+            FrameState::NeedHeader { missing }
+            | FrameState::NeedBody { missing } => missing,
+        };
+        let buffered = buf.len();
+        buf.resize(buffered + missing, 0);
+        if let Err(e) = stream.read_exact(&mut buf[buffered..]) {
+            buf.truncate(buffered);
+            return Err(e);
+        }
+    }
+}
+
+/// Send a request to a Fast server and receive the complete response, merging
+/// the data payloads of all response messages into a single `FastMessageData`
+/// carrying the metadata for `method`. Array payloads are concatenated in the
+/// order they are received and any other payload value is appended as a single
+/// element.
+pub fn call_merged(
+    method: String,
+    args: Value,
+    msg_id: &mut FastMessageId,
+    stream: &mut TcpStream,
+) -> Result<FastMessageData, Error> {
+    let mut values = Vec::new();
+
+    send(method.clone(), args, msg_id, stream)?;
+    receive(stream, |msg| {
+        match msg.data.d {
+            Value::Array(ref vs) => values.extend(vs.iter().cloned()),
+            ref v => values.push(v.clone()),
+        }
+        Ok(())
+    })?;
+
+    Ok(FastMessageData::new(method, Value::Array(values)))
+}
+
+/// Describes how much of a response was received by `receive_with_outcome`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReceiveOutcome {
+    /// The number of `DATA` messages passed to the response handler
+    pub data_frames: usize,
+    /// True if the connection was closed before an `END` or `ERROR` message
+    /// completed the response, meaning the `DATA` messages received may not be
+    /// the entire response.
+    pub incomplete: bool,
+}
+
+/// Receive a message from a Fast server on the provided TCP stream and call
+/// `response_handler` on the response. Unlike `receive`, a connection closed by
+/// the server before the response completes is not treated as an error. The
+/// returned `ReceiveOutcome` instead reports that the response is incomplete
+/// along with the number of `DATA` messages that were delivered to the handler.
+pub fn receive_with_outcome<F>(
+    stream: &mut TcpStream,
+    mut response_handler: F,
+) -> Result<ReceiveOutcome, Error>
+where
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    let mut data_frames = 0;
+    let result = receive(stream, |msg| {
+        if msg.is_data() {
+            data_frames += 1;
+        }
+        response_handler(msg)
+    });
+
+    match result {
+        Ok(_) => Ok(ReceiveOutcome {
+            data_frames,
+            incomplete: false,
+        }),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+            Ok(ReceiveOutcome {
+                data_frames,
+                incomplete: true,
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A blocking Fast client that owns a TCP stream and enforces that only one
+/// request is outstanding on the connection at a time. Sending a new request
+/// before the response to the previous one has been received would interleave
+/// responses on the stream, so `send` returns an error in that case.
+pub struct BlockingFastClient {
+    stream: TcpStream,
+    id_allocator: Box<dyn IdAllocator>,
+    method_prefix: Option<String>,
+    outstanding: bool,
+    read_buf: Vec<u8>,
+}
+
+impl BlockingFastClient {
+    /// Creates a new `BlockingFastClient` using the provided TCP stream.
+    /// Message ids are allocated sequentially.
+    pub fn new(stream: TcpStream) -> Self {
+        BlockingFastClient::with_id_allocator(
+            stream,
+            Box::new(FastMessageId::new()),
+        )
+    }
+
+    /// Creates a new `BlockingFastClient` using the provided TCP stream and
+    /// message id allocation strategy.
+    pub fn with_id_allocator(
+        stream: TcpStream,
+        id_allocator: Box<dyn IdAllocator>,
+    ) -> Self {
+        BlockingFastClient {
+            stream,
+            id_allocator,
+            method_prefix: None,
+            outstanding: false,
+            read_buf: Vec::new(),
+        }
+    }
+
+    /// Prepend `prefix` to the method name of every request sent by this
+    /// client. For example, with the prefix `"bucket."` a request for the
+    /// method `"get"` is sent as `"bucket.get"`.
+    pub fn method_prefix(mut self, prefix: &str) -> Self {
+        self.method_prefix = Some(String::from(prefix));
+        self
+    }
+
+    /// Send a message to the Fast server. Returns an error without sending
+    /// anything if the response to a previous request has not yet been
+    /// received.
+    pub fn send(
+        &mut self,
+        method: String,
+        args: Value,
+    ) -> Result<usize, Error> {
+        if self.outstanding {
+            let msg = "Unable to send request: the response to the previous \
+                       request has not been received";
+            return Err(Error::other(msg));
+        }
+
+        let method = match &self.method_prefix {
+            Some(prefix) => [prefix.as_str(), &method].concat(),
+            None => method,
+        };
+        let id = self.id_allocator.next();
+        let result = send_with_id(method, args, id, &mut self.stream);
+        self.outstanding = result.is_ok();
+        result
+    }
+
+    /// Receive the response to the outstanding request and call
+    /// `response_handler` on each response message. Returns an error if there
+    /// is no outstanding request. The client is ready to send another request
+    /// once the response has completed with an `END` or `ERROR` message. If
+    /// `response_handler` fails part way through the response, the rest of
+    /// the response is read and discarded before returning. If the response
+    /// could not be read the connection is unusable, and the request remains
+    /// outstanding so that `send` refuses to use it.
+    pub fn receive<F>(
+        &mut self,
+        mut response_handler: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(&FastMessage) -> Result<(), Error>,
+    {
+        if !self.outstanding {
+            let msg = "Unable to receive response: no request is outstanding";
+            return Err(Error::other(msg));
+        }
+
+        // Whether the response handler failed, and if so whether it failed on
+        // the message that completed the response.
+        let mut handler_failed = None;
+        let result =
+            receive_buffered(&mut self.stream, &mut self.read_buf, |msg| {
+                response_handler(msg).map_err(|e| {
+                    handler_failed = Some(msg.is_terminal());
+                    e
+                })
+            });
+
+        self.outstanding = match (&result, handler_failed) {
+            (Ok(_), _) | (_, Some(true)) => false,
+            (Err(e), None) => server_error(e).is_none(),
+            (Err(_), Some(false)) => {
+                drain_to_boundary(&mut self.stream, &mut self.read_buf).is_err()
+            }
+        };
+        result
+    }
+
+    /// Consumes the client, returning the underlying TCP stream. Any bytes
+    /// that have been read from the stream but not yet handled are discarded.
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+/// A bounded pool of `BlockingFastClient` connections to a Fast server that
+/// are reused from one request to the next. `get` hands out an idle
+/// connection if there is one, opens a new one if fewer than the maximum are
+/// open, and otherwise waits for a connection to be returned. Clones of a
+/// `Pool` share their connections, so a pool can be used from many threads.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addrs: Vec<SocketAddr>,
+    max_size: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<BlockingFastClient>,
+    open: usize,
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("addrs", &self.inner.addrs)
+            .field("max_size", &self.inner.max_size)
+            .field("open", &self.open())
+            .finish()
+    }
+}
+
+impl Pool {
+    /// Creates a new Pool of at most `max_size` connections to `addr`, which
+    /// is resolved once when the pool is created. Connections are opened as
+    /// they are needed, as described for `connect`. Returns an error of kind
+    /// `InvalidInput` if `max_size` is 0.
+    pub fn new<A: ToSocketAddrs>(
+        addr: A,
+        max_size: usize,
+    ) -> Result<Self, Error> {
+        if max_size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "connection pool size must be at least 1",
+            ));
+        }
+
+        Ok(Pool {
+            inner: Arc::new(PoolInner {
+                addrs: addr.to_socket_addrs()?.collect(),
+                max_size,
+                state: Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                returned: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Take a connection from the pool, waiting for one to be returned if the
+    /// maximum number are already in use. An idle connection that has been
+    /// closed by the server, for example because it was idle for too long, is
+    /// discarded rather than handed out. The connection is returned to the
+    /// pool when the `PooledClient` is dropped.
+    pub fn get(&self) -> Result<PooledClient, Error> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                if client.read_buf.is_empty() && is_reusable(&client.stream) {
+                    return Ok(self.pooled(client));
+                }
+                state.open -= 1;
+                continue;
             }
-            Err(FastParseError::IOError(e)) => {
-                result = Err(e);
-                done = true;
+            if state.open < self.inner.max_size {
+                break;
+            }
+            state = self.inner.returned.wait(state).unwrap();
+        }
+
+        // Reserve the slot, then connect without holding the lock.
+        state.open += 1;
+        drop(state);
+        match connect_any(self.inner.addrs.as_slice(), TcpStream::connect) {
+            Ok(stream) => Ok(self.pooled(BlockingFastClient::new(stream))),
+            Err(e) => {
+                self.inner.release();
+                Err(e)
             }
         }
     }
 
+    /// Returns the number of connections that are open, whether idle or in
+    /// use.
+    pub fn open(&self) -> usize {
+        self.inner.state.lock().unwrap().open
+    }
+
+    fn pooled(&self, client: BlockingFastClient) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.inner),
+            healthy: true,
+        }
+    }
+}
+
+impl PoolInner {
+    /// Give up the slot of a connection that is not being returned to the
+    /// pool.
+    fn release(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.returned.notify_one();
+    }
+}
+
+/// Returns true if nothing has been received on the idle connection `stream`
+/// since the last response was read from it. A connection closed by the
+/// server reads as end of file, and any unexpected bytes mean that it is no
+/// longer at a message boundary.
+fn is_reusable(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let result = stream.peek(&mut [0; 1]);
+    if stream.set_nonblocking(false).is_err() {
+        return false;
+    }
+    matches!(result, Err(ref e) if e.kind() == ErrorKind::WouldBlock)
+}
+
+/// A connection taken from a `Pool`. Dropping it returns the connection to
+/// the pool, unless an error other than an `ERROR` response from the server
+/// was seen on it or a request sent on it has not been fully received, in
+/// which case it is closed so that it is not reused.
+pub struct PooledClient {
+    client: Option<BlockingFastClient>,
+    pool: Arc<PoolInner>,
+    healthy: bool,
+}
+
+impl PooledClient {
+    /// Send a message to the Fast server, as described for
+    /// `BlockingFastClient::send`.
+    pub fn send(
+        &mut self,
+        method: String,
+        args: Value,
+    ) -> Result<usize, Error> {
+        let result = self.client_mut().send(method, args);
+        self.check(result)
+    }
+
+    /// Receive the response to the outstanding request, as described for
+    /// `BlockingFastClient::receive`.
+    pub fn receive<F>(&mut self, response_handler: F) -> Result<usize, Error>
+    where
+        F: FnMut(&FastMessage) -> Result<(), Error>,
+    {
+        let result = self.client_mut().receive(response_handler);
+        self.check(result)
+    }
+
+    /// Call `method` with the provided arguments and return the messages of
+    /// the response, as passed to the response handler by `receive`.
+    pub fn call(
+        &mut self,
+        method: String,
+        args: Value,
+    ) -> Result<Vec<FastMessage>, Error> {
+        let mut responses = Vec::new();
+        self.send(method, args)?;
+        self.receive(|msg| {
+            responses.push(msg.clone());
+            Ok(())
+        })?;
+        Ok(responses)
+    }
+
+    fn client_mut(&mut self) -> &mut BlockingFastClient {
+        self.client
+            .as_mut()
+            .expect("pooled client already returned")
+    }
+
+    /// Record whether the connection is still usable after `result`. An
+    /// `ERROR` response leaves the connection ready for the next request.
+    fn check<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &result {
+            if server_error(e).is_none() {
+                self.healthy = false;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => return,
+        };
+
+        if self.healthy && !client.outstanding {
+            self.pool.state.lock().unwrap().idle.push(client);
+            self.pool.returned.notify_one();
+        } else {
+            drop(client);
+            self.pool.release();
+        }
+    }
+}
+
+/// A call made on a `Client` that is awaiting the rest of its response.
+enum PendingCall {
+    /// A call made with `Client::call`: the responses received so far, and
+    /// the channel on which to complete the call once all have arrived.
+    Collect {
+        responses: Vec<FastMessage>,
+        done: oneshot::Sender<Result<Vec<FastMessage>, Error>>,
+    },
+    /// A call made with `Client::call_stream_typed`, which is passed each
+    /// response message as it arrives. The channel is closed once the call is
+    /// complete.
+    Stream(mpsc::UnboundedSender<Result<FastMessage, Error>>),
+}
+
+impl PendingCall {
+    /// Complete the call with `err`.
+    fn fail(self, err: Error) {
+        match self {
+            PendingCall::Collect { done, .. } => {
+                let _ = done.send(Err(err));
+            }
+            PendingCall::Stream(responses) => {
+                let _ = responses.unbounded_send(Err(err));
+            }
+        }
+    }
+}
+
+/// The calls awaiting a response on a `Client` connection, or `None` once the
+/// connection has been closed.
+type PendingCalls = Arc<Mutex<Option<HashMap<u32, PendingCall>>>>;
+
+/// An asynchronous Fast client that allows any number of calls to be
+/// outstanding on one connection at once. Responses are routed to the call
+/// they belong to by message id, so concurrent calls never see each other's
+/// messages.
+///
+/// Creating a `Client` spawns tasks that write requests to and read responses
+/// from the connection, so it must be created from within a task running on a
+/// tokio runtime.
+pub struct Client {
+    msg_id: FastMessageId,
+    requests: mpsc::UnboundedSender<Vec<FastMessage>>,
+    pending: PendingCalls,
+}
+
+/// Connect to a Fast server listening on the Unix domain socket at `path`.
+/// The returned future resolves to a `Client` using the connection, and must
+/// be run on a tokio runtime. Only available on Unix platforms.
+#[cfg(unix)]
+pub fn connect_unix<P: AsRef<Path>>(
+    path: P,
+) -> impl Future<Item = Client, Error = Error> {
+    UnixStream::connect(path).map(Client::new)
+}
+
+impl Client {
+    /// Creates a new `Client` using the provided stream, such as a
+    /// `tokio::net::TcpStream` or a `tokio_uds::UnixStream`.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Client::from_framed(FastRpc::new().framed(stream), FastMessageId::new())
+    }
+
+    /// Negotiate the use of compression with the server on the provided
+    /// stream, as described for `protocol::FP_NEGOTIATE_METHOD`, before any
+    /// other request is sent. The returned future resolves to a `Client`
+    /// using the stream, which compresses its requests if the server agreed
+    /// to gzip compression. If the server does not support negotiation the
+    /// `Client` sends its requests uncompressed.
+    pub fn with_negotiated_compression<S>(
+        stream: S,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let msg_id = FastMessageId::new();
+        let id = IdAllocator::next(&msg_id);
+        let request =
+            protocol::negotiation_request(id, &[protocol::FP_COMPRESSION_GZIP]);
+
+        FastRpc::new()
+            .framed(stream)
+            .send(vec![request])
+            .and_then(|framed| {
+                future::loop_fn((framed, None), |(framed, chosen)| {
+                    framed.into_future().map_err(|(e, _)| e).and_then(
+                        |(msgs, framed)| {
+                            let msgs = msgs.ok_or_else(|| {
+                                Error::new(
+                                    ErrorKind::UnexpectedEof,
+                                    "connection closed during negotiation",
+                                )
+                            })?;
+                            let mut chosen = chosen;
+                            let mut done = false;
+                            for msg in msgs {
+                                match msg.status {
+                                    FastMessageStatus::Data => {
+                                        chosen =
+                                            protocol::negotiated_compression(
+                                                &msg,
+                                            )
+                                    }
+                                    FastMessageStatus::End => done = true,
+                                    FastMessageStatus::Error => {
+                                        chosen = None;
+                                        done = true;
+                                    }
+                                }
+                            }
+                            Ok(if done {
+                                future::Loop::Break((framed, chosen))
+                            } else {
+                                future::Loop::Continue((framed, chosen))
+                            })
+                        },
+                    )
+                })
+            })
+            .map(move |(mut framed, chosen)| {
+                if chosen.as_deref() == Some(protocol::FP_COMPRESSION_GZIP) {
+                    let codec = framed.codec_mut();
+                    *codec = codec.clone().with_compression(true);
+                }
+                Client::from_framed(framed, msg_id)
+            })
+    }
+
+    fn from_framed<S>(framed: Framed<S, FastRpc>, msg_id: FastMessageId) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (tx, rx) = framed.split();
+        let (requests, queued) = mpsc::unbounded();
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+
+        tokio::spawn(
+            queued
+                .map_err(|()| Error::other("request queue failed"))
+                .forward(tx)
+                .map(|_| ())
+                .map_err(|_| ()),
+        );
+
+        let read_pending = Arc::clone(&pending);
+        let close_pending = Arc::clone(&pending);
+        tokio::spawn(
+            rx.for_each(move |msgs| {
+                let mut pending = read_pending.lock().unwrap();
+                if let Some(calls) = pending.as_mut() {
+                    msgs.into_iter().for_each(|msg| complete_call(calls, msg));
+                }
+                Ok(())
+            })
+            .then(move |result| {
+                let reason = match result {
+                    Ok(()) => String::from("connection closed by server"),
+                    Err(e) => format!("failed to read from connection: {}", e),
+                };
+                let calls = close_pending.lock().unwrap().take();
+                for (_, call) in calls.into_iter().flatten() {
+                    call.fail(Error::new(ErrorKind::UnexpectedEof, &*reason));
+                }
+                Ok(())
+            }),
+        );
+
+        Client {
+            msg_id,
+            requests,
+            pending,
+        }
+    }
+
+    /// Call `method` with the provided arguments. The returned future resolves
+    /// to the `DATA` messages of the response once the `END` message arrives,
+    /// including the `END` message if it carries data. An `ERROR` response
+    /// resolves to an error wrapping the `FastMessageServerError`.
+    pub fn call(
+        &self,
+        method: String,
+        args: Value,
+    ) -> impl Future<Item = Vec<FastMessage>, Error = Error> {
+        let (done, response) = oneshot::channel();
+        let responses = Vec::new();
+        self.start_call(method, args, PendingCall::Collect { responses, done });
+
+        response.then(|result| match result {
+            Ok(result) => result,
+            Err(oneshot::Canceled) => Err(connection_closed()),
+        })
+    }
+
+    /// Call `method` with the provided arguments, returning a stream of the
+    /// elements of the `d` array of each `DATA` message of the response,
+    /// deserialized into `T`, as they arrive. The stream ends when the `END`
+    /// message arrives, after the elements of its `d` array if it carries
+    /// data. An `ERROR` response, or an element that cannot be deserialized
+    /// into `T`, ends the stream with an error. Messages are queued for the
+    /// stream as they are read, so a stream that is consumed slowly does not
+    /// hold up the responses to other calls.
+    pub fn call_stream_typed<T>(
+        &self,
+        method: String,
+        args: Value,
+    ) -> impl Stream<Item = T, Error = Error>
+    where
+        T: DeserializeOwned,
+    {
+        let (tx, responses) = mpsc::unbounded();
+        self.start_call(method, args, PendingCall::Stream(tx));
+
+        responses
+            .then(|result| match result {
+                Ok(result) => result,
+                Err(()) => Err(connection_closed()),
+            })
+            .map(|msg| {
+                let values = match msg.data.d {
+                    Value::Array(values) => values,
+                    Value::Null => Vec::new(),
+                    value => vec![value],
+                };
+                stream::iter_ok(values)
+            })
+            .flatten()
+            .and_then(|value| {
+                serde_json::from_value(value)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            })
+    }
+
+    /// Cancel every call that is waiting for a response, completing each of
+    /// them with an error of kind `Interrupted`, and return the number of
+    /// calls cancelled. The protocol has no way to tell the server that a
+    /// request has been abandoned, so the server still sends its responses,
+    /// which are discarded when they arrive. The connection can still be used
+    /// for new calls.
+    pub fn cancel_all(&self) -> usize {
+        let calls = match self.pending.lock().unwrap().as_mut() {
+            Some(calls) => mem::take(calls),
+            None => return 0,
+        };
+        let cancelled = calls.len();
+        for (_, call) in calls {
+            call.fail(Error::new(
+                ErrorKind::Interrupted,
+                "Cancelled: the call was cancelled",
+            ));
+        }
+        cancelled
+    }
+
+    /// Returns the ids of the requests that are still waiting for a response,
+    /// in ascending order. A call is removed once its response has ended or it
+    /// has failed, so an id that stays in the list for a long time belongs to
+    /// a request the server is slow to answer.
+    pub fn inflight_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = match self.pending.lock().unwrap().as_ref() {
+            Some(calls) => calls.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Send a request for `method` and add `call` to the pending calls to
+    /// receive its response. If the connection has been closed the call is
+    /// dropped, which completes it with an error.
+    fn start_call(&self, method: String, args: Value, call: PendingCall) {
+        let id = IdAllocator::next(&self.msg_id);
+        if let Some(calls) = self.pending.lock().unwrap().as_mut() {
+            calls.insert(id, call);
+            let msg = FastMessage::data(id, FastMessageData::new(method, args));
+            if self.requests.unbounded_send(vec![msg]).is_err() {
+                calls.remove(&id);
+            }
+        }
+    }
+}
+
+/// The error with which a call is completed if its connection was closed
+/// before it could be sent.
+fn connection_closed() -> Error {
+    Error::new(
+        ErrorKind::NotConnected,
+        "Unable to complete call: the connection is closed",
+    )
+}
+
+/// Add `msg` to the responses of the call it belongs to, completing the call
+/// if `msg` is terminal. Messages for unknown calls are ignored.
+fn complete_call(calls: &mut HashMap<u32, PendingCall>, msg: FastMessage) {
+    match msg.status {
+        FastMessageStatus::Data => match calls.get_mut(&msg.id) {
+            Some(PendingCall::Collect { responses, .. }) => responses.push(msg),
+            Some(PendingCall::Stream(responses)) => {
+                let _ = responses.unbounded_send(Ok(msg));
+            }
+            None => (),
+        },
+        FastMessageStatus::End => match calls.remove(&msg.id) {
+            Some(PendingCall::Collect {
+                mut responses,
+                done,
+            }) => {
+                if has_end_data(&msg) {
+                    responses.push(msg);
+                }
+                let _ = done.send(Ok(responses));
+            }
+            Some(PendingCall::Stream(responses)) if has_end_data(&msg) => {
+                let _ = responses.unbounded_send(Ok(msg));
+            }
+            Some(PendingCall::Stream(_)) | None => (),
+        },
+        FastMessageStatus::Error => {
+            if let Some(call) = calls.remove(&msg.id) {
+                let err = FastMessageServerError::from_payload(&msg.data.d)
+                    .unwrap_or_else(|_| unspecified_error());
+                call.fail(err.into());
+            }
+        }
+    }
+}
+
+/// Handle each complete message at the start of `buf`, removing it from the
+/// buffer, until the response ends or more bytes are needed. Returns the `END`
+/// message once the response is complete. A failed response handler or an
+/// `ERROR` message also ends the response, and the message is removed from
+/// the buffer as well.
+fn handle_messages<F>(
+    buf: &mut Vec<u8>,
+    response_handler: &mut F,
+) -> Result<Option<FastMessage>, Error>
+where
+    F: FnMut(&FastMessage) -> Result<(), Error>,
+{
+    let mut offset = 0;
+    let result = loop {
+        let len = match FastMessage::frame_state(&buf[offset..]) {
+            FrameState::Complete { len } => len,
+            _ => break Ok(None),
+        };
+        let fm = match FastMessage::parse(&buf[offset..offset + len]) {
+            Ok(fm) => fm,
+            Err(e) => break Err(e.into()),
+        };
+        offset += len;
+
+        if fm.is_data() {
+            match response_handler(&fm) {
+                Ok(()) => continue,
+                Err(e) => break Err(e),
+            }
+        }
+
+        // The message is terminal, either an END or an ERROR. An END message
+        // may also carry data, which is handled the same way as the data of a
+        // DATA message.
+        break if fm.status == FastMessageStatus::Error {
+            FastMessageServerError::from_payload(&fm.data.d)
+                .map_err(|_| unspecified_error().into())
+                .and_then(|e: FastMessageServerError| Err(e.into()))
+        } else if has_end_data(&fm) {
+            response_handler(&fm).map(|()| Some(fm))
+        } else {
+            Ok(Some(fm))
+        };
+    };
+    buf.drain(..offset);
     result
 }
 
+/// Returns true if `msg` is an `END` message carrying data, meaning its `d`
+/// payload is something other than an empty array.
+fn has_end_data(msg: &FastMessage) -> bool {
+    match &msg.data.d {
+        Value::Array(values) => !values.is_empty(),
+        Value::Null => false,
+        _ => true,
+    }
+}
+
 fn unspecified_error() -> FastMessageServerError {
     FastMessageServerError::new(
         "UnspecifiedServerError",
         "Server reported unspecified error.",
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::net::{Shutdown, SocketAddr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use serde_json::json;
+
+    use crate::protocol::FastParseError;
+
+    fn read_request(socket: &mut TcpStream) -> FastMessage {
+        let mut msg_buf = Vec::new();
+        let mut read_buf = [0; 128];
+
+        loop {
+            match FastMessage::parse(&msg_buf) {
+                Ok(msg) => return msg,
+                Err(FastParseError::NotEnoughBytes(_)) => {
+                    let byte_count = socket.read(&mut read_buf).unwrap();
+                    assert!(byte_count > 0, "connection closed by client");
+                    msg_buf.extend_from_slice(&read_buf[0..byte_count]);
+                }
+                Err(e) => panic!("failed to parse request: {:?}", e),
+            }
+        }
+    }
+
+    /// Accept a single connection, read one request message from it, and write
+    /// the messages returned by `responder` back before closing the connection.
+    fn serve_once<F>(responder: F) -> (SocketAddr, thread::JoinHandle<()>)
+    where
+        F: FnOnce(&FastMessage) -> Vec<FastMessage> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let request = read_request(&mut socket);
+            let mut write_buf = BytesMut::new();
+            for msg in responder(&request) {
+                protocol::encode_msg(&msg, &mut write_buf).unwrap();
+            }
+            socket.write_all(&write_buf).unwrap();
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn blocking_client_rejects_second_outstanding_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut client = BlockingFastClient::new(stream);
+
+        assert!(client.receive(|_| Ok(())).is_err());
+        assert!(client.send(String::from("echo"), json!(["a"])).is_ok());
+        assert!(client.send(String::from("echo"), json!(["b"])).is_err());
+    }
+
+    #[test]
+    fn blocking_client_discards_rest_of_failed_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            for _ in 0..2 {
+                let request = read_request(&mut socket);
+                let mut write_buf = BytesMut::new();
+                for i in 0..3 {
+                    let response = request.reply(json!([i]));
+                    protocol::encode_msg(&response, &mut write_buf).unwrap();
+                }
+                let end = FastMessage::end_bare(request.id);
+                protocol::encode_msg(&end, &mut write_buf).unwrap();
+                socket.write_all(&write_buf).unwrap();
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = BlockingFastClient::new(stream);
+        client.send(String::from("list"), json!([])).unwrap();
+        let err = client
+            .receive(|msg| match msg.data.d {
+                Value::Array(ref vs) if vs[0] == json!(1) => {
+                    Err(Error::other("abandoned"))
+                }
+                _ => Ok(()),
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "abandoned");
+
+        let mut values = Vec::new();
+        client.send(String::from("list"), json!([])).unwrap();
+        client
+            .receive(|msg| {
+                values.push(msg.data.d.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(values, vec![json!([0]), json!([1]), json!([2])]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn blocking_client_prepends_method_prefix() {
+        let (addr, server) = serve_once(|request| {
+            let method = request.data.m.name.clone();
+            vec![
+                request.reply(json!([method.clone()])),
+                FastMessage::end(request.id, method),
+            ]
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client =
+            BlockingFastClient::new(stream).method_prefix("bucket.");
+        let mut methods = Vec::new();
+        client.send(String::from("get"), json!([])).unwrap();
+        client
+            .receive(|msg| {
+                methods.push(msg.data.d.clone());
+                Ok(())
+            })
+            .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(methods, vec![json!(["bucket.get"])]);
+    }
+
+    #[test]
+    fn send_reports_connection_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        drop(socket);
+
+        // The first write after the server closes the connection is accepted
+        // locally but answered with a reset, so keep sending until it fails.
+        let mut msg_id = FastMessageId::new();
+        let err = (0..50)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(10));
+                send(String::from("echo"), json!([]), &mut msg_id, &mut stream)
+                    .err()
+            })
+            .expect("send succeeded on a closed connection");
+
+        assert!(is_connection_error(&err), "unexpected error: {}", err);
+        let not_connection = Error::new(ErrorKind::TimedOut, "timed out");
+        assert!(!is_connection_error(&map_send_error(not_connection)));
+    }
+
+    #[test]
+    fn drain_to_boundary_discards_abandoned_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            for _ in 0..2 {
+                let request = read_request(&mut socket);
+                let mut write_buf = BytesMut::new();
+                for i in 0..3 {
+                    let response = request.reply(json!([i]));
+                    protocol::encode_msg(&response, &mut write_buf).unwrap();
+                }
+                let end = FastMessage::end_bare(request.id);
+                protocol::encode_msg(&end, &mut write_buf).unwrap();
+                socket.write_all(&write_buf).unwrap();
+            }
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(String::from("list"), json!([]), &mut msg_id, &mut stream)
+            .unwrap();
+        let mut buf = Vec::new();
+        let err = receive_buffered(&mut stream, &mut buf, |_| {
+            Err(Error::other("abandoned"))
+        });
+        assert!(err.is_err());
+        assert_eq!(drain_to_boundary(&mut stream, &mut buf).unwrap(), 3);
+        assert!(buf.is_empty());
+
+        let merged = call_merged(
+            String::from("list"),
+            json!([]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .unwrap();
+        assert_eq!(merged.d, json!([0, 1, 2]));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_resolves_host_names() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (_socket, peer) = listener.accept().unwrap();
+            peer
+        });
+
+        // localhost may also resolve to an IPv6 address that nothing is
+        // listening on, in which case the IPv4 address is tried next.
+        let stream = connect(("localhost", port)).unwrap();
+        assert_eq!(stream.local_addr().unwrap(), server.join().unwrap());
+        assert!(stream.peer_addr().unwrap().ip().is_loopback());
+    }
+
+    #[test]
+    fn read_timeout_fires_for_unresponsive_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            // Read the request but never respond to it.
+            read_request(&mut socket);
+            let mut buf = [0; 1];
+            let _ = socket.read(&mut buf);
+        });
+
+        let mut stream = connect_with_timeouts(
+            addr,
+            Duration::from_secs(1),
+            Duration::from_millis(100),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(String::from("echo"), json!([]), &mut msg_id, &mut stream)
+            .unwrap();
+
+        let start = Instant::now();
+        let err = receive(&mut stream, |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        stream.shutdown(Shutdown::Both).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn receive_resumable_returns_token() {
+        let (addr, server) = serve_once(|request| {
+            vec![
+                request.reply(json!([1, 2])),
+                FastMessage::end_with_resume(
+                    request.id,
+                    request.data.m.name.clone(),
+                    json!({"after": 2}),
+                ),
+            ]
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        let mut values = Vec::new();
+        send(String::from("list"), json!([]), &mut msg_id, &mut stream)
+            .unwrap();
+        let token = receive_resumable(&mut stream, |msg| {
+            values.push(msg.data.d.clone());
+            Ok(())
+        })
+        .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(values, vec![json!([1, 2])]);
+        assert_eq!(token, Some(json!({"after": 2})));
+    }
+
+    #[test]
+    fn bare_end_completes_request() {
+        let (addr, server) = serve_once(|request| {
+            vec![
+                request.reply(json!(["a"])),
+                FastMessage::end_bare(request.id),
+            ]
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        let merged = call_merged(
+            String::from("echo"),
+            json!([]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(merged.d, json!(["a"]));
+    }
+
+    #[test]
+    fn server_error_name_is_preserved() {
+        let (addr, server) = serve_once(|request| {
+            vec![request.reply_error("BucketNotFound", "no such bucket")]
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(
+            String::from("getbucket"),
+            json!([]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .unwrap();
+        let err = receive(&mut stream, |_| Ok(())).unwrap_err();
+        server.join().unwrap();
+
+        let server_err = server_error(&err).unwrap();
+        assert_eq!(server_err.name, "BucketNotFound");
+        assert_eq!(server_err.message, "no such bucket");
+        assert!(server_error(&Error::other("not from server")).is_none());
+    }
+
+    #[test]
+    fn end_message_data_is_handled() {
+        let (addr, server) = serve_once(|request| {
+            let mut end =
+                FastMessage::end(request.id, request.data.m.name.clone());
+            end.data.d = json!(["last"]);
+            vec![request.reply(json!(["first"])), end]
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        let merged = call_merged(
+            String::from("echo"),
+            json!([]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(merged.d, json!(["first", "last"]));
+    }
+
+    #[test]
+    fn receive_reports_incomplete_response() {
+        // Send two DATA messages and then close the connection without sending
+        // an END message.
+        let (addr, server) = serve_once(|request| {
+            vec![request.reply(json!(["a"])), request.reply(json!(["b"]))]
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(String::from("yes"), json!([]), &mut msg_id, &mut stream).unwrap();
+        server.join().unwrap();
+
+        let outcome = receive_with_outcome(&mut stream, |_| Ok(())).unwrap();
+        assert_eq!(
+            outcome,
+            ReceiveOutcome {
+                data_frames: 2,
+                incomplete: true,
+            }
+        );
+    }
+
+    #[test]
+    fn call_merged_concatenates_data_payloads() {
+        let (addr, server) = serve_once(|request| {
+            let method = request.data.m.name.clone();
+            vec![
+                request.reply(json!([{"hello": "world"}])),
+                request.reply(json!([{"hello": "world"}])),
+                request.reply(json!([{"hello": "world"}])),
+                FastMessage::end(request.id, method),
+            ]
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        let args = json!([{"value": {"hello": "world"}, "count": 3}]);
+        let merged =
+            call_merged(String::from("yes"), args, &mut msg_id, &mut stream)
+                .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(merged.m.name, "yes");
+        assert_eq!(merged.d, Value::Array(vec![json!({"hello": "world"}); 3]));
+    }
+
+    struct CountingReader<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn receive_sizes_reads_to_remaining_frame_bytes() {
+        let data_size = 100_000;
+        let request = FastMessage::data(
+            0,
+            FastMessageData::new(String::from("echo"), json!([])),
+        );
+        let mut write_buf = BytesMut::new();
+        let response = request.reply(json!(["a".repeat(data_size)]));
+        protocol::encode_msg(&response, &mut write_buf).unwrap();
+        let end = FastMessage::end(0, String::from("echo"));
+        protocol::encode_msg(&end, &mut write_buf).unwrap();
+
+        let mut reader = CountingReader {
+            inner: Cursor::new(write_buf.to_vec()),
+            reads: 0,
+        };
+        let mut received = 0;
+        receive_from(
+            &mut reader,
+            |msg| {
+                assert_eq!(msg.data.d[0].as_str().unwrap().len(), data_size);
+                received += 1;
+                Ok(())
+            },
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(received, 1);
+        // Reading 128 bytes at a time would take more than 780 reads
+        assert!(reader.reads <= 4, "took {} reads", reader.reads);
+    }
+
+    #[test]
+    fn receive_reads_many_small_messages_at_once() {
+        let request = FastMessage::data(
+            0,
+            FastMessageData::new(String::from("echo"), json!([])),
+        );
+        let mut write_buf = BytesMut::new();
+        for i in 0..100 {
+            let response = request.reply(json!([i]));
+            protocol::encode_msg(&response, &mut write_buf).unwrap();
+        }
+        let end = FastMessage::end(0, String::from("echo"));
+        protocol::encode_msg(&end, &mut write_buf).unwrap();
+        // The start of the response to a later request, which is left in the
+        // buffer rather than lost.
+        protocol::encode_msg(&request.reply(json!([])), &mut write_buf)
+            .unwrap();
+        let next_len = write_buf.len();
+
+        let mut reader = CountingReader {
+            inner: Cursor::new(write_buf.to_vec()),
+            reads: 0,
+        };
+        let mut buf = Vec::new();
+        let mut received = 0;
+        receive_response(
+            &mut reader,
+            &mut buf,
+            |_| {
+                received += 1;
+                Ok(())
+            },
+            false,
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(received, 100);
+        // Reading each header and then each payload would take 202 reads
+        assert_eq!(reader.reads, 1);
+        assert_eq!(&buf[..], &write_buf[next_len - buf.len()..]);
+        assert!(!buf.is_empty());
+    }
+
+    #[test]
+    fn send_raw_forwards_frame_verbatim() {
+        let request = FastMessage::data(
+            3,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        let mut frame = BytesMut::new();
+        protocol::encode_msg(&request, &mut frame).unwrap();
+        let parsed = FastMessage::parse(&frame).unwrap();
+        assert_eq!(parsed.data, request.data);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+
+        let frame_len = frame.len();
+        assert!(send_raw(&frame[..frame_len - 1], &mut stream).is_err());
+        let mut extra = frame.to_vec();
+        extra.push(0);
+        assert!(send_raw(&extra, &mut stream).is_err());
+
+        assert_eq!(send_raw(&frame, &mut stream).unwrap(), frame_len);
+        let mut received = vec![0; frame_len];
+        socket.read_exact(&mut received).unwrap();
+        assert_eq!(received, frame.to_vec());
+    }
+
+    #[test]
+    fn receive_timeout_fires_for_sleeping_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let request = read_request(&mut socket);
+            thread::sleep(Duration::from_millis(300));
+            let mut write_buf = BytesMut::new();
+            let end = FastMessage::end(request.id, request.data.m.name);
+            protocol::encode_msg(&end, &mut write_buf).unwrap();
+            let _ = socket.write_all(&write_buf);
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(String::from("echo"), json!([]), &mut msg_id, &mut stream)
+            .unwrap();
+
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let err =
+            receive_timeout(&mut stream, |_| Ok(()), timeout).unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(stream.read_timeout().unwrap(), None);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn receive_timeout_bounds_whole_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let request = read_request(&mut socket);
+            let mut write_buf = BytesMut::new();
+            let response = request.reply(json!(["x".repeat(100)]));
+            protocol::encode_msg(&response, &mut write_buf).unwrap();
+
+            // Keep the response trickling in, a byte at a time, for much
+            // longer than the timeout, until the client gives up on it.
+            for byte in write_buf.iter() {
+                if socket.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(String::from("echo"), json!([]), &mut msg_id, &mut stream)
+            .unwrap();
+
+        let timeout = Duration::from_millis(300);
+        let start = Instant::now();
+        let err =
+            receive_timeout(&mut stream, |_| Ok(()), timeout).unwrap_err();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= timeout);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(stream.read_timeout().unwrap(), None);
+
+        stream.shutdown(Shutdown::Both).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn receive_reports_stalled_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let request = read_request(&mut socket);
+            let mut write_buf = BytesMut::new();
+            protocol::encode_msg(
+                &request.reply(json!(["stalled"])),
+                &mut write_buf,
+            )
+            .unwrap();
+
+            // Send only the header and then stall until the client gives up
+            socket
+                .write_all(&write_buf[..protocol::FP_HEADER_SZ])
+                .unwrap();
+            let mut read_buf = [0; 128];
+            let _ = socket.read(&mut read_buf);
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg_id = FastMessageId::new();
+        send(String::from("echo"), json!([]), &mut msg_id, &mut stream)
+            .unwrap();
+
+        let stall_timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let err =
+            receive_with_stall_timeout(&mut stream, |_| Ok(()), stall_timeout)
+                .unwrap_err();
+        assert!(start.elapsed() >= stall_timeout);
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("StalledFrame"));
+        assert_eq!(stream.read_timeout().unwrap(), None);
+
+        stream.shutdown(Shutdown::Both).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn prepared_request_sends_identical_payloads() {
+        let prepared =
+            PreparedRequest::new(String::from("echo"), json!(["fan-out"]))
+                .unwrap();
+        let mut msg_id = FastMessageId::new();
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..2 {
+            let tx = tx.clone();
+            let (addr, server) = serve_once(move |request| {
+                tx.send(request.clone()).unwrap();
+                vec![FastMessage::end(request.id, request.data.m.name.clone())]
+            });
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            send_prepared(&prepared, &mut msg_id, &mut stream).unwrap();
+            receive(&mut stream, |_| Ok(())).unwrap();
+            server.join().unwrap();
+        }
+
+        let first = rx.recv().unwrap();
+        let second = rx.recv().unwrap();
+        assert_eq!(first.id, 0);
+        assert_eq!(second.id, 1);
+        assert_eq!(first.data, second.data);
+        assert_eq!(first.data.m.name, "echo");
+        assert_eq!(first.data.d, json!(["fan-out"]));
+    }
+}
@@ -21,31 +21,35 @@
 //!
 //! <img src="../../../docs/fastpacket.svg" width="100%" height="100%">
 //!
-//! * VERSION   1-byte integer.  The only supported value is "1".
+//! * VERSION   1-byte integer.  The only supported value is "2". Frames with
+//!   any other version, including version 1 frames from older node-fast
+//!   peers, are rejected.
 //!
-//! * TYPE      1-byte integer.  The only supported value is TYPE_JSON (0x1),
-//!           indicating that the data payload is an encoded JSON object.
+//! * TYPE      1-byte integer.  The supported values are TYPE_JSON (0x1),
+//!   indicating that the data payload is an encoded JSON object, and
+//!   TYPE_BINARY (0x2), indicating that the data payload is opaque bytes. The
+//!   high bit (0x80) may be set in addition to mark a gzip-compressed payload.
 //!
 //! * STATUS    1-byte integer.  The only supported values are:
 //!
-//!     * STATUS_DATA  0x1  indicates a "data" message
+//!   * STATUS_DATA  0x1  indicates a "data" message
 //!
-//!     * STATUS_END   0x2  indicates an "end" message
+//!   * STATUS_END   0x2  indicates an "end" message
 //!
-//!     * STATUS_ERROR 0x3  indicates an "error" message
+//!   * STATUS_ERROR 0x3  indicates an "error" message
 //!
 //! * MSGID0...MSGID3    4-byte big-endian unsigned integer, a unique identifier
-//!                    for this message.
+//!   for this message.
 //!
 //! * CRC0...CRC3        4-byte big-endian unsigned integer representing the CRC16
-//!                     value of the data payload
+//!   value of the data payload
 //!
 //! * DLEN0...DLEN4      4-byte big-endian unsigned integer representing the number
-//!                    of bytes of data payload that follow
+//!   of bytes of data payload that follow
 //!
 //! * DATA0...DATAN      Data payload.  This is a JSON-encoded object (for TYPE =
-//!                    TYPE_JSON).  The encoding length in bytes is given by the
-//!                    DLEN0...DLEN4 bytes.
+//!   TYPE_JSON).  The encoding length in bytes is given by the
+//!   DLEN0...DLEN4 bytes.
 //!
 //! ### Status
 //!
@@ -5,20 +5,27 @@
 //! server consumers of this crate, but they are exposed for the special case of
 //! someone needing to implement custom client or server code.
 
-use std::io::{Error, ErrorKind};
-use std::sync::atomic::AtomicUsize;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{Error, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{io, str, usize};
+use std::{error, fmt, io, str};
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
 use crc16::*;
-use num::{FromPrimitive, ToPrimitive};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use num::FromPrimitive;
 use num_derive::{FromPrimitive, ToPrimitive};
 use serde_derive::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokio_io::_tokio_codec::{Decoder, Encoder};
 
+const FP_OFF_VERSION: usize = 0x0;
 const FP_OFF_TYPE: usize = 0x1;
 const FP_OFF_STATUS: usize = 0x2;
 const FP_OFF_MSGID: usize = 0x3;
@@ -29,12 +36,60 @@ const FP_OFF_DATA: usize = 0xf;
 /// The size of a Fast message header
 pub const FP_HEADER_SZ: usize = FP_OFF_DATA;
 
+/// The maximum nesting depth of arrays and objects accepted in a Fast message
+/// data payload. Payloads nested more deeply than this are rejected before
+/// they are handed to `serde_json`.
+pub const FP_MAX_JSON_DEPTH: usize = 128;
+
+/// The default limit on the size of a Fast message data payload accepted by
+/// `FastRpc`. See `FastRpc::with_max_payload_len`.
+pub const FP_DEFAULT_MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+/// The largest message identifier permitted by the Fast protocol. Message
+/// identifiers are limited to 31 bits.
+pub const FP_MAX_MSGID: u32 = 0x7fff_ffff;
+
+/// The bit of the Type field of a Fast message that is set when its data
+/// payload is gzip compressed.
+const FP_TYPE_COMPRESSED: u8 = 0x80;
+
+/// The method name of the request with which a client negotiates the use of
+/// compression on a connection. The request is a `DATA` message sent before
+/// any other request on the connection, whose data payload lists the
+/// compression methods the client supports:
+///
+/// ```text
+/// { "m": { "name": "__negotiate", ... }, "d": [ { "compression": ["gzip"] } ] }
+/// ```
+///
+/// A server that supports negotiation answers with a `DATA` message naming the
+/// method it chose, or `null` if it supports none of those offered, followed
+/// by an `END` message:
+///
+/// ```text
+/// { "m": { "name": "__negotiate", ... }, "d": [ { "compression": "gzip" } ] }
+/// ```
+///
+/// Once the server has sent its answer it may compress any frame it sends,
+/// and once the client has received it the client may do the same. A server
+/// that does not support negotiation answers with an `ERROR` message, and if
+/// no negotiation takes place neither side compresses. Compressed frames are
+/// marked as such in their header, so each side can always decode the other's
+/// frames whether or not they are compressed. See `negotiation_request`,
+/// `negotiation_response` and `negotiated_compression`.
+pub const FP_NEGOTIATE_METHOD: &str = "__negotiate";
+
+/// The name of gzip compression in a negotiation exchange. This is the only
+/// compression method supported by this crate.
+pub const FP_COMPRESSION_GZIP: &str = "gzip";
+
 const FP_VERSION_2: u8 = 0x2;
 const FP_VERSION_CURRENT: u8 = FP_VERSION_2;
 
-/// A data type representing a Fast message id that can safely be shard between
+/// A data type representing a Fast message id that can safely be shared between
 /// threads. The `next` associated function retrieves the next id value and
-/// manages the circular message id space internally.
+/// manages the circular message id space internally. `IdAllocator::next`
+/// does the same through a shared reference.
 #[derive(Default)]
 pub struct FastMessageId(AtomicUsize);
 
@@ -48,15 +103,69 @@ impl FastMessageId {
 impl Iterator for FastMessageId {
     type Item = usize;
 
-    /// Returns the next Fast message id and increments the value modulo the
-    /// usize MAX_VALUE - 1.
+    /// Returns the next Fast message id, as for `IdAllocator::next`.
     fn next(&mut self) -> Option<Self::Item> {
-        // Increment our count. This is why we started at zero.
-        let id_value = self.0.get_mut();
-        let current = *id_value;
-        *id_value = (*id_value + 1) % (usize::max_value() - 1);
+        Some(IdAllocator::next(self) as usize)
+    }
+}
+
+/// A strategy for allocating Fast message ids. Implementations must be safe to
+/// share between threads.
+pub trait IdAllocator: Send + Sync {
+    /// Returns the next message id.
+    fn next(&self) -> u32;
+}
+
+impl IdAllocator for FastMessageId {
+    /// Returns the next sequential message id, wrapping back to zero after
+    /// `FP_MAX_MSGID`. This only takes `&self`, so a single `FastMessageId`
+    /// can be used by several threads at once.
+    fn next(&self) -> u32 {
+        // The id space is a power of two, so masking the counter wraps it in
+        // step with the counter itself wrapping and no compare-and-swap loop
+        // is needed.
+        let count = self.0.fetch_add(1, Ordering::Relaxed);
+        (count & FP_MAX_MSGID as usize) as u32
+    }
+}
+
+/// An `IdAllocator` that hands out message ids in an unpredictable order. Each
+/// allocator starts at a random point in the 31-bit message id space and
+/// scrambles a sequential counter, so no id is repeated until the entire space
+/// has been allocated.
+pub struct RandomIdAllocator {
+    count: AtomicUsize,
+    offset: u32,
+}
+
+impl RandomIdAllocator {
+    // Multiplying by an odd constant modulo a power of two is a bijection, so
+    // distinct counter values always produce distinct ids.
+    const MULTIPLIER: u32 = 0x5bd1_e995;
+    const ID_MASK: u32 = FP_MAX_MSGID;
+
+    /// Creates a new RandomIdAllocator with a random starting point
+    pub fn new() -> Self {
+        RandomIdAllocator {
+            count: AtomicUsize::new(0),
+            offset: rand::random::<u32>() & RandomIdAllocator::ID_MASK,
+        }
+    }
+}
 
-        Some(current)
+impl Default for RandomIdAllocator {
+    fn default() -> Self {
+        RandomIdAllocator::new()
+    }
+}
+
+impl IdAllocator for RandomIdAllocator {
+    fn next(&self) -> u32 {
+        let count = self.count.fetch_add(1, Ordering::SeqCst) as u32;
+        count
+            .wrapping_mul(RandomIdAllocator::MULTIPLIER)
+            .wrapping_add(self.offset)
+            & RandomIdAllocator::ID_MASK
     }
 }
 
@@ -64,6 +173,36 @@ impl Iterator for FastMessageId {
 #[derive(Debug)]
 pub enum FastParseError {
     NotEnoughBytes(usize),
+    /// The message header contains a protocol version other than the one
+    /// supported by this crate.
+    UnsupportedVersion(u8),
+    /// The message header contains an unknown message type byte.
+    InvalidType(u8),
+    /// The message header contains a known message type but an unknown status
+    /// byte for the message with identifier `id`.
+    InvalidStatus {
+        id: u32,
+        status: u8,
+    },
+    /// The CRC of the data payload, `actual`, does not match the CRC in the
+    /// message header, `expected`. The payload was most likely corrupted in
+    /// transit.
+    CrcMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The data payload for the message with identifier `id` is not valid
+    /// UTF-8.
+    InvalidUtf8 {
+        id: u32,
+        source: str::Utf8Error,
+    },
+    /// The message header was parsed successfully, but the data payload for
+    /// the message with identifier `id` is not valid JSON.
+    BadPayload {
+        id: u32,
+        source: serde_json::Error,
+    },
     IOError(Error),
 }
 
@@ -73,24 +212,84 @@ impl From<io::Error> for FastParseError {
     }
 }
 
+impl fmt::Display for FastParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FastParseError::NotEnoughBytes(len) => write!(
+                f,
+                "Unable to parse message: not enough bytes ({} available)",
+                len
+            ),
+            FastParseError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported protocol version {:#04x}, expected {:#04x}",
+                version, FP_VERSION_CURRENT
+            ),
+            FastParseError::InvalidType(msg_type) => write!(
+                f,
+                "Failed to parse message type: unknown type {:#04x}",
+                msg_type
+            ),
+            FastParseError::InvalidStatus { id, status } => write!(
+                f,
+                "Failed to parse status of message {}: unknown status {:#04x}",
+                id, status
+            ),
+            FastParseError::CrcMismatch { expected, actual } => write!(
+                f,
+                "Calculated CRC {:#010x} does not match the provided CRC \
+                 {:#010x}",
+                actual, expected
+            ),
+            FastParseError::InvalidUtf8 { id, source } => write!(
+                f,
+                "Failed to parse data payload of message {} as UTF-8: {}",
+                id, source
+            ),
+            FastParseError::BadPayload { id, source } => write!(
+                f,
+                "Failed to parse data payload of message {} as JSON: {}",
+                id, source
+            ),
+            FastParseError::IOError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for FastParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FastParseError::InvalidUtf8 { source, .. } => Some(source),
+            FastParseError::BadPayload { source, .. } => Some(source),
+            FastParseError::IOError(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
 impl From<FastParseError> for Error {
     fn from(pfr: FastParseError) -> Self {
         match pfr {
-            FastParseError::NotEnoughBytes(_) => {
-                let msg = "Unable to parse message: not enough bytes";
-                Error::new(ErrorKind::Other, msg)
-            }
             FastParseError::IOError(e) => e,
+            _ => Error::other(pfr.to_string()),
         }
     }
 }
 
 /// An error type representing Fast error messages that may be returned from a
 /// Fast server.
-#[derive(Debug, Deserialize, Serialize)]
+///
+/// Servers such as Moray return VError-style errors that may also carry a
+/// `context` object and, for a MultiError, the list of constituent errors in
+/// `ase_errors`. These fields are optional and are omitted when empty.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct FastMessageServerError {
     pub name: String,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ase_errors: Vec<FastMessageServerError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<Value>,
 }
 
 impl FastMessageServerError {
@@ -98,31 +297,169 @@ impl FastMessageServerError {
         FastMessageServerError {
             name: String::from(name),
             message: String::from(message),
+            ase_errors: Vec::new(),
+            context: None,
+        }
+    }
+
+    /// Creates the error returned for a request for `method` when the server
+    /// has no handler for it. The name is always `FastError.MethodNotFound`,
+    /// so clients can tell this case apart from a failure of the method.
+    pub fn method_not_found(method: &str) -> Self {
+        FastMessageServerError::new(
+            "FastError.MethodNotFound",
+            &format!("unsupported method: {}", method),
+        )
+    }
+
+    /// Creates a new FastMessageServerError from an `io::Error`. The name is
+    /// inferred from the kind of the error and the message is the error's
+    /// description. If `err` wraps a `FastMessageServerError`, as the errors
+    /// returned by the client do, a copy of that error is returned instead.
+    pub fn from_io(err: &io::Error) -> Self {
+        if let Some(inner) = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<FastMessageServerError>())
+        {
+            return inner.clone();
+        }
+
+        FastMessageServerError::new(
+            error_kind_name(err.kind()),
+            &err.to_string(),
+        )
+    }
+
+    /// Parses the `d` payload of an `ERROR` message. The error may be the
+    /// payload itself or, as sent by some servers, the only element of an
+    /// array. See `ErrorPayloadFormat`.
+    pub fn from_payload(d: &Value) -> Result<Self, serde_json::Error> {
+        match d {
+            Value::Array(values) if values.len() == 1 => {
+                serde_json::from_value(values[0].clone())
+            }
+            _ => serde_json::from_value(d.clone()),
+        }
+    }
+}
+
+/// The shape of the `d` payload of an `ERROR` message. Peers differ in what
+/// they expect, so servers can choose the format that matches their clients.
+/// `FastMessageServerError::from_payload` accepts either.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ErrorPayloadFormat {
+    /// The error object is the payload: `{"name": .., "message": ..}`
+    #[default]
+    Object,
+    /// The payload is an array holding the error object:
+    /// `[{"name": .., "message": ..}]`
+    Array,
+}
+
+impl ErrorPayloadFormat {
+    /// Returns the `d` payload carrying the error object `err` in this format.
+    pub fn encode(self, err: Value) -> Value {
+        match self {
+            ErrorPayloadFormat::Object => err,
+            ErrorPayloadFormat::Array => Value::Array(vec![err]),
+        }
+    }
+}
+
+/// Returns the name of the `FastMessageServerError` corresponding to an
+/// `io::ErrorKind`.
+fn error_kind_name(kind: io::ErrorKind) -> &'static str {
+    match kind {
+        io::ErrorKind::NotFound => "NotFoundError",
+        io::ErrorKind::PermissionDenied => "PermissionDeniedError",
+        io::ErrorKind::AlreadyExists => "AlreadyExistsError",
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => {
+            "InvalidArgumentError"
         }
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "TimeoutError",
+        io::ErrorKind::Interrupted => "InterruptedError",
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => "ConnectionError",
+        io::ErrorKind::Unsupported => "UnsupportedError",
+        _ => "FastError",
     }
 }
 
+impl fmt::Display for FastMessageServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+impl error::Error for FastMessageServerError {}
+
+/// The `FastMessageServerError` is kept as the inner error of the resulting
+/// `io::Error` and can be recovered with `get_ref` and `downcast_ref`.
 impl From<FastMessageServerError> for Error {
     fn from(err: FastMessageServerError) -> Self {
-        Error::new(ErrorKind::Other, format!("{}: {}", err.name, err.message))
+        Error::other(err)
     }
 }
 
-/// Represents the Type field of a Fast message. Currently there is only one
-/// valid value, JSON.
-#[derive(Debug, FromPrimitive, ToPrimitive, PartialEq, Clone)]
+/// Represents the Type field of a Fast message. The data payload of a `Json`
+/// message is a JSON encoded `FastMessageData`, while the data payload of a
+/// `Binary` message is carried as raw bytes in `FastMessage::payload`.
+/// Serialized as its numeric value.
+#[derive(
+    Debug, FromPrimitive, ToPrimitive, PartialEq, Clone, Serialize, Deserialize,
+)]
+#[serde(into = "u8", try_from = "u8")]
 pub enum FastMessageType {
     Json = 1,
+    Binary = 2,
+}
+
+impl From<FastMessageType> for u8 {
+    fn from(msg_type: FastMessageType) -> u8 {
+        msg_type as u8
+    }
+}
+
+impl TryFrom<u8> for FastMessageType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, String> {
+        FromPrimitive::from_u8(value)
+            .ok_or_else(|| format!("invalid message type: {}", value))
+    }
 }
 
-/// Represents the Status field of a Fast message.
-#[derive(Debug, FromPrimitive, ToPrimitive, PartialEq, Clone)]
+/// Represents the Status field of a Fast message. Serialized as its numeric
+/// value.
+#[derive(
+    Debug, FromPrimitive, ToPrimitive, PartialEq, Clone, Serialize, Deserialize,
+)]
+#[serde(into = "u8", try_from = "u8")]
 pub enum FastMessageStatus {
     Data = 1,
     End = 2,
     Error = 3,
 }
 
+impl From<FastMessageStatus> for u8 {
+    fn from(status: FastMessageStatus) -> u8 {
+        status as u8
+    }
+}
+
+impl TryFrom<u8> for FastMessageStatus {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, String> {
+        FromPrimitive::from_u8(value)
+            .ok_or_else(|| format!("invalid message status: {}", value))
+    }
+}
+
 /// This type encapsulates the header of a Fast message.
 pub struct FastMessageHeader {
     /// The Type field of the Fast message
@@ -135,30 +472,95 @@ pub struct FastMessageHeader {
     crc: u32,
     /// The length in bytes of the Fast message data payload
     data_len: usize,
+    /// Whether the Fast message data payload is gzip compressed
+    compressed: bool,
+}
+
+/// Describes how much of a Fast message frame is present at the start of a
+/// byte buffer.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FrameState {
+    /// The buffer does not yet contain a complete header. `missing` is the
+    /// number of additional bytes needed to complete the header.
+    NeedHeader { missing: usize },
+    /// The buffer contains a complete header, but not the complete data
+    /// payload. `missing` is the number of additional bytes needed to complete
+    /// the frame.
+    NeedBody { missing: usize },
+    /// The buffer contains a complete frame of `len` bytes, including the
+    /// header.
+    Complete { len: usize },
 }
 
 /// Represents the metadata about a `FastMessage` data payload. This includes a
-/// timestamp and an RPC method name.
+/// timestamp and an RPC method name. The timestamp is optional on the wire and
+/// is 0 when a peer omits it. The method name is empty when a peer omits it,
+/// and is only omitted from the wire for a bare `END` message, as described
+/// for `FastMessage::end_bare`. `extra` holds optional
+/// application defined metadata, such as a resume token, and is omitted from
+/// the wire when unset.
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct FastMessageMetaData {
+    #[serde(default)]
     pub uts: u64,
+    #[serde(default)]
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<Value>,
 }
 
 impl FastMessageMetaData {
     pub fn new(n: String) -> FastMessageMetaData {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let now_micros =
-            now.as_secs() * 1_000_000 + u64::from(now.subsec_micros());
+        FastMessageMetaData::new_with_clock(n, &SystemClock)
+    }
 
+    /// Creates a new FastMessageMetaData with a timestamp read from the
+    /// provided `Clock`.
+    pub fn new_with_clock<C: Clock>(
+        n: String,
+        clock: &C,
+    ) -> FastMessageMetaData {
         FastMessageMetaData {
-            uts: now_micros,
+            uts: clock.now_micros(),
             name: n,
+            extra: None,
         }
     }
 }
 
+/// A source of timestamps for Fast message metadata.
+pub trait Clock {
+    /// Returns the current time in microseconds since the Unix epoch.
+    fn now_micros(&self) -> u64;
+}
+
+/// A `Clock` that reads the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_micros(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs() * 1_000_000 + u64::from(now.subsec_micros())
+    }
+}
+
+/// A `Clock` that always returns the same time. This is useful for producing
+/// deterministic metadata in tests.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now_micros(&self) -> u64 {
+        self.0
+    }
+}
+
 /// Encapsulates the Fast message metadata and the JSON formatted message data.
+///
+/// Integers in `d` that fit in a `u64` or `i64` round-trip exactly. Enable the
+/// `arbitrary-precision` feature to also preserve larger integers and decimals
+/// exactly as they appeared on the wire.
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct FastMessageData {
     pub m: FastMessageMetaData,
@@ -172,11 +574,58 @@ impl FastMessageData {
             d,
         }
     }
+
+    /// The data of a `Binary` message, which has no metadata or JSON value.
+    fn empty() -> FastMessageData {
+        FastMessageData {
+            m: FastMessageMetaData {
+                uts: 0,
+                name: String::new(),
+                extra: None,
+            },
+            d: Value::Null,
+        }
+    }
 }
 
-/// Represents a Fast message including the header and data payload
+/// A borrowed view of `FastMessageMetaData` whose method name refers to the
+/// buffer the message was parsed from.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+pub struct FastMessageMetaDataRef<'a> {
+    #[serde(default)]
+    pub uts: u64,
+    #[serde(default)]
+    pub name: &'a str,
+}
+
+/// The data payload of a bare `END` message as it is encoded on the wire,
+/// without a method name. See `FastMessage::end_bare`.
+#[derive(Serialize)]
+struct BareEndData<'a> {
+    m: BareEndMetaData<'a>,
+    d: &'a Value,
+}
+
+#[derive(Serialize)]
+struct BareEndMetaData<'a> {
+    uts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<&'a Value>,
+}
+
+/// A borrowed view of `FastMessageData` whose metadata refers to the buffer
+/// the message was parsed from.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct FastMessageDataRef<'a> {
+    #[serde(borrow)]
+    pub m: FastMessageMetaDataRef<'a>,
+    pub d: Value,
+}
+
+/// A Fast message parsed by `FastMessage::parse_borrowed`. This is the same as
+/// a `FastMessage` except that the data payload borrows from the buffer.
 #[derive(Debug, Clone)]
-pub struct FastMessage {
+pub struct FastMessageRef<'a> {
     /// The Type field of the Fast message
     pub msg_type: FastMessageType,
     /// The Status field of the Fast message
@@ -186,7 +635,38 @@ pub struct FastMessage {
     /// The length in bytes of the Fast message data payload
     pub msg_size: Option<usize>,
     /// The data payload of the Fast message
+    pub data: FastMessageDataRef<'a>,
+    /// The raw data payload of a `Binary` message, which is `None` for a
+    /// `Json` message
+    pub payload: Option<&'a [u8]>,
+}
+
+/// Represents a Fast message including the header and data payload
+///
+/// A `FastMessage` can be serialized, for example to persist it in a log. The
+/// type and status are serialized as their numeric values and `msg_size` is
+/// not serialized, so it is always `None` on a deserialized message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FastMessage {
+    /// The Type field of the Fast message
+    pub msg_type: FastMessageType,
+    /// The Status field of the Fast message
+    pub status: FastMessageStatus,
+    /// The Fast message identifier
+    pub id: u32,
+    /// The length in bytes of the Fast message data payload
+    #[serde(skip)]
+    pub msg_size: Option<usize>,
+    /// The data payload of the Fast message. For a `Binary` message this has
+    /// an empty method name and a null value.
     pub data: FastMessageData,
+    /// The raw data payload of a `Binary` message, which is sent as is rather
+    /// than being encoded as JSON. This is `None` for a `Json` message unless
+    /// it was decoded by a `FastRpc` configured with `with_raw_payloads` or
+    /// made by `echo_reply`, in which case it holds the JSON payload bytes
+    /// that are sent in place of encoding `data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Vec<u8>>,
 }
 
 impl PartialEq for FastMessage {
@@ -196,6 +676,7 @@ impl PartialEq for FastMessage {
             && self.id == other.id
             && self.msg_size == other.msg_size
             && self.data == other.data
+            && self.payload == other.payload
     }
 }
 
@@ -203,17 +684,27 @@ impl FastMessage {
     /// Parse a byte buffer into a `FastMessage`. Returns a `FastParseError` if
     /// the available bytes cannot be parsed to a `FastMessage`.
     pub fn parse(buf: &[u8]) -> Result<FastMessage, FastParseError> {
-        FastMessage::check_buffer_size(buf)?;
-        let header = FastMessage::parse_header(buf)?;
-
-        FastMessage::validate_data_length(buf, header.data_len)?;
-        let raw_data = &buf[FP_OFF_DATA..FP_OFF_DATA + header.data_len];
-        FastMessage::validate_crc(raw_data, header.crc)?;
-        let data = FastMessage::parse_data(raw_data)?;
+        FastMessage::parse_with_limit(buf, FP_DEFAULT_MAX_PAYLOAD_LEN)
+    }
 
-        let msg_size = match header.status {
-            FastMessageStatus::End => None,
-            _ => Some(FP_OFF_DATA + header.data_len),
+    /// Parse a byte buffer into a `FastMessage` as `parse` does, allowing a
+    /// compressed data payload to decompress to at most `max_payload_len`
+    /// bytes.
+    fn parse_with_limit(
+        buf: &[u8],
+        max_payload_len: usize,
+    ) -> Result<FastMessage, FastParseError> {
+        let (header, raw_data) = FastMessage::parse_frame(buf)?;
+        let raw_data =
+            FastMessage::decompress(&header, raw_data, max_payload_len)?;
+        let msg_size = FastMessage::msg_size(&header);
+        let (data, payload) = match header.msg_type {
+            FastMessageType::Json => {
+                (FastMessage::parse_data(&raw_data, header.id)?, None)
+            }
+            FastMessageType::Binary => {
+                (FastMessageData::empty(), Some(raw_data.into_owned()))
+            }
         };
 
         Ok(FastMessage {
@@ -222,9 +713,145 @@ impl FastMessage {
             id: header.id,
             msg_size,
             data,
+            payload,
+        })
+    }
+
+    /// Parse the Fast message frame at the start of `buf`, returning the
+    /// message and the number of bytes it occupies so that a buffer holding
+    /// several frames can be parsed one message at a time. Returns a
+    /// `FastParseError` if `buf` does not start with a complete frame that can
+    /// be parsed to a `FastMessage`.
+    pub fn from_bytes(
+        buf: &[u8],
+    ) -> Result<(FastMessage, usize), FastParseError> {
+        match FastMessage::frame_state(buf) {
+            FrameState::Complete { len } => {
+                FastMessage::parse(&buf[..len]).map(|msg| (msg, len))
+            }
+            _ => Err(FastParseError::NotEnoughBytes(buf.len())),
+        }
+    }
+
+    /// Encode this message into a new buffer holding a single Fast message
+    /// frame.
+    pub fn to_bytes(&self) -> Result<BytesMut, Error> {
+        let mut buf = BytesMut::new();
+        encode_msg(self, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Parse a byte buffer into a `FastMessageRef` that borrows the method
+    /// name from the buffer rather than allocating it. Returns a
+    /// `FastParseError` if the available bytes cannot be parsed to a
+    /// `FastMessageRef`, including when the method name contains JSON escape
+    /// sequences or the data payload is compressed and so cannot be borrowed.
+    pub fn parse_borrowed(
+        buf: &[u8],
+    ) -> Result<FastMessageRef<'_>, FastParseError> {
+        let (header, raw_data) = FastMessage::parse_frame(buf)?;
+        if header.compressed {
+            let msg = "Unable to borrow from a compressed data payload";
+            return Err(FastParseError::IOError(Error::other(msg)));
+        }
+        let msg_size = FastMessage::msg_size(&header);
+        let (data, payload) = match header.msg_type {
+            FastMessageType::Json => {
+                (FastMessage::parse_data(raw_data, header.id)?, None)
+            }
+            FastMessageType::Binary => {
+                let data = FastMessageDataRef {
+                    m: FastMessageMetaDataRef { uts: 0, name: "" },
+                    d: Value::Null,
+                };
+                (data, Some(raw_data))
+            }
+        };
+
+        Ok(FastMessageRef {
+            msg_type: header.msg_type,
+            status: header.status,
+            id: header.id,
+            msg_size,
+            data,
+            payload,
         })
     }
 
+    /// Validate the frame at the start of `buf` and return its header and
+    /// data payload.
+    fn parse_frame(
+        buf: &[u8],
+    ) -> Result<(FastMessageHeader, &[u8]), FastParseError> {
+        FastMessage::check_buffer_size(buf)?;
+        let header = FastMessage::parse_header(buf)?;
+
+        FastMessage::validate_data_length(buf, header.data_len)?;
+        let raw_data = &buf[FP_OFF_DATA..FP_OFF_DATA + header.data_len];
+        FastMessage::validate_crc(raw_data, header.crc)?;
+
+        Ok((header, raw_data))
+    }
+
+    /// Returns the data payload of a frame, decompressing it if the header
+    /// marks it as compressed. The decompressed payload may be at most
+    /// `max_payload_len` bytes long.
+    fn decompress<'a>(
+        header: &FastMessageHeader,
+        raw_data: &'a [u8],
+        max_payload_len: usize,
+    ) -> Result<Cow<'a, [u8]>, FastParseError> {
+        if !header.compressed {
+            return Ok(Cow::Borrowed(raw_data));
+        }
+
+        let limit = max_payload_len as u64 + 1;
+        let mut data = Vec::new();
+        GzDecoder::new(raw_data)
+            .take(limit)
+            .read_to_end(&mut data)?;
+        if data.len() > max_payload_len {
+            let msg = "Decompressed data payload exceeds maximum length";
+            return Err(FastParseError::IOError(Error::new(
+                io::ErrorKind::InvalidData,
+                msg,
+            )));
+        }
+
+        Ok(Cow::Owned(data))
+    }
+
+    fn msg_size(header: &FastMessageHeader) -> Option<usize> {
+        match header.status {
+            FastMessageStatus::End => None,
+            _ => Some(FP_OFF_DATA + header.data_len),
+        }
+    }
+
+    /// Report whether the provided byte buffer begins with a complete Fast
+    /// message frame and, if not, how many more bytes are needed. Only the
+    /// data length field of the header is inspected, so this does not validate
+    /// the frame.
+    pub fn frame_state(buf: &[u8]) -> FrameState {
+        if buf.len() < FP_HEADER_SZ {
+            return FrameState::NeedHeader {
+                missing: FP_HEADER_SZ - buf.len(),
+            };
+        }
+
+        let data_len =
+            BigEndian::read_u32(&buf[FP_OFF_DATALEN..FP_OFF_DATALEN + 4])
+                as usize;
+        let frame_len = FP_HEADER_SZ + data_len;
+        if buf.len() < frame_len {
+            FrameState::NeedBody {
+                missing: frame_len - buf.len(),
+            }
+        } else {
+            FrameState::Complete { len: frame_len }
+        }
+    }
+
     /// Check that the provided byte buffer contains at least `FP_HEADER_SZ`
     /// bytes.  Returns a `FastParseError` if this is not the case.
     pub fn check_buffer_size(buf: &[u8]) -> Result<(), FastParseError> {
@@ -241,17 +868,22 @@ impl FastMessage {
     pub fn parse_header(
         buf: &[u8],
     ) -> Result<FastMessageHeader, FastParseError> {
+        if buf[FP_OFF_VERSION] != FP_VERSION_CURRENT {
+            return Err(FastParseError::UnsupportedVersion(
+                buf[FP_OFF_VERSION],
+            ));
+        }
+        let compressed = buf[FP_OFF_TYPE] & FP_TYPE_COMPRESSED != 0;
         let msg_type =
-            FromPrimitive::from_u8(buf[FP_OFF_TYPE]).ok_or_else(|| {
-                let msg = "Failed to parse message type";
-                FastParseError::IOError(Error::new(ErrorKind::Other, msg))
-            })?;
-        let status =
-            FromPrimitive::from_u8(buf[FP_OFF_STATUS]).ok_or_else(|| {
-                let msg = "Failed to parse message status";
-                FastParseError::IOError(Error::new(ErrorKind::Other, msg))
-            })?;
+            FromPrimitive::from_u8(buf[FP_OFF_TYPE] & !FP_TYPE_COMPRESSED)
+                .ok_or(FastParseError::InvalidType(buf[FP_OFF_TYPE]))?;
         let msg_id = BigEndian::read_u32(&buf[FP_OFF_MSGID..FP_OFF_MSGID + 4]);
+        let status = FromPrimitive::from_u8(buf[FP_OFF_STATUS]).ok_or(
+            FastParseError::InvalidStatus {
+                id: msg_id,
+                status: buf[FP_OFF_STATUS],
+            },
+        )?;
         let expected_crc =
             BigEndian::read_u32(&buf[FP_OFF_CRC..FP_OFF_CRC + 4]);
         let data_len =
@@ -264,6 +896,7 @@ impl FastMessage {
             id: msg_id,
             crc: expected_crc,
             data_len,
+            compressed,
         })
     }
 
@@ -279,26 +912,66 @@ impl FastMessage {
     }
 
     fn validate_crc(data_buf: &[u8], crc: u32) -> Result<(), FastParseError> {
-        let calculated_crc = u32::from(State::<ARC>::calculate(data_buf));
+        let calculated_crc = compute_crc(data_buf);
         if crc != calculated_crc {
-            let msg = "Calculated CRC does not match the provided CRC";
-            Err(FastParseError::IOError(Error::new(ErrorKind::Other, msg)))
+            Err(FastParseError::CrcMismatch {
+                expected: crc,
+                actual: calculated_crc,
+            })
         } else {
             Ok(())
         }
     }
 
-    fn parse_data(data_buf: &[u8]) -> Result<FastMessageData, FastParseError> {
-        match str::from_utf8(data_buf) {
-            Ok(data_str) => serde_json::from_str(data_str).map_err(|_e| {
-                let msg = "Failed to parse data payload as JSON";
-                FastParseError::IOError(Error::new(ErrorKind::Other, msg))
-            }),
-            Err(_) => {
-                let msg = "Failed to parse data payload as UTF-8";
-                Err(FastParseError::IOError(Error::new(ErrorKind::Other, msg)))
+    fn validate_json_depth(data_buf: &[u8]) -> Result<(), FastParseError> {
+        // serde_json has its own recursion limit, but it can be turned off by
+        // any crate in the dependency graph enabling the `unbounded_depth`
+        // feature. Scan the payload here so a deeply nested payload from a
+        // peer can never exhaust the stack during deserialization.
+        let mut depth: usize = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for b in data_buf {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if *b == b'\\' {
+                    escaped = true;
+                } else if *b == b'"' {
+                    in_string = false;
+                }
+                continue;
+            }
+
+            match *b {
+                b'"' => in_string = true,
+                b'[' | b'{' => {
+                    depth += 1;
+                    if depth > FP_MAX_JSON_DEPTH {
+                        let msg = "Data payload exceeds maximum JSON nesting \
+                                   depth";
+                        return Err(FastParseError::IOError(Error::other(msg)));
+                    }
+                }
+                b']' | b'}' => depth = depth.saturating_sub(1),
+                _ => (),
             }
         }
+
+        Ok(())
+    }
+
+    fn parse_data<'a, T: serde::Deserialize<'a>>(
+        data_buf: &'a [u8],
+        id: u32,
+    ) -> Result<T, FastParseError> {
+        FastMessage::validate_json_depth(data_buf)?;
+        match str::from_utf8(data_buf) {
+            Ok(data_str) => serde_json::from_str(data_str)
+                .map_err(|source| FastParseError::BadPayload { id, source }),
+            Err(source) => Err(FastParseError::InvalidUtf8 { id, source }),
+        }
     }
 
     /// Returns a `FastMessage` that represents a Fast protocol `DATA` message
@@ -310,6 +983,114 @@ impl FastMessage {
             id: msg_id,
             msg_size: None,
             data,
+            payload: None,
+        }
+    }
+
+    /// Returns a `FastMessage` that represents a Fast protocol `DATA` message
+    /// of type `Binary` with the provided message identifier, whose data
+    /// payload is `payload` sent as is rather than encoded as JSON.
+    pub fn data_binary(msg_id: u32, payload: Vec<u8>) -> FastMessage {
+        FastMessage {
+            msg_type: FastMessageType::Binary,
+            status: FastMessageStatus::Data,
+            id: msg_id,
+            msg_size: None,
+            data: FastMessageData::empty(),
+            payload: Some(payload),
+        }
+    }
+
+    /// Returns the data payload of this message as it is encoded on the wire.
+    fn payload_bytes(&self) -> Result<Cow<'_, [u8]>, serde_json::Error> {
+        match (&self.msg_type, &self.payload) {
+            (FastMessageType::Binary, Some(payload)) => {
+                Ok(Cow::Borrowed(payload))
+            }
+            (FastMessageType::Binary, None) => Ok(Cow::Borrowed(&[])),
+            (FastMessageType::Json, Some(payload)) => {
+                Ok(Cow::Borrowed(payload))
+            }
+            (FastMessageType::Json, _) if self.is_bare_end() => {
+                let bare = BareEndData {
+                    m: BareEndMetaData {
+                        uts: self.data.m.uts,
+                        extra: self.data.m.extra.as_ref(),
+                    },
+                    d: &self.data.d,
+                };
+                serde_json::to_vec(&bare).map(Cow::Owned)
+            }
+            (FastMessageType::Json, _) => {
+                serde_json::to_vec(&self.data).map(Cow::Owned)
+            }
+        }
+    }
+
+    fn is_bare_end(&self) -> bool {
+        self.status == FastMessageStatus::End && self.data.m.name.is_empty()
+    }
+
+    /// Returns a `DATA` message like `data` that is marked with
+    /// `m.extra.more` as being continued by further `DATA` messages with the
+    /// same identifier. A request streamed over several frames is sent as
+    /// continued messages followed by one unmarked message, and the server
+    /// handles it as a single request whose `d` array is the concatenation of
+    /// the `d` arrays of all its messages. See `client::send_stream`.
+    pub fn data_continued(msg_id: u32, data: FastMessageData) -> FastMessage {
+        let mut msg = FastMessage::data(msg_id, data);
+        msg.data.m.extra = Some(json!({ "more": true }));
+        msg
+    }
+
+    /// Returns true if this message is continued by further messages with the
+    /// same identifier. See `data_continued`.
+    pub fn is_continued(&self) -> bool {
+        self.data
+            .m
+            .extra
+            .as_ref()
+            .and_then(|extra| extra.get("more"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Returns a `FastMessage` that represents a Fast protocol `DATA` message
+    /// carrying the result for one sub-request of a batched request. A method
+    /// that accepts several sub-requests in its `d` array can respond to each
+    /// with its own `DATA` message, all sharing the request's message
+    /// identifier. The data payload is a single element array of the form
+    /// `[{"index": index, "value": value}]` where `index` is the position of
+    /// the sub-request in the request's `d` array. Use `indexed_value` to
+    /// recover the index and value on the client.
+    pub fn data_indexed(
+        msg_id: u32,
+        method: String,
+        index: usize,
+        value: Value,
+    ) -> FastMessage {
+        let d = json!([{
+            "index": index,
+            "value": value
+        }]);
+        FastMessage::data(msg_id, FastMessageData::new(method, d))
+    }
+
+    /// Returns the sub-request index and value of a `DATA` message created by
+    /// `data_indexed`, or `None` if the message is not a `DATA` message or its
+    /// payload does not follow that convention.
+    pub fn indexed_value(&self) -> Option<(usize, &Value)> {
+        if !self.is_data() {
+            return None;
+        }
+
+        match self.data.d.as_array().map(Vec::as_slice) {
+            Some([entry]) => {
+                let index = entry.get("index")?.as_u64()? as usize;
+                let value = entry.get("value")?;
+                Some((index, value))
+            }
+            _ => None,
         }
     }
 
@@ -323,9 +1104,40 @@ impl FastMessage {
             id: msg_id,
             msg_size: None,
             data: FastMessageData::new(method, Value::Array(vec![])),
+            payload: None,
         }
     }
 
+    /// Returns a `FastMessage` that represents a Fast protocol `END` message
+    /// with the provided message identifier and no method name, saving the
+    /// bytes of the redundant name. The `name` field is left out of the
+    /// encoded metadata entirely. Any `END` message with an empty method name
+    /// is encoded this way, while other messages always carry `name`.
+    pub fn end_bare(msg_id: u32) -> FastMessage {
+        FastMessage::end(msg_id, String::new())
+    }
+
+    /// Returns a `FastMessage` that represents a Fast protocol `END` message
+    /// like `end`, but carrying `token` as `m.extra.resume_token`. A client can
+    /// pass the token back in a follow-up request to continue a large response
+    /// from where this one left off. The meaning of the token is left to the
+    /// application.
+    pub fn end_with_resume(
+        msg_id: u32,
+        method: String,
+        token: Value,
+    ) -> FastMessage {
+        let mut msg = FastMessage::end(msg_id, method);
+        msg.data.m.extra = Some(json!({ "resume_token": token }));
+        msg
+    }
+
+    /// Returns the resume token carried by this message, if any. See
+    /// `end_with_resume`.
+    pub fn resume_token(&self) -> Option<&Value> {
+        self.data.m.extra.as_ref()?.get("resume_token")
+    }
+
     /// Returns a `FastMessage` that represents a Fast protocol `ERROR` message
     /// with the provided message identifer and data payload.
     pub fn error(msg_id: u32, data: FastMessageData) -> FastMessage {
@@ -335,57 +1147,411 @@ impl FastMessage {
             id: msg_id,
             msg_size: None,
             data,
+            payload: None,
         }
     }
-}
 
-/// This type implements the functions necessary for the Fast protocl framing.
-pub struct FastRpc;
+    /// Returns a `DATA` message responding to this request message. The
+    /// response uses the message identifier and method name of the request and
+    /// carries `value` as its data payload.
+    pub fn reply(&self, value: Value) -> FastMessage {
+        FastMessage::data(
+            self.id,
+            FastMessageData::new(self.data.m.name.clone(), value),
+        )
+    }
 
-impl Decoder for FastRpc {
-    type Item = Vec<FastMessage>;
-    type Error = Error;
+    /// Returns a `DATA` message responding to this request message with the
+    /// request's own data payload. If the request kept its raw payload bytes,
+    /// as it does when decoded by a `FastRpc` configured with
+    /// `with_raw_payloads`, those bytes are sent exactly as they were received
+    /// rather than being encoded from `data` again, so changes made to the
+    /// `data` of the response have no effect on what is sent.
+    pub fn echo_reply(&self) -> FastMessage {
+        FastMessage {
+            msg_type: FastMessageType::Json,
+            status: FastMessageStatus::Data,
+            id: self.id,
+            msg_size: None,
+            data: self.data.clone(),
+            payload: match self.msg_type {
+                FastMessageType::Json => self.payload.clone(),
+                FastMessageType::Binary => None,
+            },
+        }
+    }
 
-    fn decode(
-        &mut self,
-        buf: &mut BytesMut,
-    ) -> Result<Option<Self::Item>, Error> {
-        let mut msgs: Self::Item = Vec::new();
-        let mut done = false;
+    /// Returns an `ERROR` message responding to this request message. The
+    /// response uses the message identifier and method name of the request and
+    /// carries the provided error `name` and `message` as its data payload.
+    pub fn reply_error(&self, name: &str, message: &str) -> FastMessage {
+        let value = json!({
+            "name": name,
+            "message": message
+        });
+
+        FastMessage::error(
+            self.id,
+            FastMessageData::new(self.data.m.name.clone(), value),
+        )
+    }
 
-        while !done && !buf.is_empty() {
-            // Make sure there is room in msgs to fit a message
-            if msgs.len() + 1 > msgs.capacity() {
-                msgs.reserve(1);
-            }
+    /// Returns true if this is a `DATA` message.
+    pub fn is_data(&self) -> bool {
+        self.status == FastMessageStatus::Data
+    }
 
-            match FastMessage::parse(&buf) {
-                Ok(parsed_msg) => {
-                    // TODO: Handle the error case here!
-                    let data_str =
-                        serde_json::to_string(&parsed_msg.data).unwrap();
-                    let data_len = data_str.len();
-                    buf.advance(FP_HEADER_SZ + data_len);
-                    msgs.push(parsed_msg);
-                    Ok(())
-                }
-                Err(FastParseError::NotEnoughBytes(_)) => {
-                    // Not enough bytes available yet so we need to return
-                    // Ok(None) to let the Framed instance know to read more
-                    // data before calling this function again.
-                    done = true;
-                    Ok(())
-                }
-                Err(err) => {
-                    let msg = format!(
+    /// Returns true if this message completes an RPC request, meaning it is
+    /// either an `END` or an `ERROR` message.
+    pub fn is_terminal(&self) -> bool {
+        match self.status {
+            FastMessageStatus::End | FastMessageStatus::Error => true,
+            FastMessageStatus::Data => false,
+        }
+    }
+
+    /// Returns a compact single line description of this message, such as
+    /// `DATA id=42 method=echo payload=128B crc=0x1a2b`, for debugging. The
+    /// payload size and CRC are those of the data payload as it is encoded.
+    pub fn describe(&self) -> String {
+        let status = match self.status {
+            FastMessageStatus::Data => "DATA",
+            FastMessageStatus::End => "END",
+            FastMessageStatus::Error => "ERROR",
+        };
+        let payload = self.payload_bytes().unwrap_or_default();
+        format!(
+            "{} id={} method={} payload={}B crc={:#06x}",
+            status,
+            self.id,
+            self.data.m.name,
+            payload.len(),
+            State::<ARC>::calculate(&payload)
+        )
+    }
+
+    /// Returns the fraction of the encoded size of this message that is taken
+    /// up by the header rather than the data payload.
+    pub fn overhead_ratio(&self) -> f64 {
+        let payload_bytes =
+            self.payload_bytes().map(|data| data.len()).unwrap_or(0);
+        FP_HEADER_SZ as f64 / (FP_HEADER_SZ + payload_bytes) as f64
+    }
+
+    /// Check that this message satisfies the invariants of the Fast protocol
+    /// and return a description of the first violation found. This is
+    /// intended for tests of Fast servers. Only the message id of a `Binary`
+    /// message is checked, since its payload is opaque.
+    pub fn validate_conformance(&self) -> Result<(), String> {
+        if self.id > FP_MAX_MSGID {
+            return Err(format!(
+                "message id {} exceeds the maximum of {}",
+                self.id, FP_MAX_MSGID
+            ));
+        }
+
+        if self.msg_type == FastMessageType::Binary {
+            return Ok(());
+        }
+
+        // A bare END message leaves out the method name. See `end_bare`.
+        if self.data.m.name.is_empty() && self.status != FastMessageStatus::End
+        {
+            return Err(String::from("method name is empty"));
+        }
+
+        match self.status {
+            FastMessageStatus::Data if !self.data.d.is_array() => {
+                Err(String::from("DATA payload is not an array"))
+            }
+            FastMessageStatus::End if !self.data.d.is_array() => {
+                Err(String::from("END payload is not an array"))
+            }
+            FastMessageStatus::Error => {
+                FastMessageServerError::from_payload(&self.data.d)
+                    .map(|_| ())
+                    .map_err(|e| {
+                        format!("ERROR payload is not an error: {}", e)
+                    })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Hooks invoked by `FastRpc` to report on the messages it processes. All
+/// methods have no-op default implementations so implementors only need to
+/// provide the ones they are interested in.
+pub trait CodecMetrics: Send + Sync {
+    /// Called each time a call to `decode` returns a non-empty batch of
+    /// messages, or `decode_each` decodes at least one message. `frames` is the
+    /// number of messages decoded and `bytes` is the number of bytes consumed
+    /// from the buffer to decode them.
+    fn on_decode(&self, _frames: usize, _bytes: usize) {}
+
+    /// Called for each message decoded. `header_bytes` is the size of the
+    /// message header, which is always `FP_HEADER_SZ`, and `payload_bytes` is
+    /// the size of its data payload.
+    fn on_message(&self, _header_bytes: usize, _payload_bytes: usize) {}
+}
+
+/// A fault that `FastRpc` can inject into an encoded frame in order to test
+/// how peers handle malformed input. Only available in tests or with the
+/// `test-util` feature enabled.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fault {
+    /// Alter the CRC in the frame header so it no longer matches the payload
+    CorruptCrc,
+    /// Remove the given number of bytes from the end of the frame
+    Truncate(usize),
+    /// Append an extra zero byte after the end of the frame
+    ExtraByte,
+}
+
+/// This type implements the functions necessary for the Fast protocl framing.
+///
+/// `decode` may be called directly on a caller-managed `BytesMut`. It only
+/// advances the buffer past complete frames, so a partially received frame is
+/// left intact at the start of the buffer and is returned by a later call once
+/// the rest of its bytes have been appended.
+#[derive(Clone, Default)]
+pub struct FastRpc {
+    metrics: Option<Arc<dyn CodecMetrics>>,
+    lenient: bool,
+    max_payload_len: Option<usize>,
+    compress: bool,
+    compress_switch: Option<Arc<AtomicBool>>,
+    raw_payloads: bool,
+    #[cfg(any(test, feature = "test-util"))]
+    faults: Vec<(usize, Fault)>,
+    #[cfg(any(test, feature = "test-util"))]
+    frames_encoded: usize,
+}
+
+impl FastRpc {
+    /// Creates a new FastRpc
+    pub fn new() -> Self {
+        FastRpc::default()
+    }
+
+    /// Returns this FastRpc configured to report to the provided
+    /// `CodecMetrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn CodecMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns this FastRpc configured to skip over frames that cannot be
+    /// parsed rather than failing the entire decode. A frame can only be
+    /// skipped once all of the bytes given by its data length field are
+    /// available. Note that no response is ever sent for a request in a
+    /// skipped frame.
+    pub fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// Returns this FastRpc configured to reject any frame whose header gives
+    /// a data payload longer than `max_payload_len` bytes. The error is
+    /// returned as soon as the header is received, without waiting for the
+    /// payload, so a peer cannot make the decode buffer grow without bound.
+    /// A compressed payload is also rejected if it decompresses to more than
+    /// `max_payload_len` bytes. The default is `FP_DEFAULT_MAX_PAYLOAD_LEN`.
+    pub fn with_max_payload_len(mut self, max_payload_len: usize) -> Self {
+        self.max_payload_len = Some(max_payload_len);
+        self
+    }
+
+    /// Returns this FastRpc configured to gzip compress the data payload of
+    /// each message it encodes, if doing so makes it smaller, and to mark the
+    /// message as compressed in the Type field of its header. Compressed
+    /// messages are always decompressed when decoded, so this only needs to
+    /// be enabled on the side that sends them.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Returns this FastRpc configured to keep the JSON data payload of each
+    /// uncompressed message it decodes in the `payload` field of the message,
+    /// as well as parsing it into `data`, so that a response made by
+    /// `FastMessage::echo_reply` sends the payload exactly as it was received.
+    /// Compressed payloads are not kept. By default no payloads are kept.
+    pub fn with_raw_payloads(mut self, keep: bool) -> Self {
+        self.raw_payloads = keep;
+        self
+    }
+
+    /// Returns this FastRpc configured to compress the data payloads it
+    /// encodes, as for `with_compression`, whenever `switch` is set. This lets
+    /// compression be turned on part way through a connection once it has
+    /// been negotiated, after the codec has been split into its halves.
+    pub(crate) fn with_compression_switch(
+        mut self,
+        switch: Arc<AtomicBool>,
+    ) -> Self {
+        self.compress_switch = Some(switch);
+        self
+    }
+
+    fn compresses(&self) -> bool {
+        self.compress
+            || self
+                .compress_switch
+                .as_ref()
+                .is_some_and(|switch| switch.load(Ordering::SeqCst))
+    }
+
+    fn max_payload_len(&self) -> usize {
+        self.max_payload_len.unwrap_or(FP_DEFAULT_MAX_PAYLOAD_LEN)
+    }
+
+    /// Returns an error if `buf` begins with a header giving a data payload
+    /// longer than the maximum allowed.
+    fn check_payload_len(&self, buf: &[u8]) -> Result<(), Error> {
+        if buf.len() < FP_HEADER_SZ {
+            return Ok(());
+        }
+
+        let max_payload_len = self.max_payload_len();
+        let data_len =
+            BigEndian::read_u32(&buf[FP_OFF_DATALEN..FP_OFF_DATALEN + 4])
+                as usize;
+        if data_len > max_payload_len {
+            return Err(payload_too_long(data_len, max_payload_len));
+        }
+
+        Ok(())
+    }
+
+    /// Returns this FastRpc configured to inject `fault` into the `frame`th
+    /// frame it encodes, counting from one.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn inject_fault(mut self, frame: usize, fault: Fault) -> Self {
+        self.faults.push((frame, fault));
+        self
+    }
+
+    #[cfg(any(test, feature = "test-util"))]
+    fn apply_faults(&mut self, frame_start: usize, buf: &mut BytesMut) {
+        self.frames_encoded += 1;
+        let frame = self.frames_encoded;
+        for (_, fault) in self.faults.iter().filter(|(n, _)| *n == frame) {
+            match fault {
+                Fault::CorruptCrc => buf[frame_start + FP_OFF_CRC + 3] ^= 0xff,
+                Fault::Truncate(count) => {
+                    let frame_len = buf.len() - frame_start;
+                    buf.truncate(buf.len() - count.min(&frame_len));
+                }
+                Fault::ExtraByte => buf.extend_from_slice(&[0]),
+            }
+        }
+    }
+}
+
+impl FastRpc {
+    /// Decode the complete messages at the start of `buf` one at a time,
+    /// passing each to `f` as soon as it is decoded instead of collecting them
+    /// into a batch as `decode` does. The buffer is advanced past each message
+    /// before `f` is called, and a partial message at the end of the buffer is
+    /// left in place. Returns the number of messages decoded. If `f` returns an
+    /// error no further messages are decoded and the error is returned.
+    pub fn decode_each<F>(
+        &mut self,
+        buf: &mut BytesMut,
+        mut f: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(FastMessage) -> Result<(), Error>,
+    {
+        let mut frames = 0;
+        let mut done = false;
+        let start_len = buf.len();
+
+        let mut result = Ok(());
+        while !done && !buf.is_empty() && result.is_ok() {
+            if let Err(err) = self.check_payload_len(buf) {
+                result = Err(err);
+                break;
+            }
+
+            // The frame length comes from the data length field of the header
+            // rather than from the parsed message, since re-serializing the
+            // payload need not reproduce the bytes that were received.
+            let frame_len = match FastMessage::frame_state(buf) {
+                FrameState::Complete { len } => len,
+                _ => buf.len(),
+            };
+            let parsed = FastMessage::parse_with_limit(
+                &buf[..frame_len],
+                self.max_payload_len(),
+            );
+            result = match parsed {
+                Ok(mut parsed_msg) => {
+                    if self.raw_payloads
+                        && parsed_msg.msg_type == FastMessageType::Json
+                    {
+                        parsed_msg.payload =
+                            raw_json_payload(&buf[..frame_len]);
+                    }
+                    buf.advance(frame_len);
+                    if let Some(metrics) = &self.metrics {
+                        metrics
+                            .on_message(FP_HEADER_SZ, frame_len - FP_HEADER_SZ);
+                    }
+                    frames += 1;
+                    f(parsed_msg)
+                }
+                Err(FastParseError::NotEnoughBytes(_)) => {
+                    // Not enough bytes available yet, so leave the partial
+                    // message in the buffer until more data has been read.
+                    done = true;
+                    Ok(())
+                }
+                Err(_) if self.lenient => {
+                    // Skip the unparseable frame if it is complete, otherwise
+                    // wait for the rest of it to arrive.
+                    match FastMessage::frame_state(buf) {
+                        FrameState::Complete { len } => buf.advance(len),
+                        _ => done = true,
+                    }
+                    Ok(())
+                }
+                Err(err) => {
+                    let msg = format!(
                         "failed to parse Fast request: {}",
                         Error::from(err)
                     );
-                    Err(Error::new(ErrorKind::Other, msg))
+                    Err(Error::other(msg))
                 }
-            }?
+            }
+        }
+
+        if frames > 0 {
+            if let Some(metrics) = &self.metrics {
+                metrics.on_decode(frames, start_len - buf.len());
+            }
         }
 
+        result.map(|_| frames)
+    }
+}
+
+impl Decoder for FastRpc {
+    type Item = Vec<FastMessage>;
+    type Error = Error;
+
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, Error> {
+        let mut msgs: Self::Item = Vec::new();
+        self.decode_each(buf, |msg| {
+            msgs.push(msg);
+            Ok(())
+        })?;
+
         if msgs.is_empty() {
             Ok(None)
         } else {
@@ -403,47 +1569,303 @@ impl Encoder for FastRpc {
         item: Self::Item,
         buf: &mut BytesMut,
     ) -> Result<(), io::Error> {
-        let results: Vec<Result<(), String>> =
-            item.iter().map(|x| encode_msg(x, buf)).collect();
-        let result: Result<Vec<()>, String> = results.iter().cloned().collect();
-        match result {
-            Ok(_) => Ok(()),
-            Err(errs) => Err(Error::new(ErrorKind::Other, errs)),
+        // Serialize every message first so that the buffer can be grown
+        // once for the whole batch.
+        let compress = self.compresses();
+        let serialized = item
+            .iter()
+            .map(|msg| {
+                let serialized = SerializedMsg::new(msg)?;
+                if compress {
+                    serialized.compress()
+                } else {
+                    Ok(serialized)
+                }
+            })
+            .collect::<Result<Vec<SerializedMsg>, Error>>()?;
+        buf.reserve(serialized.iter().map(SerializedMsg::frame_len).sum());
+
+        for msg in serialized {
+            #[cfg(any(test, feature = "test-util"))]
+            let frame_start = buf.len();
+            msg.write(buf);
+            #[cfg(any(test, feature = "test-util"))]
+            self.apply_faults(frame_start, buf);
         }
+
+        Ok(())
     }
 }
 
-/// Encode a `FastMessage` into a byte buffer. The `Result` contains a unit type
-/// on success and an error string on failure.
-pub(crate) fn encode_msg(
-    msg: &FastMessage,
+/// Returns the request with id `msg_id` with which a client offers the
+/// compression methods in `compression` to a server. See
+/// `FP_NEGOTIATE_METHOD`.
+pub fn negotiation_request(msg_id: u32, compression: &[&str]) -> FastMessage {
+    FastMessage::data(
+        msg_id,
+        FastMessageData::new(
+            String::from(FP_NEGOTIATE_METHOD),
+            json!([{ "compression": compression }]),
+        ),
+    )
+}
+
+/// Returns the response of a server supporting the compression methods in
+/// `supported` to the negotiation request `request`, along with the method
+/// chosen, which is the first offered by the client that the server supports.
+/// See `FP_NEGOTIATE_METHOD`.
+pub fn negotiation_response(
+    request: &FastMessage,
+    supported: &[&str],
+) -> (Vec<FastMessage>, Option<String>) {
+    let chosen = request.data.d[0]["compression"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .find(|offered| supported.contains(offered))
+        .map(String::from);
+    let responses = vec![
+        request.reply(json!([{ "compression": chosen }])),
+        FastMessage::end(request.id, request.data.m.name.clone()),
+    ];
+    (responses, chosen)
+}
+
+/// Returns the compression method chosen by a server in `response`, the `DATA`
+/// message of its response to a negotiation request, or `None` if it chose
+/// none. See `FP_NEGOTIATE_METHOD`.
+pub fn negotiated_compression(response: &FastMessage) -> Option<String> {
+    response.data.d[0]["compression"].as_str().map(String::from)
+}
+
+/// Encode the response of an echo handler to the request frame at the start of
+/// `request` into `buf`. The response is a `DATA` message carrying the
+/// request's data payload followed by an `END` message from `end_bare`. The
+/// payload is copied without being parsed as JSON, so the `DATA` message is
+/// byte-for-byte identical to the request frame. Returns the length of the
+/// request frame.
+pub fn encode_echo(
+    request: &[u8],
     buf: &mut BytesMut,
-) -> Result<(), String> {
-    let m_msg_type_u8 = msg.msg_type.to_u8();
-    let m_status_u8 = msg.status.to_u8();
-    match (m_msg_type_u8, m_status_u8) {
-        (Some(msg_type_u8), Some(status_u8)) => {
-            // TODO: Handle the error case here!
-            let data_str = serde_json::to_string(&msg.data).unwrap();
-            let data_len = data_str.len();
-            let buf_capacity = buf.capacity();
-            if buf.len() + FP_HEADER_SZ + data_len > buf_capacity {
-                buf.reserve(FP_HEADER_SZ + data_len as usize);
-            }
-            buf.put_u8(FP_VERSION_CURRENT);
-            buf.put_u8(msg_type_u8);
-            buf.put_u8(status_u8);
-            buf.put_u32_be(msg.id);
-            buf.put_u32_be(u32::from(State::<ARC>::calculate(
-                data_str.as_bytes(),
-            )));
-            buf.put_u32_be(data_str.len() as u32);
-            buf.put(data_str);
-            Ok(())
+) -> Result<usize, FastParseError> {
+    FastMessage::check_buffer_size(request)?;
+    let header = FastMessage::parse_header(request)?;
+    FastMessage::validate_data_length(request, header.data_len)?;
+    let frame_len = FP_HEADER_SZ + header.data_len;
+    FastMessage::validate_crc(&request[FP_HEADER_SZ..frame_len], header.crc)?;
+
+    if header.status != FastMessageStatus::Data {
+        let msg = "Unable to echo frame: not a DATA message";
+        return Err(FastParseError::IOError(Error::other(msg)));
+    }
+
+    buf.extend_from_slice(&request[..frame_len]);
+    encode_msg(&FastMessage::end_bare(header.id), buf)?;
+
+    Ok(frame_len)
+}
+
+/// Decode the complete Fast message frames at the start of `buf` without
+/// copying or modifying it, for callers such as tools reading a capture file
+/// that have a byte slice rather than a `BytesMut`. Returns the decoded
+/// messages and the number of bytes they occupy. A partial frame at the end of
+/// `buf` is not consumed, and the first frame that cannot be parsed is
+/// returned as an error.
+pub fn decode_slice(
+    buf: &[u8],
+) -> Result<(Vec<FastMessage>, usize), FastParseError> {
+    let mut msgs = Vec::new();
+    let mut consumed = 0;
+
+    while let FrameState::Complete { len } =
+        FastMessage::frame_state(&buf[consumed..])
+    {
+        msgs.push(FastMessage::parse(&buf[consumed..consumed + len])?);
+        consumed += len;
+    }
+
+    Ok((msgs, consumed))
+}
+
+/// Read a single Fast message from `reader`. This blocks until the complete
+/// header and then the complete data payload have been read, and reads no
+/// bytes beyond the end of the message. A message with a data payload longer
+/// than `FP_DEFAULT_MAX_PAYLOAD_LEN` is rejected without reading the payload.
+pub fn read_message<R: Read>(
+    reader: &mut R,
+) -> Result<FastMessage, FastParseError> {
+    read_message_with_limit(reader, FP_DEFAULT_MAX_PAYLOAD_LEN)
+}
+
+/// Read a single Fast message from `reader` as described for `read_message`,
+/// rejecting a message with a data payload longer than `max_payload_len`
+/// bytes before any memory is allocated for the payload.
+pub fn read_message_with_limit<R: Read>(
+    reader: &mut R,
+    max_payload_len: usize,
+) -> Result<FastMessage, FastParseError> {
+    let mut buf = vec![0; FP_HEADER_SZ];
+    reader.read_exact(&mut buf)?;
+
+    let data_len =
+        BigEndian::read_u32(&buf[FP_OFF_DATALEN..FP_OFF_DATALEN + 4]) as usize;
+    if data_len > max_payload_len {
+        return Err(payload_too_long(data_len, max_payload_len).into());
+    }
+    buf.resize(FP_HEADER_SZ + data_len, 0);
+    reader.read_exact(&mut buf[FP_HEADER_SZ..])?;
+
+    FastMessage::parse_with_limit(&buf, max_payload_len)
+}
+
+/// Returns a copy of the data payload of the already parsed frame `frame`, or
+/// `None` if the payload is compressed.
+fn raw_json_payload(frame: &[u8]) -> Option<Vec<u8>> {
+    match FastMessage::parse_header(frame) {
+        Ok(header) if !header.compressed => {
+            Some(frame[FP_OFF_DATA..FP_OFF_DATA + header.data_len].to_vec())
+        }
+        _ => None,
+    }
+}
+
+fn payload_too_long(data_len: usize, max_payload_len: usize) -> Error {
+    let msg = format!(
+        "Fast message payload of {} bytes exceeds the maximum of {} bytes",
+        data_len, max_payload_len
+    );
+    Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the CRC of a Fast message data payload, as stored in the message
+/// header. Fast uses the 16-bit CRC-16/ARC checksum, widened to 32 bits.
+pub fn compute_crc(data: &[u8]) -> u32 {
+    u32::from(State::<ARC>::calculate(data))
+}
+
+/// Check whether `expected`, the CRC from a Fast message header, matches the
+/// CRC of the data payload `data`.
+pub fn verify_crc(data: &[u8], expected: u32) -> bool {
+    compute_crc(data) == expected
+}
+
+/// Check whether the CRC stored in the header of the Fast message frame at the
+/// start of `buf` matches the CRC of its data payload. Only the CRC and data
+/// length fields of the header are read, so the frame is not otherwise
+/// validated. Returns a `FastParseError` if `buf` does not contain the complete
+/// frame.
+pub fn verify_frame_crc(buf: &[u8]) -> Result<bool, FastParseError> {
+    FastMessage::check_buffer_size(buf)?;
+    let expected_crc = BigEndian::read_u32(&buf[FP_OFF_CRC..FP_OFF_CRC + 4]);
+    let data_len =
+        BigEndian::read_u32(&buf[FP_OFF_DATALEN..FP_OFF_DATALEN + 4]) as usize;
+    FastMessage::validate_data_length(buf, data_len)?;
+
+    let raw_data = &buf[FP_OFF_DATA..FP_OFF_DATA + data_len];
+    Ok(verify_crc(raw_data, expected_crc))
+}
+
+/// Write a Fast message header into a byte buffer. The buffer must have room
+/// for at least `FP_HEADER_SZ` more bytes.
+pub(crate) fn encode_header(
+    msg_type_u8: u8,
+    status_u8: u8,
+    id: u32,
+    crc: u32,
+    data_len: usize,
+    buf: &mut BytesMut,
+) {
+    buf.put_u8(FP_VERSION_CURRENT);
+    buf.put_u8(msg_type_u8);
+    buf.put_u8(status_u8);
+    buf.put_u32_be(id);
+    buf.put_u32_be(crc);
+    buf.put_u32_be(data_len as u32);
+}
+
+/// Encode a `FastMessage` into a byte buffer as a single Fast message frame.
+/// Returns an error if the message data cannot be serialized.
+///
+/// ```
+/// use bytes::BytesMut;
+/// use fast_rpc::protocol::{self, FastMessage, FastMessageData};
+/// use serde_json::json;
+///
+/// let msg = FastMessage::data(
+///     1,
+///     FastMessageData::new(String::from("echo"), json!(["hello"])),
+/// );
+/// let mut buf = BytesMut::new();
+/// protocol::encode_msg(&msg, &mut buf)?;
+///
+/// let parsed = FastMessage::parse(&buf)?;
+/// assert_eq!(parsed.id, 1);
+/// assert_eq!(parsed.data, msg.data);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn encode_msg(msg: &FastMessage, buf: &mut BytesMut) -> Result<(), Error> {
+    let serialized = SerializedMsg::new(msg)?;
+    buf.reserve(serialized.frame_len());
+    serialized.write(buf);
+    Ok(())
+}
+
+/// The header fields and serialized data payload of a `FastMessage`, ready to
+/// be written to a buffer. Serializing first allows the size of a batch of
+/// messages to be known before any of it is written.
+struct SerializedMsg<'a> {
+    msg_type_u8: u8,
+    status_u8: u8,
+    id: u32,
+    payload: Cow<'a, [u8]>,
+}
+
+impl<'a> SerializedMsg<'a> {
+    fn new(msg: &'a FastMessage) -> Result<Self, Error> {
+        let payload = msg.payload_bytes().map_err(|e| {
+            Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to serialize message data: {}", e),
+            )
+        })?;
+        Ok(SerializedMsg {
+            msg_type_u8: msg.msg_type.clone().into(),
+            status_u8: msg.status.clone().into(),
+            id: msg.id,
+            payload,
+        })
+    }
+
+    /// Gzip compress the data payload, unless that would make it larger.
+    fn compress(mut self) -> Result<Self, Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.payload)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < self.payload.len() {
+            self.payload = Cow::Owned(compressed);
+            self.msg_type_u8 |= FP_TYPE_COMPRESSED;
         }
-        (None, Some(_)) => Err(String::from("Invalid message type")),
-        (Some(_), None) => Err(String::from("Invalid status")),
-        (None, None) => Err(String::from("Invalid message type and status")),
+        Ok(self)
+    }
+
+    fn frame_len(&self) -> usize {
+        FP_HEADER_SZ + self.payload.len()
+    }
+
+    /// Write the frame to `buf`, which must have room for `frame_len` bytes.
+    fn write(&self, buf: &mut BytesMut) {
+        let data_len = self.payload.len();
+        let crc = compute_crc(&self.payload);
+        encode_header(
+            self.msg_type_u8,
+            self.status_u8,
+            self.id,
+            crc,
+            data_len,
+            buf,
+        );
+        buf.put_slice(&self.payload);
     }
 }
 
@@ -451,14 +1873,39 @@ pub(crate) fn encode_msg(
 mod test {
     use super::*;
 
+    use std::collections::HashSet;
     use std::iter;
+    use std::sync::Mutex;
 
+    use num::ToPrimitive;
     use quickcheck::{quickcheck, Arbitrary, Gen};
     use rand::distributions::Alphanumeric;
     use rand::seq::SliceRandom;
-    use rand::Rng;
+    use rand::{Rng, SeedableRng};
     use serde_json::Map;
 
+    fn raw_frame(status: u8, id: u32, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(FP_HEADER_SZ + payload.len());
+        buf.put_u8(FP_VERSION_CURRENT);
+        buf.put_u8(FastMessageType::Json.to_u8().unwrap());
+        buf.put_u8(status);
+        buf.put_u32_be(id);
+        buf.put_u32_be(u32::from(State::<ARC>::calculate(payload)));
+        buf.put_u32_be(payload.len() as u32);
+        buf.put_slice(payload);
+        buf
+    }
+
+    fn nested_payload(depth: usize) -> String {
+        [
+            "{\"m\":{\"uts\":0,\"name\":\"nested\"},\"d\":",
+            &"[".repeat(depth),
+            &"]".repeat(depth),
+            "}",
+        ]
+        .concat()
+    }
+
     fn random_string<G: Gen>(g: &mut G, len: usize) -> String {
         iter::repeat(())
             .map(|()| g.sample(Alphanumeric))
@@ -560,6 +2007,7 @@ mod test {
                 id,
                 msg_size: msg_sz,
                 data,
+                payload: None,
             }
         }
     }
@@ -621,7 +2069,7 @@ mod test {
                 fast_msgs.push(msg.clone());
             });
 
-            let mut fast_rpc = FastRpc;
+            let mut fast_rpc = FastRpc::new();
             let encode_res = fast_rpc.encode(fast_msgs, &mut write_buf);
 
             if encode_res.is_err() {
@@ -653,4 +2101,1183 @@ mod test {
             !error_occurred
         }
     }
+
+    /// Generate frames from the `Arbitrary` implementations using a fixed
+    /// seed, corrupt a single byte of each, and check that neither parsing nor
+    /// decoding panics on the result.
+    #[test]
+    fn mutated_frames_do_not_panic() {
+        let rng = rand::rngs::StdRng::seed_from_u64(0x00fa_57f0);
+        let mut g = quickcheck::StdGen::new(rng, 100);
+
+        for _ in 0..5000 {
+            let msg = FastMessage::arbitrary(&mut g);
+            let mut frame = BytesMut::new();
+            encode_msg(&msg, &mut frame).unwrap();
+            assert_eq!(FastMessage::parse(&frame).unwrap(), msg);
+
+            let offset = g.gen_range(0, frame.len());
+            frame[offset] ^= g.gen_range(1, 0x100) as u8;
+
+            let _ = FastMessage::parse(&frame);
+            let _ = FastMessage::parse_borrowed(&frame);
+            let _ = decode_slice(&frame);
+            let _ = FastRpc::new().decode(&mut frame.clone());
+            let _ = FastRpc::new().lenient().decode(&mut frame);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_payload_is_rejected() {
+        let payload = nested_payload(10_000);
+        let mut buf = raw_frame(1, 1, payload.as_bytes());
+
+        match FastMessage::parse(&buf) {
+            Err(FastParseError::IOError(e)) => {
+                assert!(e.to_string().contains("nesting depth"))
+            }
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+
+        assert!(FastRpc::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn nested_payload_within_limit_is_accepted() {
+        let payload = nested_payload(FP_MAX_JSON_DEPTH / 2);
+        let buf = raw_frame(1, 1, payload.as_bytes());
+
+        assert!(FastMessage::parse(&buf).is_ok());
+    }
+
+    #[test]
+    fn terminal_and_data_statuses() {
+        let data = FastMessageData::new(String::from("echo"), Value::Null);
+
+        let data_msg = FastMessage::data(1, data.clone());
+        assert!(data_msg.is_data());
+        assert!(!data_msg.is_terminal());
+
+        let end_msg = FastMessage::end(1, String::from("echo"));
+        assert!(!end_msg.is_data());
+        assert!(end_msg.is_terminal());
+
+        let error_msg = FastMessage::error(1, data);
+        assert!(!error_msg.is_data());
+        assert!(error_msg.is_terminal());
+    }
+
+    fn roundtrip_number(number: &str) -> String {
+        let payload = [
+            "{\"m\":{\"uts\":0,\"name\":\"getobject\"},\"d\":[{\"size\":",
+            number,
+            "}]}",
+        ]
+        .concat();
+        let msg = FastMessage::parse(&raw_frame(1, 1, payload.as_bytes()))
+            .expect("failed to parse message");
+
+        let mut write_buf = BytesMut::new();
+        encode_msg(&msg, &mut write_buf).expect("failed to encode message");
+        let decoded =
+            FastMessage::parse(&write_buf).expect("failed to parse message");
+
+        decoded.data.d[0]["size"].to_string()
+    }
+
+    #[test]
+    fn large_integer_roundtrip() {
+        // 2^53 + 1 is the smallest integer an f64 cannot represent exactly
+        assert_eq!(roundtrip_number("9007199254740993"), "9007199254740993");
+        assert_eq!(
+            roundtrip_number("18446744073709551615"),
+            "18446744073709551615"
+        );
+    }
+
+    #[cfg(feature = "arbitrary-precision")]
+    #[test]
+    fn arbitrary_precision_roundtrip() {
+        let number = "340282366920938463463374607431768211457";
+        assert_eq!(roundtrip_number(number), number);
+        assert_eq!(
+            roundtrip_number("0.10000000000000000001"),
+            "0.10000000000000000001"
+        );
+    }
+
+    #[test]
+    fn reply_uses_request_id_and_method() {
+        let request = FastMessage::data(
+            42,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+
+        let reply = request.reply(json!(["world"]));
+        assert_eq!(reply.status, FastMessageStatus::Data);
+        assert_eq!(reply.id, 42);
+        assert_eq!(reply.data.m.name, "echo");
+        assert_eq!(reply.data.d, json!(["world"]));
+
+        let reply = request.reply_error("InvalidArgument", "bad input");
+        assert_eq!(reply.status, FastMessageStatus::Error);
+        assert_eq!(reply.id, 42);
+        assert_eq!(reply.data.m.name, "echo");
+        let err: FastMessageServerError =
+            serde_json::from_value(reply.data.d).unwrap();
+        assert_eq!(err.name, "InvalidArgument");
+        assert_eq!(err.message, "bad input");
+    }
+
+    #[test]
+    fn bad_payload_error_carries_msg_id() {
+        let buf = raw_frame(1, 0x1234, b"{\"m\":{\"uts\":0,\"name\":");
+
+        match FastMessage::parse(&buf) {
+            Err(FastParseError::BadPayload { id, .. }) => {
+                assert_eq!(id, 0x1234)
+            }
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let payload = b"{\"m\":{\"uts\":0,\"name\":\"echo\"},\"d\":[]}";
+        let mut buf = raw_frame(1, 1, payload);
+        buf[FP_OFF_VERSION] = 0xff;
+
+        match FastMessage::parse(&buf) {
+            Err(FastParseError::UnsupportedVersion(version)) => {
+                assert_eq!(version, 0xff)
+            }
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+        assert!(FastRpc::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn corrupted_payload_reports_crc_mismatch() {
+        let payload = b"{\"m\":{\"uts\":0,\"name\":\"echo\"},\"d\":[]}";
+        let mut buf = raw_frame(1, 1, payload);
+        let expected = u32::from(State::<ARC>::calculate(payload));
+        let last = buf.len() - 2;
+        buf[last] ^= 0x01;
+        let actual = u32::from(State::<ARC>::calculate(&buf[FP_HEADER_SZ..]));
+        assert_ne!(expected, actual);
+
+        match FastMessage::parse(&buf) {
+            Err(FastParseError::CrcMismatch {
+                expected: e,
+                actual: a,
+            }) => {
+                assert_eq!(e, expected);
+                assert_eq!(a, actual);
+            }
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_payload_is_reported() {
+        let buf = raw_frame(1, 7, b"{\"d\":[\"\xff\"]}");
+
+        match FastMessage::parse(&buf) {
+            Err(FastParseError::InvalidUtf8 { id, .. }) => assert_eq!(id, 7),
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frame_state_reports_missing_bytes() {
+        let payload = b"{\"m\":{\"uts\":0,\"name\":\"echo\"},\"d\":[]}";
+        let frame_len = FP_HEADER_SZ + payload.len();
+        let mut buf = raw_frame(1, 1, payload);
+
+        assert_eq!(
+            FastMessage::frame_state(&buf[..0]),
+            FrameState::NeedHeader {
+                missing: FP_HEADER_SZ
+            }
+        );
+        assert_eq!(
+            FastMessage::frame_state(&buf[..4]),
+            FrameState::NeedHeader {
+                missing: FP_HEADER_SZ - 4
+            }
+        );
+        assert_eq!(
+            FastMessage::frame_state(&buf[..FP_HEADER_SZ]),
+            FrameState::NeedBody {
+                missing: payload.len()
+            }
+        );
+        assert_eq!(
+            FastMessage::frame_state(&buf[..frame_len - 1]),
+            FrameState::NeedBody { missing: 1 }
+        );
+        assert_eq!(
+            FastMessage::frame_state(&buf),
+            FrameState::Complete { len: frame_len }
+        );
+
+        // Trailing bytes from a following frame do not change the length
+        buf.extend_from_slice(&[0; 3]);
+        assert_eq!(
+            FastMessage::frame_state(&buf),
+            FrameState::Complete { len: frame_len }
+        );
+    }
+
+    #[test]
+    fn sequential_id_allocator_increments() {
+        let allocator = FastMessageId::new();
+
+        assert_eq!(IdAllocator::next(&allocator), 0);
+        assert_eq!(IdAllocator::next(&allocator), 1);
+        assert_eq!(IdAllocator::next(&allocator), 2);
+    }
+
+    #[test]
+    fn random_id_allocator_ids_are_unique() {
+        let allocator = RandomIdAllocator::new();
+        let mut ids = HashSet::new();
+
+        for _ in 0..100_000 {
+            let id = allocator.next();
+            assert!(id <= 0x7fff_ffff);
+            assert!(ids.insert(id), "duplicate id {}", id);
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        decodes: Mutex<Vec<(usize, usize)>>,
+        messages: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl CodecMetrics for RecordingMetrics {
+        fn on_decode(&self, frames: usize, bytes: usize) {
+            self.decodes.lock().unwrap().push((frames, bytes));
+        }
+
+        fn on_message(&self, header_bytes: usize, payload_bytes: usize) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push((header_bytes, payload_bytes));
+        }
+    }
+
+    #[test]
+    fn decode_reports_frames_per_read() {
+        let metrics = Arc::new(RecordingMetrics::default());
+        let mut fast_rpc = FastRpc::new().with_metrics(metrics.clone());
+
+        let mut buf = BytesMut::new();
+        for id in 0..5 {
+            let data = FastMessageData::new(String::from("echo"), json!([id]));
+            encode_msg(&FastMessage::data(id, data), &mut buf).unwrap();
+        }
+        let total_bytes = buf.len();
+
+        let msgs = fast_rpc.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msgs.len(), 5);
+        assert_eq!(fast_rpc.decode(&mut buf).unwrap(), None);
+        assert_eq!(*metrics.decodes.lock().unwrap(), vec![(5, total_bytes)]);
+    }
+
+    #[test]
+    fn decode_reports_header_and_payload_bytes() {
+        let metrics = Arc::new(RecordingMetrics::default());
+        let mut fast_rpc = FastRpc::new().with_metrics(metrics.clone());
+        let data = FastMessageData::new(String::from("echo"), json!([1]));
+        let payload_len = serde_json::to_string(&data).unwrap().len();
+
+        let mut buf = BytesMut::new();
+        encode_msg(&FastMessage::data(1, data), &mut buf).unwrap();
+        fast_rpc.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(
+            *metrics.messages.lock().unwrap(),
+            vec![(FP_HEADER_SZ, payload_len)]
+        );
+    }
+
+    #[test]
+    fn server_error_name_inferred_from_io_error_kind() {
+        let cases = vec![
+            (io::ErrorKind::NotFound, "NotFoundError"),
+            (io::ErrorKind::InvalidInput, "InvalidArgumentError"),
+            (io::ErrorKind::TimedOut, "TimeoutError"),
+            (io::ErrorKind::ConnectionReset, "ConnectionError"),
+            (io::ErrorKind::Other, "FastError"),
+        ];
+        for (kind, name) in cases {
+            let err =
+                FastMessageServerError::from_io(&Error::new(kind, "oops"));
+            assert_eq!(err, FastMessageServerError::new(name, "oops"));
+        }
+
+        let server_err = FastMessageServerError::new("BucketNotFound", "nope");
+        let err = Error::from(server_err.clone());
+        assert_eq!(FastMessageServerError::from_io(&err), server_err);
+    }
+
+    #[test]
+    fn message_id_wraps_at_31_bits() {
+        let mut msg_id =
+            FastMessageId(AtomicUsize::new(FP_MAX_MSGID as usize - 1));
+        let ids: Vec<usize> = msg_id.by_ref().take(3).collect();
+        assert_eq!(
+            ids,
+            vec![FP_MAX_MSGID as usize - 1, FP_MAX_MSGID as usize, 0]
+        );
+
+        let allocator = FastMessageId(AtomicUsize::new(FP_MAX_MSGID as usize));
+        assert_eq!(IdAllocator::next(&allocator), FP_MAX_MSGID);
+        assert_eq!(IdAllocator::next(&allocator), 0);
+    }
+
+    #[test]
+    fn message_id_shared_between_threads() {
+        let msg_id = Arc::new(FastMessageId::new());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let msg_id = Arc::clone(&msg_id);
+                std::thread::spawn(move || {
+                    (0..1000)
+                        .map(|_| IdAllocator::next(&*msg_id))
+                        .collect::<Vec<u32>>()
+                })
+            })
+            .collect();
+
+        let mut ids = HashSet::new();
+        for thread in threads {
+            for id in thread.join().unwrap() {
+                assert!(ids.insert(id), "duplicate id {}", id);
+            }
+        }
+        assert_eq!(ids.len(), 4000);
+    }
+
+    #[test]
+    fn decode_rejects_oversized_payload_from_header() {
+        let mut buf = BytesMut::with_capacity(FP_HEADER_SZ);
+        encode_header(
+            FastMessageType::Json.to_u8().unwrap(),
+            FastMessageStatus::Data.to_u8().unwrap(),
+            1,
+            0,
+            0xffff_ffff,
+            &mut buf,
+        );
+        let err = FastRpc::new().decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let msg = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!(["a".repeat(20)])),
+        );
+        let mut buf = BytesMut::new();
+        encode_msg(&msg, &mut buf).unwrap();
+        let mut partial = BytesMut::from(&buf[..FP_HEADER_SZ]);
+        let mut rpc = FastRpc::new().with_max_payload_len(10);
+        assert!(rpc.decode(&mut partial).is_err());
+        let mut rpc = FastRpc::new().with_max_payload_len(buf.len());
+        let decoded = rpc.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded[0].data, msg.data);
+    }
+
+    #[test]
+    fn decode_advances_by_wire_length() {
+        // Whitespace, an escaped character and a float that serde_json would
+        // write differently all change the length of the payload if it is
+        // serialized again.
+        let payload = br#"{ "m": {"uts": 1, "name": "\u0065cho"},
+                            "d": [1.50, {"b": 1, "a": 2}] }"#;
+        let mut buf = raw_frame(1, 1, payload);
+        buf.extend_from_slice(&raw_frame(1, 2, payload));
+        let reserialized = FastMessage::parse(&buf).unwrap().data;
+        assert_ne!(
+            serde_json::to_vec(&reserialized).unwrap().len(),
+            payload.len()
+        );
+
+        let msgs = FastRpc::new().decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        let ids: Vec<u32> = msgs.iter().map(|msg| msg.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(msgs.iter().all(|msg| msg.data.m.name == "echo"));
+    }
+
+    #[test]
+    fn decode_slice_reports_consumed_bytes() {
+        let msgs: Vec<FastMessage> = (0..3)
+            .map(|id| {
+                FastMessage::data(
+                    id,
+                    FastMessageData::new(
+                        String::from("echo"),
+                        json!(["x".repeat(id as usize * 10)]),
+                    ),
+                )
+            })
+            .collect();
+        let mut frames = BytesMut::new();
+        let mut frame_sizes = 0;
+        for msg in &msgs {
+            let start = frames.len();
+            encode_msg(msg, &mut frames).unwrap();
+            frame_sizes += frames.len() - start;
+        }
+        // A partial frame at the end is left for the caller.
+        let mut buf = frames.to_vec();
+        buf.extend_from_slice(&frames[..FP_HEADER_SZ + 2]);
+
+        let (decoded, consumed) = decode_slice(&buf).unwrap();
+        assert_eq!(consumed, frame_sizes);
+        assert_eq!(decoded.len(), msgs.len());
+        for (decoded, msg) in decoded.iter().zip(msgs.iter()) {
+            assert_eq!(decoded.id, msg.id);
+            assert_eq!(decoded.data, msg.data);
+        }
+    }
+
+    #[test]
+    fn error_payload_formats_round_trip() {
+        let err = FastMessageServerError::new("FastError", "boom");
+        let value = serde_json::to_value(&err).unwrap();
+
+        let object = ErrorPayloadFormat::Object.encode(value.clone());
+        assert_eq!(object, json!({"name": "FastError", "message": "boom"}));
+        let array = ErrorPayloadFormat::Array.encode(value);
+        assert_eq!(array, json!([{"name": "FastError", "message": "boom"}]));
+
+        for d in [object, array] {
+            assert_eq!(FastMessageServerError::from_payload(&d).unwrap(), err);
+            let msg = FastMessage::error(
+                1,
+                FastMessageData::new(String::from("echo"), d),
+            );
+            assert_eq!(msg.validate_conformance(), Ok(()));
+        }
+        assert!(FastMessageServerError::from_payload(&json!([])).is_err());
+    }
+
+    #[test]
+    fn fast_message_json_round_trip() {
+        let payload = br#"{"m":{"uts":5,"name":"echo"},"d":["hello"]}"#;
+        let msg = FastMessage::parse(&raw_frame(3, 9, payload)).unwrap();
+
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "msg_type": 1,
+                "status": 3,
+                "id": 9,
+                "data": {"m": {"uts": 5, "name": "echo"}, "d": ["hello"]}
+            })
+        );
+
+        let mut restored: FastMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.msg_size, None);
+        restored.msg_size = msg.msg_size;
+        assert_eq!(restored, msg);
+
+        let bad_status = json!({
+            "msg_type": 1,
+            "status": 7,
+            "id": 9,
+            "data": {"m": {"uts": 5, "name": "echo"}, "d": []}
+        });
+        assert!(serde_json::from_value::<FastMessage>(bad_status).is_err());
+    }
+
+    #[test]
+    fn describe_format() {
+        let mut msg = FastMessage::data(
+            42,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        msg.data.m.uts = 1;
+        let payload = br#"{"m":{"uts":1,"name":"echo"},"d":["hello"]}"#;
+
+        assert_eq!(
+            msg.describe(),
+            format!(
+                "DATA id=42 method=echo payload={}B crc={:#06x}",
+                payload.len(),
+                State::<ARC>::calculate(payload)
+            )
+        );
+        assert!(FastMessage::end(7, String::from("echo"))
+            .describe()
+            .starts_with("END id=7 method=echo payload="));
+    }
+
+    #[test]
+    fn overhead_ratio_reflects_payload_size() {
+        let small = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!([])),
+        );
+        let large = FastMessage::data(
+            1,
+            FastMessageData::new(
+                String::from("echo"),
+                json!(["x".repeat(10_000)]),
+            ),
+        );
+
+        assert!(small.overhead_ratio() > 0.2);
+        assert!(large.overhead_ratio() < 0.01);
+        assert!(small.overhead_ratio() < 1.0);
+    }
+
+    #[test]
+    fn fixed_clock_metadata_is_deterministic() {
+        let clock = FixedClock(1_457_475_515_355_000);
+        let first =
+            FastMessageMetaData::new_with_clock(String::from("date"), &clock);
+        let second =
+            FastMessageMetaData::new_with_clock(String::from("date"), &clock);
+
+        assert_eq!(first.uts, 1_457_475_515_355_000);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn moray_error_details_are_captured() {
+        let payload = json!({
+            "name": "MultiError",
+            "message": "first of 2 errors: bucket not found",
+            "context": {"bucket": "manta_storage"},
+            "ase_errors": [
+                {
+                    "name": "BucketNotFoundError",
+                    "message": "manta_storage does not exist",
+                    "context": {"bucket": "manta_storage"}
+                },
+                {
+                    "name": "NoDatabasePeersError",
+                    "message": "no peers available"
+                }
+            ]
+        });
+
+        let err: FastMessageServerError =
+            serde_json::from_value(payload).unwrap();
+        assert_eq!(err.name, "MultiError");
+        assert_eq!(err.context, Some(json!({"bucket": "manta_storage"})));
+        assert_eq!(err.ase_errors.len(), 2);
+        assert_eq!(err.ase_errors[0].name, "BucketNotFoundError");
+        assert_eq!(
+            err.ase_errors[0].context,
+            Some(json!({"bucket": "manta_storage"}))
+        );
+        assert_eq!(err.ase_errors[1].name, "NoDatabasePeersError");
+        assert!(err.ase_errors[1].ase_errors.is_empty());
+
+        let io_err: Error = err.clone().into();
+        assert_eq!(
+            io_err.to_string(),
+            "MultiError: first of 2 errors: bucket not found"
+        );
+        let inner = io_err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<FastMessageServerError>());
+        assert_eq!(inner, Some(&err));
+    }
+
+    #[test]
+    fn plain_server_error_omits_optional_fields() {
+        let err = FastMessageServerError::new("FastError", "boom");
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            json!({"name": "FastError", "message": "boom"})
+        );
+    }
+
+    #[test]
+    fn indexed_data_roundtrip() {
+        let method = String::from("batch");
+        let msgs = vec![
+            FastMessage::data_indexed(9, method.clone(), 2, json!("c")),
+            FastMessage::data_indexed(9, method.clone(), 0, json!({"a": 1})),
+            FastMessage::data_indexed(9, method.clone(), 1, Value::Null),
+        ];
+
+        let mut buf = BytesMut::new();
+        let mut fast_rpc = FastRpc::new();
+        fast_rpc.encode(msgs, &mut buf).unwrap();
+        let decoded = fast_rpc.decode(&mut buf).unwrap().unwrap();
+
+        let results: Vec<(usize, Value)> = decoded
+            .iter()
+            .map(|msg| {
+                assert_eq!(msg.id, 9);
+                let (index, value) = msg.indexed_value().unwrap();
+                (index, value.clone())
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![(2, json!("c")), (0, json!({"a": 1})), (1, Value::Null)]
+        );
+
+        let plain = FastMessage::data(
+            9,
+            FastMessageData::new(method.clone(), json!(["c"])),
+        );
+        assert_eq!(plain.indexed_value(), None);
+        assert_eq!(FastMessage::end(9, method).indexed_value(), None);
+    }
+
+    fn three_echo_messages() -> Vec<FastMessage> {
+        (1..=3)
+            .map(|id| {
+                let data =
+                    FastMessageData::new(String::from("echo"), json!([id]));
+                FastMessage::data(id, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lenient_decode_skips_corrupted_frame() {
+        let mut buf = BytesMut::new();
+        FastRpc::new()
+            .inject_fault(2, Fault::CorruptCrc)
+            .encode(three_echo_messages(), &mut buf)
+            .unwrap();
+
+        let mut strict_buf = buf.clone();
+        assert!(FastRpc::new().decode(&mut strict_buf).is_err());
+
+        let decoded =
+            FastRpc::new().lenient().decode(&mut buf).unwrap().unwrap();
+        let ids: Vec<u32> = decoded.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn conforming_messages_validate() {
+        let request = FastMessage::data(
+            FP_MAX_MSGID,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        assert_eq!(request.validate_conformance(), Ok(()));
+        assert_eq!(
+            FastMessage::end(1, String::from("echo")).validate_conformance(),
+            Ok(())
+        );
+        let mut end_with_data = FastMessage::end(1, String::from("echo"));
+        end_with_data.data.d = json!(["last"]);
+        assert_eq!(end_with_data.validate_conformance(), Ok(()));
+        let resume =
+            FastMessage::end_with_resume(1, String::from("echo"), json!(7));
+        assert_eq!(resume.validate_conformance(), Ok(()));
+        assert_eq!(
+            request
+                .reply_error("FastError", "boom")
+                .validate_conformance(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn nonconforming_messages_fail_validation() {
+        let echo = |d| FastMessageData::new(String::from("echo"), d);
+
+        let big_id = FastMessage::data(FP_MAX_MSGID + 1, echo(json!([])));
+        assert!(big_id.validate_conformance().is_err());
+
+        let no_method = FastMessage::data(
+            1,
+            FastMessageData::new(String::new(), json!([])),
+        );
+        assert!(no_method.validate_conformance().is_err());
+
+        let object_data = FastMessage::data(1, echo(json!({"a": 1})));
+        assert!(object_data.validate_conformance().is_err());
+
+        let mut end = FastMessage::end(1, String::from("echo"));
+        end.data.d = json!({"unexpected": true});
+        assert!(end.validate_conformance().is_err());
+
+        let bad_error = FastMessage::error(1, echo(json!(["not an error"])));
+        assert!(bad_error.validate_conformance().is_err());
+    }
+
+    #[test]
+    fn encode_echo_copies_request_frame() {
+        let payload = br#"{"m":{"uts":5,"name":"echo"},"d":[ "spaced" ]}"#;
+        let request = raw_frame(1, 6, payload);
+
+        let mut buf = BytesMut::new();
+        assert_eq!(encode_echo(&request, &mut buf).unwrap(), request.len());
+        assert_eq!(&buf[..request.len()], &request[..]);
+
+        let end = FastMessage::parse(&buf[request.len()..]).unwrap();
+        assert_eq!(end.status, FastMessageStatus::End);
+        assert_eq!(end.id, 6);
+
+        let end_frame = raw_frame(2, 6, payload);
+        assert!(encode_echo(&end_frame, &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn bare_end_omits_method_name() {
+        let mut buf = BytesMut::new();
+        encode_msg(&FastMessage::end_bare(4), &mut buf).unwrap();
+        let payload: Value =
+            serde_json::from_slice(&buf[FP_HEADER_SZ..]).unwrap();
+        assert!(payload["m"].get("name").is_none());
+
+        let msg = FastMessage::parse(&buf).unwrap();
+        assert_eq!(msg.status, FastMessageStatus::End);
+        assert_eq!(msg.id, 4);
+        assert_eq!(msg.data.m.name, "");
+        assert_eq!(msg.validate_conformance(), Ok(()));
+    }
+
+    #[test]
+    fn empty_method_name_is_kept_on_data_messages() {
+        let msg = FastMessage::data(
+            4,
+            FastMessageData::new(String::new(), json!(["value"])),
+        );
+        let mut buf = BytesMut::new();
+        encode_msg(&msg, &mut buf).unwrap();
+        let payload: Value =
+            serde_json::from_slice(&buf[FP_HEADER_SZ..]).unwrap();
+        assert_eq!(payload["m"]["name"], "");
+    }
+
+    #[test]
+    fn decode_each_calls_back_per_frame() {
+        let msgs: Vec<FastMessage> = (0..1000)
+            .map(|id| {
+                let data =
+                    FastMessageData::new(String::from("echo"), json!([id]));
+                FastMessage::data(id, data)
+            })
+            .collect();
+        let mut buf = BytesMut::new();
+        FastRpc::new().encode(msgs, &mut buf).unwrap();
+
+        let mut ids = Vec::new();
+        let decoded = FastRpc::new()
+            .decode_each(&mut buf, |msg| {
+                ids.push(msg.id);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(decoded, 1000);
+        assert_eq!(ids, (0..1000).collect::<Vec<u32>>());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_resumes_frame_split_across_calls() {
+        let msg = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!(["split"])),
+        );
+        let mut frame = BytesMut::new();
+        FastRpc::new()
+            .encode(vec![msg.clone()], &mut frame)
+            .unwrap();
+        let half = frame.len() / 2;
+
+        let mut codec = FastRpc::new();
+        let mut buf = BytesMut::from(&frame[..half]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], &frame[..half]);
+
+        buf.extend_from_slice(&frame[half..]);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].data, msg.data);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn heterogeneous_batch_round_trip() {
+        let batch: Vec<FastMessage> = (0..2000)
+            .map(|i| {
+                let d = match i % 3 {
+                    0 => json!([i]),
+                    1 => json!([{"key": "x".repeat((i % 500) as usize)}]),
+                    _ => json!([vec![i; (i % 200) as usize]]),
+                };
+                let data = FastMessageData::new(String::from("mixed"), d);
+                match i % 4 {
+                    3 => FastMessage::error(i, data),
+                    _ => FastMessage::data(i, data),
+                }
+            })
+            .collect();
+
+        let mut buf = BytesMut::new();
+        FastRpc::new().encode(batch.clone(), &mut buf).unwrap();
+        let decoded = FastRpc::new().decode(&mut buf).unwrap().unwrap();
+
+        assert!(buf.is_empty());
+        assert_eq!(decoded.len(), batch.len());
+        for (expected, actual) in batch.iter().zip(decoded.iter()) {
+            assert_eq!(actual.id, expected.id);
+            assert_eq!(actual.status, expected.status);
+            assert_eq!(actual.data, expected.data);
+        }
+    }
+
+    #[test]
+    fn missing_uts_defaults_to_zero() {
+        let payload = b"{\"m\":{\"name\":\"echo\"},\"d\":[]}";
+        let buf = raw_frame(1, 1, payload);
+
+        let msg = FastMessage::parse(&buf).unwrap();
+        assert_eq!(msg.data.m.uts, 0);
+        assert_eq!(msg.data.m.name, "echo");
+
+        let msg = FastMessage::parse_borrowed(&buf).unwrap();
+        assert_eq!(msg.data.m.uts, 0);
+    }
+
+    #[test]
+    fn parse_borrowed_refers_to_buffer() {
+        let payload = b"{\"m\":{\"uts\":5,\"name\":\"echo\"},\"d\":[1]}";
+        let buf = raw_frame(1, 3, payload);
+        let msg = FastMessage::parse_borrowed(&buf).unwrap();
+
+        assert_eq!(msg.id, 3);
+        assert_eq!(msg.msg_size, Some(buf.len()));
+        assert_eq!(msg.data.m.uts, 5);
+        assert_eq!(msg.data.m.name, "echo");
+        assert_eq!(msg.data.d, json!([1]));
+
+        let name = msg.data.m.name.as_bytes().as_ptr_range();
+        let frame = buf.as_ptr_range();
+        assert!(frame.start <= name.start && name.end <= frame.end);
+    }
+
+    #[test]
+    fn invalid_status_mid_batch() {
+        let payload = b"{\"m\":{\"uts\":0,\"name\":\"echo\"},\"d\":[]}";
+        let mut buf = raw_frame(1, 1, payload);
+        buf.extend_from_slice(&raw_frame(9, 2, payload));
+        buf.extend_from_slice(&raw_frame(1, 3, payload));
+
+        match FastMessage::parse(&buf[FP_HEADER_SZ + payload.len()..]) {
+            Err(FastParseError::InvalidStatus { id, status }) => {
+                assert_eq!((id, status), (2, 9));
+            }
+            _ => panic!("expected an invalid status error"),
+        }
+
+        let mut strict_buf = buf.clone();
+        let err = FastRpc::new().decode(&mut strict_buf).unwrap_err();
+        assert!(err.to_string().contains("unknown status 0x09"));
+
+        let decoded =
+            FastRpc::new().lenient().decode(&mut buf).unwrap().unwrap();
+        let ids: Vec<u32> = decoded.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn injected_faults_change_frame_length() {
+        let mut clean_buf = BytesMut::new();
+        FastRpc::new()
+            .encode(three_echo_messages(), &mut clean_buf)
+            .unwrap();
+
+        let mut buf = BytesMut::new();
+        FastRpc::new()
+            .inject_fault(1, Fault::Truncate(4))
+            .inject_fault(3, Fault::ExtraByte)
+            .encode(three_echo_messages(), &mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), clean_buf.len() - 3);
+
+        // The truncated first frame swallows the start of the second, so the
+        // stream can no longer be parsed.
+        assert!(FastRpc::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn from_bytes_iterates_concatenated_frames() {
+        let first = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!(["first"])),
+        );
+        let second = FastMessage::end(1, String::from("echo"));
+        let mut buf = first.to_bytes().unwrap();
+        let first_len = buf.len();
+        buf.extend_from_slice(&second.to_bytes().unwrap());
+
+        let (msg, len) = FastMessage::from_bytes(&buf).unwrap();
+        assert_eq!(len, first_len);
+        assert_eq!(msg.data, first.data);
+
+        let (msg, len) = FastMessage::from_bytes(&buf[first_len..]).unwrap();
+        assert_eq!(first_len + len, buf.len());
+        assert_eq!(msg.status, FastMessageStatus::End);
+        assert_eq!(msg.data, second.data);
+
+        match FastMessage::from_bytes(&buf[..first_len - 1]) {
+            Err(FastParseError::NotEnoughBytes(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compressed_payload_round_trip() {
+        let large = FastMessage::data(
+            1,
+            FastMessageData::new(
+                String::from("getobject"),
+                json!(["metadata".repeat(10_000)]),
+            ),
+        );
+        let small = FastMessage::end(1, String::from("getobject"));
+        let msgs = vec![large.clone(), small.clone()];
+
+        let mut plain = BytesMut::new();
+        FastRpc::new().encode(msgs.clone(), &mut plain).unwrap();
+        let mut compressed = BytesMut::new();
+        FastRpc::new()
+            .with_compression(true)
+            .encode(msgs, &mut compressed)
+            .unwrap();
+        assert!(compressed.len() * 10 < plain.len());
+        assert_eq!(compressed[FP_OFF_TYPE], 0x81);
+
+        let decoded = FastRpc::new().decode(&mut compressed).unwrap().unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].msg_type, FastMessageType::Json);
+        assert_eq!(decoded[0].data, large.data);
+        // The small END message does not shrink, so it is sent uncompressed.
+        assert_eq!(decoded[1].data, small.data);
+        assert_eq!(FastMessage::parse(&plain).unwrap().data, large.data);
+    }
+
+    #[test]
+    fn negotiation_chooses_first_supported_method() {
+        let request = negotiation_request(1, &["lz4", FP_COMPRESSION_GZIP]);
+        let (responses, chosen) =
+            negotiation_response(&request, &[FP_COMPRESSION_GZIP]);
+        assert_eq!(chosen.as_deref(), Some(FP_COMPRESSION_GZIP));
+        assert_eq!(negotiated_compression(&responses[0]), chosen);
+        assert_eq!(responses[1].status, FastMessageStatus::End);
+
+        let request = negotiation_request(2, &["lz4"]);
+        let (responses, chosen) =
+            negotiation_response(&request, &[FP_COMPRESSION_GZIP]);
+        assert_eq!(chosen, None);
+        assert_eq!(negotiated_compression(&responses[0]), None);
+    }
+
+    #[test]
+    fn decompressed_payload_is_limited_by_codec() {
+        // About 2 MiB of JSON that compresses to a few KiB.
+        let msg = FastMessage::data(
+            1,
+            FastMessageData::new(
+                String::from("getobject"),
+                json!(["x".repeat(2 * 1024 * 1024)]),
+            ),
+        );
+        let mut compressed = BytesMut::new();
+        FastRpc::new()
+            .with_compression(true)
+            .encode(vec![msg], &mut compressed)
+            .unwrap();
+        assert!(compressed.len() < 1024 * 1024);
+
+        let mut small = FastRpc::new().with_max_payload_len(1024 * 1024);
+        assert!(small.decode(&mut compressed.clone()).is_err());
+
+        let mut large = FastRpc::new().with_max_payload_len(4 * 1024 * 1024);
+        assert!(large.decode(&mut compressed).unwrap().is_some());
+    }
+
+    #[test]
+    fn decompression_allows_payloads_above_default_limit() {
+        let len = FP_DEFAULT_MAX_PAYLOAD_LEN + 1024;
+        let msg = FastMessage::data(
+            1,
+            FastMessageData::new(
+                String::from("getobject"),
+                json!(["x".repeat(len)]),
+            ),
+        );
+        let mut compressed = BytesMut::new();
+        FastRpc::new()
+            .with_compression(true)
+            .encode(vec![msg.clone()], &mut compressed)
+            .unwrap();
+
+        let mut rpc = FastRpc::new().with_max_payload_len(2 * len);
+        let decoded = rpc.decode(&mut compressed).unwrap().unwrap();
+        assert_eq!(decoded[0].data, msg.data);
+    }
+
+    #[test]
+    fn binary_message_round_trip() {
+        // Not valid UTF-8 or JSON, so this must bypass JSON encoding.
+        let payload = vec![0xff, 0x00, 0x7b, 0x80, 0x5d];
+        let msg = FastMessage::data_binary(0x1234, payload.clone());
+        let mut buf = BytesMut::new();
+        encode_msg(&msg, &mut buf).unwrap();
+
+        assert_eq!(buf[FP_OFF_TYPE], 2);
+        assert_eq!(&buf[FP_OFF_DATA..], &payload[..]);
+        assert!(verify_frame_crc(&buf).unwrap());
+
+        let decoded = FastMessage::parse(&buf).unwrap();
+        assert_eq!(decoded.msg_type, FastMessageType::Binary);
+        assert_eq!(decoded.status, FastMessageStatus::Data);
+        assert_eq!(decoded.id, 0x1234);
+        assert_eq!(decoded.payload, Some(payload.clone()));
+        assert_eq!(decoded.msg_size, Some(buf.len()));
+
+        let borrowed = FastMessage::parse_borrowed(&buf).unwrap();
+        assert_eq!(borrowed.payload, Some(&payload[..]));
+
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
+        match FastMessage::parse(&buf) {
+            Err(FastParseError::CrcMismatch { .. }) => (),
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_and_json_messages_decode_together() {
+        let json_msg = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        let binary_msg = FastMessage::data_binary(1, b"hello".to_vec());
+        let mut buf = BytesMut::new();
+        FastRpc::new()
+            .encode(vec![json_msg.clone(), binary_msg.clone()], &mut buf)
+            .unwrap();
+
+        let msgs = FastRpc::new().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].data, json_msg.data);
+        assert_eq!(msgs[0].payload, None);
+        assert_eq!(msgs[1].payload, binary_msg.payload);
+    }
+
+    #[test]
+    fn crc_matches_known_answer() {
+        // The standard check value for CRC-16/ARC.
+        assert_eq!(compute_crc(b"123456789"), 0xbb3d);
+        assert!(verify_crc(b"123456789", 0xbb3d));
+        assert!(!verify_crc(b"123456780", 0xbb3d));
+        assert_eq!(compute_crc(b""), 0);
+    }
+
+    #[test]
+    fn verify_frame_crc_detects_corruption() {
+        let payload = b"{\"m\":{\"uts\":0,\"name\":\"echo\"},\"d\":[]}";
+        let mut buf = raw_frame(1, 1, payload);
+        assert!(verify_frame_crc(&buf).unwrap());
+
+        let last = buf.len() - 2;
+        buf[last] ^= 0x01;
+        assert!(!verify_frame_crc(&buf).unwrap());
+
+        match verify_frame_crc(&buf[..FP_HEADER_SZ - 1]) {
+            Err(FastParseError::NotEnoughBytes(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match verify_frame_crc(&buf[..buf.len() - 1]) {
+            Err(FastParseError::NotEnoughBytes(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_message_reads_one_frame_at_a_time() {
+        let mut buf = BytesMut::new();
+        let first = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!(["first"])),
+        );
+        let second = FastMessage::end(1, String::from("echo"));
+        encode_msg(&first, &mut buf).unwrap();
+        encode_msg(&second, &mut buf).unwrap();
+        let mut cursor = io::Cursor::new(buf.to_vec());
+
+        let msg = read_message(&mut cursor).unwrap();
+        assert_eq!(msg.data, first.data);
+        assert_eq!(cursor.position() as usize, msg.msg_size.unwrap());
+
+        let msg = read_message(&mut cursor).unwrap();
+        assert_eq!(msg.status, FastMessageStatus::End);
+        assert_eq!(msg.data, second.data);
+        assert_eq!(cursor.position() as usize, buf.len());
+
+        match read_message(&mut cursor) {
+            Err(FastParseError::IOError(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_message_rejects_oversized_payload() {
+        // A header claiming a 4 GiB payload, with no payload following it.
+        let mut header = vec![0; FP_HEADER_SZ];
+        header[FP_OFF_VERSION] = FP_VERSION_CURRENT;
+        BigEndian::write_u32(
+            &mut header[FP_OFF_DATALEN..FP_OFF_DATALEN + 4],
+            u32::MAX,
+        );
+
+        match read_message(&mut io::Cursor::new(header.clone())) {
+            Err(FastParseError::IOError(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut buf = BytesMut::new();
+        let msg = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        encode_msg(&msg, &mut buf).unwrap();
+        let mut cursor = io::Cursor::new(buf.to_vec());
+        assert!(read_message_with_limit(&mut cursor, 4).is_err());
+        let mut cursor = io::Cursor::new(buf.to_vec());
+        assert!(read_message_with_limit(&mut cursor, buf.len()).is_ok());
+    }
+
+    #[test]
+    fn parse_error_display() {
+        let err = FastParseError::NotEnoughBytes(3);
+        assert_eq!(
+            err.to_string(),
+            "Unable to parse message: not enough bytes (3 available)"
+        );
+        assert!(error::Error::source(&err).is_none());
+
+        let err = FastParseError::IOError(Error::other("boom"));
+        assert_eq!(err.to_string(), "boom");
+        let err: Box<dyn error::Error> = Box::new(err);
+        assert_eq!(err.to_string(), "boom");
+    }
 }
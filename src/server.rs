@@ -2,100 +2,3700 @@
 
 //! This module provides the interface for creating Fast servers.
 
-use std::io::Error;
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, mem, vec};
 
-use serde_json::json;
-use slog::{debug, error, o, Drain, Logger};
+use futures::sink;
+use futures::sync::mpsc;
+use futures::task::{self, Task};
+use futures::StartSend;
+#[cfg(feature = "schema")]
+use jsonschema::Validator;
+use serde_json::{json, Value};
+use slog::{debug, error, info, o, warn, Drain, Logger};
 use tokio;
 use tokio::codec::Decoder;
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::timer::{Delay, Interval};
 
-use crate::protocol::{FastMessage, FastMessageData, FastRpc};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::Session;
+#[cfg(feature = "tracing")]
+use tracing_futures::Instrument;
+
+use crate::client::BlockingFastClient;
+use crate::protocol::{
+    self, ErrorPayloadFormat, FastMessage, FastMessageData,
+    FastMessageServerError, FastRpc, FP_COMPRESSION_GZIP,
+    FP_DEFAULT_MAX_PAYLOAD_LEN, FP_HEADER_SZ, FP_NEGOTIATE_METHOD,
+};
+
+/// The default number of streamed requests that may be partially received on
+/// a connection at once. See `TaskOptions::max_partial_requests`.
+pub const DEFAULT_MAX_PARTIAL_REQUESTS: usize = 64;
+
+/// Options that control how a task created by `make_task_with_options` handles
+/// a connection.
+#[derive(Clone, Default)]
+pub struct TaskOptions {
+    max_requests: Option<usize>,
+    in_flight_limit: Option<InFlightLimit>,
+    heartbeat_interval: Option<Duration>,
+    #[cfg(feature = "schema")]
+    schema_registry: Option<Arc<SchemaRegistry>>,
+    metrics: Option<Arc<dyn ServerMetrics>>,
+    error_format: ErrorPayloadFormat,
+    response_hook: Option<Arc<ResponseHook>>,
+    slow_request_threshold: Option<Duration>,
+    quiesce: Option<Quiesce>,
+    shutdown: Option<Shutdown>,
+    idle_timeout: Option<Duration>,
+    max_reassembled_len: Option<usize>,
+    max_partial_requests: Option<usize>,
+    negotiate_compression: bool,
+    request_deadline: Option<Duration>,
+    raw_payloads: bool,
+}
+
+/// A function applied to every response message by `TaskOptions::response_hook`.
+pub type ResponseHook = dyn Fn(&mut FastMessage) + Send + Sync;
+
+impl fmt::Debug for TaskOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("TaskOptions");
+        d.field("max_requests", &self.max_requests)
+            .field("in_flight_limit", &self.in_flight_limit)
+            .field("heartbeat_interval", &self.heartbeat_interval);
+        #[cfg(feature = "schema")]
+        d.field("schema_registry", &self.schema_registry);
+        d.field("metrics", &self.metrics.is_some())
+            .field("error_format", &self.error_format)
+            .field("response_hook", &self.response_hook.is_some())
+            .field("slow_request_threshold", &self.slow_request_threshold)
+            .field("quiesce", &self.quiesce)
+            .field("shutdown", &self.shutdown)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_reassembled_len", &self.max_reassembled_len)
+            .field("max_partial_requests", &self.max_partial_requests)
+            .field("negotiate_compression", &self.negotiate_compression)
+            .field("request_deadline", &self.request_deadline)
+            .field("raw_payloads", &self.raw_payloads)
+            .finish()
+    }
+}
+
+impl TaskOptions {
+    /// Creates a new TaskOptions with the default settings
+    pub fn new() -> Self {
+        TaskOptions::default()
+    }
+
+    /// Close the connection once `max_requests` requests have been responded
+    /// to. Any further requests that have already been received on the
+    /// connection are not responded to. By default there is no limit.
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Limit the number of requests handled at once using `limit`. Clones of
+    /// an `InFlightLimit` share their count, so using the same limit for every
+    /// connection limits the requests in flight across the whole server.
+    pub fn in_flight_limit(mut self, limit: InFlightLimit) -> Self {
+        self.in_flight_limit = Some(limit);
+        self
+    }
+
+    /// While a batch of requests is being handled, send a heartbeat `DATA`
+    /// message with an empty `d` array for each request every `interval` to
+    /// keep the connection from appearing idle. Clients see a heartbeat as a
+    /// `DATA` message with no values. When this is set the response handler
+    /// runs on a thread of its own for each connection rather than on the
+    /// tokio runtime, and heartbeats stop for each request as soon as its
+    /// responses have been sent.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Validate the `d` payload of each request against the schema registered
+    /// for its method in `registry` before the request is handled. A request
+    /// that fails validation is sent a `SchemaValidationError` error instead.
+    /// Requires the `schema` feature.
+    #[cfg(feature = "schema")]
+    pub fn schema_registry(mut self, registry: SchemaRegistry) -> Self {
+        self.schema_registry = Some(Arc::new(registry));
+        self
+    }
+
+    /// Report on the requests handled using `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn ServerMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Send the `d` payload of `ERROR` messages in `format`. By default the
+    /// error object is sent as the payload itself.
+    pub fn error_format(mut self, format: ErrorPayloadFormat) -> Self {
+        self.error_format = format;
+        self
+    }
+
+    /// Call `hook` on every response message, including `END` and `ERROR`
+    /// messages, just before it is sent. This allows payloads to be rewritten,
+    /// for example to redact sensitive fields, in one place regardless of
+    /// which handler produced them.
+    pub fn response_hook<H>(mut self, hook: H) -> Self
+    where
+        H: Fn(&mut FastMessage) + Send + Sync + 'static,
+    {
+        self.response_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Log a warning with the method, id and duration of any request whose
+    /// response handler takes longer than `threshold`. The duration of other
+    /// requests is logged at debug level.
+    pub fn slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Respond to new requests with a `ServerDraining` error once `quiesce`
+    /// has been triggered. See `Quiesce`.
+    pub fn quiesce(mut self, quiesce: Quiesce) -> Self {
+        self.quiesce = Some(quiesce);
+        self
+    }
+
+    /// Stop reading requests from the connection once `shutdown` has been
+    /// triggered. Responses to requests that have already been read are still
+    /// sent before the connection is closed. See `Shutdown`.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Close the connection if no request is received within `timeout` of
+    /// the connection being opened or of the responses to the previous
+    /// request being sent. By default idle connections are kept open.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Answer compression negotiation requests, as described for
+    /// `protocol::FP_NEGOTIATE_METHOD`, by agreeing to gzip compression if the
+    /// client offers it, and compress the responses sent once it has been
+    /// agreed. Negotiation requests are not passed to the response handler.
+    /// By default they are passed to the handler like any other request,
+    /// which is expected to answer with an error, and nothing is compressed.
+    pub fn negotiate_compression(mut self, enabled: bool) -> Self {
+        self.negotiate_compression = enabled;
+        self
+    }
+
+    /// Keep the raw JSON data payload of each request received, as described
+    /// for `FastRpc::with_raw_payloads`, so that a handler can answer a request
+    /// with `FastMessage::echo_reply` and have its payload copied to the
+    /// response byte for byte rather than being encoded as JSON again. This
+    /// costs a copy of each payload, so it is disabled by default.
+    pub fn raw_payloads(mut self, enabled: bool) -> Self {
+        self.raw_payloads = enabled;
+        self
+    }
+
+    /// Close the connection if the `d` payloads of the messages of a request
+    /// streamed over several messages, as described for
+    /// `FastMessage::data_continued`, add up to more than `max_len` bytes of
+    /// JSON. The default is `FP_DEFAULT_MAX_PAYLOAD_LEN`.
+    pub fn max_reassembled_len(mut self, max_len: usize) -> Self {
+        self.max_reassembled_len = Some(max_len);
+        self
+    }
+
+    /// Close the connection if more than `max` streamed requests have been
+    /// started on it and not yet completed. The default is
+    /// `DEFAULT_MAX_PARTIAL_REQUESTS`.
+    pub fn max_partial_requests(mut self, max: usize) -> Self {
+        self.max_partial_requests = Some(max);
+        self
+    }
+
+    /// Give up on a request handled by a task created by `make_task_async` if
+    /// the future returned by the response handler has not completed within
+    /// `deadline` of the handler being called. The future is dropped and a
+    /// `DeadlineExceeded` error is sent in place of its response. Synchronous
+    /// response handlers cannot be interrupted, so this has no effect on them.
+    /// By default there is no deadline.
+    pub fn request_deadline(mut self, deadline: Duration) -> Self {
+        self.request_deadline = Some(deadline);
+        self
+    }
+}
+
+/// Hooks invoked by a task created by `make_task_with_options` to report on the
+/// requests it handles. All methods have no-op default implementations so
+/// implementors only need to provide the ones they are interested in.
+pub trait ServerMetrics: Send + Sync {
+    /// Called each time a request is received, before it is checked and passed
+    /// to the response handler.
+    fn on_request(&self, _method: &str) {}
+
+    /// Called each time the response handler finishes with a request.
+    /// `duration` is the time the handler took, whether or not it succeeded.
+    /// Requests rejected before reaching the handler are not reported.
+    fn on_response(&self, _method: &str, _duration: Duration) {}
+
+    /// Called each time an `ERROR` message is sent in response to a request.
+    /// `category` records whether the client or the server was at fault.
+    fn on_error(&self, _method: &str, _category: ErrorCategory) {}
+}
+
+/// Whether an error responding to a request was caused by the client, such as
+/// a request for an unknown method or with invalid arguments, or by the
+/// server, such as a failure of a backend or a panic in the response handler.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    Client,
+    Server,
+}
+
+impl ErrorCategory {
+    /// Classify an error returned by a response handler. Errors created with
+    /// `client_error` are caused by the client and all others by the server.
+    pub fn of(err: &Error) -> ErrorCategory {
+        match err.get_ref() {
+            Some(inner) if inner.is::<ClientError>() => ErrorCategory::Client,
+            _ => ErrorCategory::Server,
+        }
+    }
+}
+
+/// The error wrapped by an error created with `client_error`.
+#[derive(Debug)]
+pub struct ClientError(String);
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Create an error for a response handler to return when a request cannot be
+/// handled because of a problem with the request itself. Such errors are
+/// reported to `ServerMetrics` as `ErrorCategory::Client`.
+pub fn client_error(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, ClientError(String::from(message)))
+}
+
+/// A mapping from method names to the JSON Schema that the `d` payload of
+/// requests for that method must satisfy. Requests for methods with no
+/// registered schema are not validated. Requires the `schema` feature.
+#[cfg(feature = "schema")]
+#[derive(Clone, Debug, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Arc<Validator>>,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaRegistry {
+    /// Creates a new SchemaRegistry with no schemas registered
+    pub fn new() -> Self {
+        SchemaRegistry::default()
+    }
+
+    /// Register `schema` for requests for `method`, replacing any schema
+    /// already registered for it. Returns an error if `schema` is not a valid
+    /// JSON Schema.
+    pub fn register(
+        &mut self,
+        method: &str,
+        schema: &Value,
+    ) -> Result<(), Error> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| {
+            Error::other(format!("invalid schema for {}: {}", method, e))
+        })?;
+        self.schemas
+            .insert(String::from(method), Arc::new(validator));
+        Ok(())
+    }
+
+    /// Check the `d` payload of `msg` against the schema registered for its
+    /// method and return a description of the first violation found.
+    pub fn validate(&self, msg: &FastMessage) -> Result<(), String> {
+        match self.schemas.get(&msg.data.m.name) {
+            Some(validator) => {
+                validator.validate(&msg.data.d).map_err(|e| e.to_string())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// What to do with a request that arrives when the in-flight limit has been
+/// reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverloadMode {
+    /// Wait for an in-flight request to complete before handling the request.
+    Queue,
+    /// Respond immediately with a `ServerBusy` error.
+    Shed,
+}
+
+/// A limit on the number of requests that are handled at once.
+#[derive(Clone, Debug)]
+pub struct InFlightLimit {
+    state: Arc<Mutex<InFlightState>>,
+    max: usize,
+    mode: OverloadMode,
+}
+
+#[derive(Debug, Default)]
+struct InFlightState {
+    in_flight: usize,
+    waiters: Vec<Task>,
+}
+
+impl InFlightLimit {
+    /// Creates a new InFlightLimit allowing at most `max` requests to be
+    /// handled at once, with excess requests dealt with according to `mode`.
+    pub fn new(max: usize, mode: OverloadMode) -> Self {
+        InFlightLimit {
+            state: Arc::new(Mutex::new(InFlightState::default())),
+            max,
+            mode,
+        }
+    }
+
+    /// Take a slot for a request. Resolves to `None` if the limit has been
+    /// reached and the mode is `Shed`, otherwise waits for a request to
+    /// complete if necessary. Every waiting task is woken when a slot is
+    /// released, and those that do not get it wait again.
+    fn poll_acquire(&self) -> Async<Option<InFlightPermit>> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= self.max {
+            if self.mode == OverloadMode::Shed {
+                return Async::Ready(None);
+            }
+            if !state.waiters.iter().any(Task::will_notify_current) {
+                state.waiters.push(task::current());
+            }
+            return Async::NotReady;
+        }
+        state.in_flight += 1;
+
+        Async::Ready(Some(InFlightPermit {
+            state: Arc::clone(&self.state),
+        }))
+    }
+}
+
+/// A switch that puts a server into a draining state ahead of shutting it
+/// down. Once triggered, every new request is sent a `ServerDraining` error so
+/// that clients move to another server, while requests already being handled
+/// complete normally and connections are left open. Clones of a `Quiesce`
+/// share their state, so one switch can be used for every connection.
+#[derive(Clone, Debug, Default)]
+pub struct Quiesce {
+    quiesced: Arc<AtomicBool>,
+}
+
+impl Quiesce {
+    /// Creates a new Quiesce that has not been triggered
+    pub fn new() -> Self {
+        Quiesce::default()
+    }
+
+    /// Start rejecting new requests.
+    pub fn trigger(&self) {
+        self.quiesced.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if `trigger` has been called.
+    pub fn is_triggered(&self) -> bool {
+        self.quiesced.load(Ordering::SeqCst)
+    }
+}
+
+/// A signal that shuts a server down gracefully. Once triggered, connections
+/// stop reading new requests, finish responding to the requests they have
+/// already read and are then closed, and a `Server` stops accepting
+/// connections. Clones of a `Shutdown` share their state, so one signal can be
+/// used for every connection.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<Mutex<ShutdownState>>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    triggered: bool,
+    next_waiter: usize,
+    waiters: HashMap<usize, Task>,
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("triggered", &self.is_triggered())
+            .finish()
+    }
+}
+
+impl Shutdown {
+    /// Creates a new Shutdown that has not been triggered
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Start shutting down, waking every connection so that it sees the
+    /// signal.
+    pub fn trigger(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.triggered = true;
+        state.waiters.drain().for_each(|(_, task)| task.notify());
+    }
+
+    /// Returns true if `trigger` has been called.
+    pub fn is_triggered(&self) -> bool {
+        self.state.lock().unwrap().triggered
+    }
+}
+
+/// A stream that ends early once a `Shutdown` signal, if any, has been
+/// triggered.
+struct UntilShutdown<S> {
+    inner: S,
+    shutdown: Option<Shutdown>,
+    waiter: Option<usize>,
+}
+
+impl<S> UntilShutdown<S> {
+    fn new(inner: S, shutdown: Option<Shutdown>) -> Self {
+        UntilShutdown {
+            inner,
+            shutdown,
+            waiter: None,
+        }
+    }
+}
+
+impl<S: Stream> Stream for UntilShutdown<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(shutdown) = &self.shutdown {
+            let mut state = shutdown.state.lock().unwrap();
+            if state.triggered {
+                return Ok(Async::Ready(None));
+            }
+
+            // Make sure the current task is woken when the signal is
+            // triggered.
+            let waiter = *self.waiter.get_or_insert_with(|| {
+                state.next_waiter += 1;
+                state.next_waiter
+            });
+            state.waiters.insert(waiter, task::current());
+        }
+
+        self.inner.poll()
+    }
+}
+
+impl<S> Drop for UntilShutdown<S> {
+    fn drop(&mut self) {
+        if let (Some(shutdown), Some(waiter)) = (&self.shutdown, self.waiter) {
+            shutdown.state.lock().unwrap().waiters.remove(&waiter);
+        }
+    }
+}
+
+/// A slot taken from an `InFlightLimit` that is released when dropped.
+struct InFlightPermit {
+    state: Arc<Mutex<InFlightState>>,
+}
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.waiters.drain(..).for_each(|waiter| waiter.notify());
+    }
+}
+
+/// A limit on the number of connections a `Server` handles at once. Only the
+/// accept loop takes slots, so a single waiting task is enough.
+#[derive(Clone, Debug)]
+struct ConnectionLimit {
+    state: Arc<Mutex<ConnectionState>>,
+    max: usize,
+    mode: OverloadMode,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionState {
+    open: usize,
+    waiter: Option<Task>,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize, mode: OverloadMode) -> Self {
+        ConnectionLimit {
+            state: Arc::new(Mutex::new(ConnectionState::default())),
+            max,
+            mode,
+        }
+    }
+
+    /// Take a slot for a connection. Resolves to `None` if the limit has been
+    /// reached and the mode is `Shed`, otherwise waits for a connection to be
+    /// closed if necessary.
+    fn poll_acquire(&self) -> Poll<Option<ConnectionPermit>, ()> {
+        let mut state = self.state.lock().unwrap();
+        if state.open >= self.max {
+            if self.mode == OverloadMode::Shed {
+                return Ok(Async::Ready(None));
+            }
+            state.waiter = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+        state.open += 1;
+
+        Ok(Async::Ready(Some(ConnectionPermit {
+            state: Arc::clone(&self.state),
+        })))
+    }
+}
+
+/// A slot taken from a `ConnectionLimit` that is released when dropped.
+struct ConnectionPermit {
+    state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        if let Some(waiter) = state.waiter.take() {
+            waiter.notify();
+        }
+    }
+}
+
+/// A builder for a `Server`. The response handler is provided up front and
+/// the remaining settings are optional.
+pub struct ServerBuilder<F> {
+    response_handler: F,
+    log: Option<Logger>,
+    options: TaskOptions,
+    connection_limit: ConnectionLimit,
+}
+
+impl<F> ServerBuilder<F>
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Clone
+        + Send
+        + 'static,
+{
+    /// Creates a new ServerBuilder that will handle requests using a clone of
+    /// `response_handler` for each connection.
+    pub fn new(response_handler: F) -> Self {
+        ServerBuilder {
+            response_handler,
+            log: None,
+            options: TaskOptions::new(),
+            connection_limit: ConnectionLimit::new(
+                usize::MAX,
+                OverloadMode::Queue,
+            ),
+        }
+    }
+
+    /// Log using `log` rather than the slog StdLog drain.
+    pub fn logger(mut self, log: Logger) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Handle each connection as configured by `options`.
+    pub fn options(mut self, options: TaskOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Handle at most `max` connections at once. When the limit has been
+    /// reached, `OverloadMode::Queue` stops accepting connections until one
+    /// is closed, leaving new ones waiting in the listen backlog, and
+    /// `OverloadMode::Shed` closes new connections as soon as they are
+    /// accepted. By default there is no limit.
+    pub fn max_connections(mut self, max: usize, mode: OverloadMode) -> Self {
+        self.connection_limit = ConnectionLimit::new(max, mode);
+        self
+    }
+
+    /// Creates the configured `Server`.
+    pub fn build(self) -> Server<F> {
+        Server {
+            response_handler: self.response_handler,
+            log: self.log.unwrap_or_else(|| {
+                Logger::root(slog_stdlog::StdLog.fuse(), o!())
+            }),
+            options: self.options,
+            connection_limit: self.connection_limit,
+        }
+    }
+}
+
+/// A Fast server created by a `ServerBuilder`. Each accepted connection is
+/// handled by a task created with `make_task_with_options`.
+pub struct Server<F> {
+    response_handler: F,
+    log: Logger,
+    options: TaskOptions,
+    connection_limit: ConnectionLimit,
+}
+
+impl<F> Server<F>
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Clone
+        + Send
+        + 'static,
+{
+    /// Bind to `addr` and return a future that accepts and handles
+    /// connections. The future is intended to be run with `tokio::run`.
+    pub fn serve(
+        self,
+        addr: &SocketAddr,
+    ) -> Result<impl Future<Item = (), Error = ()> + Send, Error> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(self.serve_listener(listener))
+    }
+
+    /// Return a future that accepts and handles connections on an already
+    /// bound `listener`.
+    pub fn serve_listener(
+        self,
+        listener: TcpListener,
+    ) -> impl Future<Item = (), Error = ()> + Send {
+        let Server {
+            response_handler,
+            log,
+            options,
+            connection_limit,
+        } = self;
+        let err_log = log.clone();
+        let incoming =
+            UntilShutdown::new(listener.incoming(), options.shutdown.clone());
+
+        incoming
+            .map_err(move |e| {
+                error!(err_log, "failed to accept socket"; "err" => %e)
+            })
+            .for_each(move |socket| {
+                let task = make_task_with_options(
+                    socket,
+                    response_handler.clone(),
+                    Some(&log),
+                    options.clone(),
+                );
+                let limit = connection_limit.clone();
+                let log = log.clone();
+
+                // Waiting for a slot here stops the accept loop, so further
+                // connections wait in the listen backlog.
+                future::poll_fn(move || limit.poll_acquire()).map(
+                    move |permit| match permit {
+                        Some(permit) => {
+                            tokio::spawn(task.then(move |result| {
+                                drop(permit);
+                                result
+                            }));
+                        }
+                        None => warn!(
+                            log,
+                            "connection limit reached, closing connection"
+                        ),
+                    },
+                )
+            })
+    }
+}
 
 /// Create a task to be used by the tokio runtime for handling responses to Fast
 /// protocol requests.
+///
+/// Each request in a batch of messages read from the socket is handled
+/// independently. If `response_handler` returns an error for one request, an
+/// `ERROR` message is sent for that request and the remaining requests in the
+/// batch are still handled. Responses are sent in the order the requests were
+/// received, and the responses to each request are written to the connection
+/// as soon as its handler returns rather than once the whole batch is done.
+/// The messages returned by a handler are only sent once it returns, so a
+/// long-running handler that produces many `DATA` messages should be used with
+/// `make_streaming_task` instead, which sends each message as it is produced.
 pub fn make_task<F>(
     socket: TcpStream,
+    response_handler: F,
+    log: Option<&Logger>,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Send
+        + 'static,
+{
+    make_task_with_options(socket, response_handler, log, TaskOptions::new())
+}
+
+/// Create a task to be used by the tokio runtime for handling responses to Fast
+/// protocol requests, configured by the provided `TaskOptions`. Requests are
+/// handled as described for `make_task`.
+pub fn make_task_with_options<F>(
+    socket: TcpStream,
+    response_handler: F,
+    log: Option<&Logger>,
+    options: TaskOptions,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Send
+        + 'static,
+{
+    make_task_generic(socket, response_handler, log, options)
+}
+
+/// Create a task to be used by the tokio runtime for handling responses to Fast
+/// protocol requests received over a TLS connection, configured by the
+/// provided `TaskOptions`. Requests are handled as described for `make_task`.
+///
+/// To require clients to present a certificate, accept `socket` with a
+/// rustls `ServerConfig` created with a client certificate verifier such as
+/// `AllowAnyAuthenticatedClient`. The verified identity can then be read with
+/// `peer_identity` before the task is created and captured by the handler:
+///
+/// ```text
+/// acceptor.accept(socket).and_then(|socket| {
+///     let identity = server::peer_identity(&socket);
+///     server::make_task_tls(
+///         socket,
+///         move |msg: &FastMessage, log: &Logger| handle(msg, &identity, log),
+///         None,
+///         TaskOptions::new(),
+///     )
+/// })
+/// ```
+#[cfg(feature = "tls")]
+pub fn make_task_tls<F>(
+    socket: tokio_rustls::server::TlsStream<TcpStream>,
+    response_handler: F,
+    log: Option<&Logger>,
+    options: TaskOptions,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Send
+        + 'static,
+{
+    make_task_generic(socket, response_handler, log, options)
+}
+
+/// The identity a TLS client proved with its certificate, as returned by
+/// `peer_identity`.
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerIdentity {
+    /// The common name (CN) of the certificate's subject, if it has one.
+    pub common_name: Option<String>,
+    /// The DNS names in the certificate's subject alternative name extension.
+    pub dns_names: Vec<String>,
+}
+
+/// Returns the identity in the certificate presented by the client on
+/// `socket`, or `None` if the client did not present one. The certificate has
+/// already been checked by the `ServerConfig`'s client certificate verifier
+/// by the time the handshake completes.
+#[cfg(feature = "tls")]
+pub fn peer_identity(
+    socket: &tokio_rustls::server::TlsStream<TcpStream>,
+) -> Option<PeerIdentity> {
+    let certs = socket.get_ref().1.get_peer_certificates()?;
+    certs.first().and_then(|cert| parse_identity(&cert.0))
+}
+
+/// The DER encoded object identifiers of the common name attribute and the
+/// subject alternative name extension.
+#[cfg(feature = "tls")]
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+#[cfg(feature = "tls")]
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Read the subject common name and DNS subject alternative names from a DER
+/// encoded X.509 certificate.
+#[cfg(feature = "tls")]
+fn parse_identity(cert: &[u8]) -> Option<PeerIdentity> {
+    let (_, cert, _) = der_read(cert, 0x30)?;
+    let (_, mut tbs, _) = der_read(cert, 0x30)?;
+    // Skip the version, if present, and the serial number, signature
+    // algorithm, issuer and validity.
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_read(tbs, 0xa0)?.2;
+    }
+    for tag in &[0x02, 0x30, 0x30, 0x30] {
+        tbs = der_read(tbs, *tag)?.2;
+    }
+    let (_, subject, rest) = der_read(tbs, 0x30)?;
+    let mut identity = PeerIdentity {
+        common_name: find_common_name(subject),
+        dns_names: Vec::new(),
+    };
+
+    // Skip the public key and any unique identifiers to reach the extensions.
+    let mut rest = der_read(rest, 0x30)?.2;
+    while let Some((tag, value, next)) = der_read_any(rest) {
+        if tag == 0xa3 {
+            let (_, mut extensions, _) = der_read(value, 0x30)?;
+            while let Some((_, extension, next)) = der_read(extensions, 0x30) {
+                let (_, oid, mut body) = der_read(extension, 0x06)?;
+                if body.first() == Some(&0x01) {
+                    body = der_read(body, 0x01)?.2;
+                }
+                if oid == OID_SUBJECT_ALT_NAME {
+                    let (_, names, _) = der_read(body, 0x04)?;
+                    let (_, mut names, _) = der_read(names, 0x30)?;
+                    while let Some((tag, name, next)) = der_read_any(names) {
+                        if tag == 0x82 {
+                            let name = String::from_utf8(name.to_vec()).ok()?;
+                            identity.dns_names.push(name);
+                        }
+                        names = next;
+                    }
+                }
+                extensions = next;
+            }
+        }
+        rest = next;
+    }
+
+    Some(identity)
+}
+
+/// Returns the value of the first common name attribute in a DER encoded
+/// X.509 name.
+#[cfg(feature = "tls")]
+fn find_common_name(mut name: &[u8]) -> Option<String> {
+    while let Some((_, set, next)) = der_read(name, 0x31) {
+        let (_, attribute, _) = der_read(set, 0x30)?;
+        let (_, oid, value) = der_read(attribute, 0x06)?;
+        if oid == OID_COMMON_NAME {
+            let (_, value, _) = der_read_any(value)?;
+            return String::from_utf8(value.to_vec()).ok();
+        }
+        name = next;
+    }
+    None
+}
+
+/// Read a DER element with the given tag from the start of `input`,
+/// returning its tag, its contents and the bytes that follow it.
+#[cfg(feature = "tls")]
+fn der_read(input: &[u8], tag: u8) -> Option<(u8, &[u8], &[u8])> {
+    der_read_any(input).filter(|(found, _, _)| *found == tag)
+}
+
+/// Read a DER element of any tag from the start of `input`, as for
+/// `der_read`. Only single byte tags and lengths of up to four bytes are
+/// supported, which covers everything in a certificate.
+#[cfg(feature = "tls")]
+fn der_read_any(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let octets = usize::from(first & 0x7f);
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let (octets, rest) = input.split_at(octets);
+        input = rest;
+        octets
+            .iter()
+            .fold(0, |len, octet| (len << 8) | usize::from(*octet))
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (value, rest) = input.split_at(len);
+    Some((tag, value, rest))
+}
+
+/// Create a task to be used by the tokio runtime for handling responses to Fast
+/// protocol requests received over any transport, such as a Unix domain
+/// socket, configured by the provided `TaskOptions`. Requests are handled as
+/// described for `make_task`.
+pub fn make_task_generic<S, F>(
+    socket: S,
     mut response_handler: F,
     log: Option<&Logger>,
+    options: TaskOptions,
 ) -> impl Future<Item = (), Error = ()> + Send
 where
-    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error> + Send,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Send
+        + 'static,
+{
+    let response_handler = move |msg: &FastMessage, log: &Logger| {
+        response_handler(msg, log).map(HandlerOutcome::from)
+    };
+    serve_outcomes(socket, response_handler, log, options)
+}
+
+/// Create a task to be used by the tokio runtime for handling Fast protocol
+/// requests with a handler that returns a `HandlerOutcome`, configured by the
+/// provided `TaskOptions`. This allows the handler to decide whether the `END`
+/// message is sent after its response messages, for example because it has
+/// already sent its own terminal message. Requests are otherwise handled as
+/// described for `make_task`.
+pub fn make_task_with_outcome<F>(
+    socket: TcpStream,
+    response_handler: F,
+    log: Option<&Logger>,
+    options: TaskOptions,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<HandlerOutcome, Error>
+        + Send
+        + 'static,
+{
+    serve_outcomes(socket, response_handler, log, options)
+}
+
+fn serve_outcomes<S, F>(
+    socket: S,
+    response_handler: F,
+    log: Option<&Logger>,
+    options: TaskOptions,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: FnMut(&FastMessage, &Logger) -> Result<HandlerOutcome, Error>
+        + Send
+        + 'static,
+{
+    let response_handler = Arc::new(Mutex::new(response_handler));
+    let mut worker = None;
+    serve_connection(socket, log, options, move |msgs, options, log| {
+        match options.heartbeat_interval {
+            Some(interval) => {
+                let worker = worker.get_or_insert_with(|| {
+                    HeartbeatWorker::spawn(
+                        Arc::clone(&response_handler),
+                        options.clone(),
+                        log.clone(),
+                    )
+                });
+                Box::new(Heartbeats::new(msgs, worker, options, log, interval))
+            }
+            None => Box::new(RespondEach {
+                requests: msgs.into_iter(),
+                response_handler: Arc::clone(&response_handler),
+                options: options.clone(),
+                log: log.clone(),
+                flushed: true,
+                waiting: None,
+            }),
+        }
+    })
+}
+
+/// Create a task to be used by the tokio runtime for handling Fast protocol
+/// requests with a handler that sends its response messages one at a time
+/// through a `ResponseSender`, rather than returning them all at once. Each
+/// message is written to the connection as soon as possible, so a large
+/// response is never held in memory in its entirety. This also applies
+/// backpressure to the handler: messages are taken from the queue only as
+/// fast as the connection accepts them, so once `STREAMING_QUEUE_LEN`
+/// messages are waiting to be written `ResponseSender::send` blocks until a
+/// slow client reads some of them. The cost is a little more work per
+/// message, and an operating system thread for each connection: the handler
+/// runs on a thread of its own, rather than on the tokio runtime, which is
+/// started when the first request arrives and exits when the connection is
+/// closed. The `END` message, or an `ERROR` message if the handler returns an
+/// error, is sent after the handler returns. Requests are otherwise handled as
+/// described for `make_task_with_options`, except that heartbeats are not
+/// sent.
+pub fn make_streaming_task<F>(
+    socket: TcpStream,
+    response_handler: F,
+    log: Option<&Logger>,
+    options: TaskOptions,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &mut ResponseSender, &Logger) -> Result<(), Error>
+        + Send
+        + 'static,
 {
-    let (tx, rx) = FastRpc.framed(socket).split();
+    let mut response_handler = Some(response_handler);
+    let mut worker = None;
+    serve_connection(socket, log, options, move |msgs, options, log| {
+        let worker = worker.get_or_insert_with(|| {
+            let response_handler =
+                response_handler.take().expect("worker already started");
+            StreamingWorker::spawn(
+                response_handler,
+                options.clone(),
+                log.clone(),
+            )
+        });
+        Box::new(worker.respond(msgs))
+    })
+}
+
+/// Create a task to be used by the tokio runtime for handling responses to Fast
+/// protocol requests with a handler that is given a reference to `state` on
+/// every call. Passing a clone of the same `Arc` to the task for each
+/// connection shares the state, such as a connection pool or configuration,
+/// between all of them. Requests are handled as described for `make_task`.
+pub fn make_task_with_state<S, F>(
+    socket: TcpStream,
+    state: Arc<S>,
+    response_handler: F,
+    log: Option<&Logger>,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    S: Send + Sync + 'static,
+    F: Fn(&FastMessage, &S, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Send
+        + 'static,
+{
+    make_task(
+        socket,
+        move |msg, log| response_handler(msg, &state, log),
+        log,
+    )
+}
+
+/// Create a task to be used by the tokio runtime for handling Fast protocol
+/// requests with a handler that returns a future of its response messages,
+/// so that it can wait for I/O without blocking the runtime. The futures for
+/// the requests in a batch run concurrently, and their responses are sent in
+/// the order the requests were received once the whole batch is complete.
+/// Requests are otherwise handled as described for `make_task_with_options`,
+/// except that heartbeats are not sent, and a request can be given a deadline
+/// with `TaskOptions::request_deadline`. A request waiting for a slot from an
+/// `InFlightLimit` in `OverloadMode::Queue` does not block the runtime, and
+/// its handler is not called until it has a slot. With the
+/// `tracing` feature enabled the returned future is polled within the
+/// request's `fast_request` span.
+pub fn make_task_async<F, R>(
+    socket: TcpStream,
+    response_handler: F,
+    log: Option<&Logger>,
+    options: TaskOptions,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> R + Send + 'static,
+    R: Future<Item = Vec<FastMessage>, Error = Error> + Send + 'static,
+{
+    let response_handler = Arc::new(Mutex::new(response_handler));
+    serve_connection(socket, log, options, move |msgs, options, log| {
+        Box::new(
+            respond_async(msgs, &response_handler, options, log).into_stream(),
+        )
+    })
+}
+
+/// Handle the requests read from `socket`, sending the messages produced by
+/// `responses` for each batch of requests back on it.
+fn serve_connection<S, R>(
+    socket: S,
+    log: Option<&Logger>,
+    options: TaskOptions,
+    mut responses: R,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    R: FnMut(Vec<FastMessage>, &TaskOptions, &Logger) -> ResponseStream
+        + Send
+        + 'static,
+{
+    // If no logger was provided use the slog StdLog drain by default
+    let rx_log = log
+        .cloned()
+        .unwrap_or_else(|| Logger::root(slog_stdlog::StdLog.fuse(), o!()));
+
+    let compress = Arc::new(AtomicBool::new(false));
+    let mut codec = FastRpc::new().with_raw_payloads(options.raw_payloads);
+    if options.negotiate_compression {
+        codec = codec.with_compression_switch(Arc::clone(&compress));
+    }
+    let (tx, rx) = codec.framed(socket).split();
+    let rx = IdleTimeout {
+        inner: rx,
+        timeout: options.idle_timeout,
+        delay: None,
+        log: rx_log.clone(),
+    };
+    let rx = UntilShutdown::new(rx, options.shutdown.clone());
+    let rx = Reassemble {
+        inner: rx,
+        partial: HashMap::new(),
+        max_len: options
+            .max_reassembled_len
+            .unwrap_or(FP_DEFAULT_MAX_PAYLOAD_LEN),
+        max_partial: options
+            .max_partial_requests
+            .unwrap_or(DEFAULT_MAX_PARTIAL_REQUESTS),
+    };
+    let rx = RequestLimit {
+        inner: rx,
+        remaining: options.max_requests,
+    };
+
+    let tx_log = rx_log.clone();
+    let sent = rx
+        .map(move |x| -> ResponseStream {
+            debug!(rx_log, "processing fast message");
+            #[cfg(feature = "tracing")]
+            tracing::debug!(requests = x.len(), "decoded requests");
+            if !options.negotiate_compression {
+                return responses(x, &options, &rx_log);
+            }
+
+            let (replies, x) = negotiate(x, &compress, &rx_log);
+            match (replies.is_empty(), x.is_empty()) {
+                (true, _) => responses(x, &options, &rx_log),
+                (false, true) => Box::new(stream::once(Ok(replies))),
+                (false, false) => Box::new(
+                    stream::once(Ok(replies))
+                        .chain(responses(x, &options, &rx_log)),
+                ),
+            }
+        })
+        .flatten();
+    #[cfg(feature = "tracing")]
+    let sent = sent.inspect(|msgs| {
+        tracing::debug!(messages = msgs.len(), "sending responses")
+    });
+
+    tx.send_all(sent).then(move |res| {
+        if let Err(e) = res {
+            error!(tx_log, "failed to process connection"; "err" => %e);
+        }
+
+        debug!(tx_log, "transmitted response to client");
+        Ok(())
+    })
+}
+
+/// Answer the compression negotiation requests in `msgs`, turning on
+/// `compress` if gzip compression is agreed. Returns the responses to them and
+/// the rest of the requests.
+fn negotiate(
+    msgs: Vec<FastMessage>,
+    compress: &AtomicBool,
+    log: &Logger,
+) -> (Vec<FastMessage>, Vec<FastMessage>) {
+    let (requests, rest): (Vec<FastMessage>, Vec<FastMessage>) = msgs
+        .into_iter()
+        .partition(|msg| msg.data.m.name == FP_NEGOTIATE_METHOD);
+    let mut replies = Vec::new();
+    for request in requests {
+        let (mut responses, chosen) =
+            protocol::negotiation_response(&request, &[FP_COMPRESSION_GZIP]);
+        debug!(log, "negotiated compression";
+               "compression" => chosen.as_deref().unwrap_or("none"));
+        if chosen.is_some() {
+            compress.store(true, Ordering::SeqCst);
+        }
+        replies.append(&mut responses);
+    }
+    (replies, rest)
+}
+
+/// The responses to a batch of requests, possibly sent as several groups of
+/// messages.
+type ResponseStream =
+    Box<dyn Stream<Item = Vec<FastMessage>, Error = Error> + Send>;
+
+/// A stream that handles a batch of requests one at a time, yielding the
+/// responses to each request as soon as they are ready. After each request it
+/// returns `NotReady` once, so that the responses are flushed to the
+/// connection before the response handler is called for the next request.
+struct RespondEach<F> {
+    requests: vec::IntoIter<FastMessage>,
+    response_handler: Arc<Mutex<F>>,
+    options: TaskOptions,
+    log: Logger,
+    flushed: bool,
+    /// An admitted request waiting for a slot from the in-flight limit.
+    waiting: Option<FastMessage>,
+}
+
+impl<F> RespondEach<F> {
+    /// Yield the responses to a request, first applying the response hook.
+    fn ready(
+        &mut self,
+        mut responses: Vec<FastMessage>,
+    ) -> Async<Option<Vec<FastMessage>>> {
+        self.flushed = false;
+        apply_hook(&mut responses, &self.options);
+        Async::Ready(Some(responses))
+    }
+}
+
+impl<F> Stream for RespondEach<F>
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<HandlerOutcome, Error>,
+{
+    type Item = Vec<FastMessage>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.flushed {
+            self.flushed = true;
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+
+        let msg = match self.waiting.take() {
+            Some(msg) => msg,
+            None => match self.requests.next() {
+                Some(msg) => match admit(&msg, &self.options, &self.log) {
+                    Ok(()) => msg,
+                    Err(rejection) => return Ok(self.ready(vec![*rejection])),
+                },
+                None => return Ok(Async::Ready(None)),
+            },
+        };
+        let responses = match poll_permit(&msg, &self.options, &self.log) {
+            Async::Ready(Ok(permit)) => respond_admitted(
+                msg,
+                permit,
+                &mut *self.response_handler.lock().unwrap(),
+                &self.options,
+                &self.log,
+            ),
+            Async::Ready(Err(rejection)) => vec![*rejection],
+            Async::NotReady => {
+                self.waiting = Some(msg);
+                return Ok(Async::NotReady);
+            }
+        };
+        Ok(self.ready(responses))
+    }
+}
+
+/// A thread that handles the batches of requests received on one connection
+/// with heartbeats enabled, one request at a time, leaving the tokio runtime
+/// free to send heartbeats in the meantime. The responses to each request are
+/// sent back as soon as they are ready. The thread exits once the
+/// `HeartbeatWorker` has been dropped and the batches already given to it
+/// have been handled.
+struct HeartbeatWorker {
+    batches: std_mpsc::Sender<HeartbeatBatch>,
+}
+
+/// A batch of requests for a `HeartbeatWorker` and where to send the response
+/// messages for each of them, along with the request's identifier.
+type HeartbeatBatch = (
+    Vec<FastMessage>,
+    mpsc::UnboundedSender<(u32, Vec<FastMessage>)>,
+);
+
+impl HeartbeatWorker {
+    fn spawn<F>(
+        response_handler: Arc<Mutex<F>>,
+        options: TaskOptions,
+        log: Logger,
+    ) -> Self
+    where
+        F: FnMut(&FastMessage, &Logger) -> Result<HandlerOutcome, Error>
+            + Send
+            + 'static,
+    {
+        let (tx, rx) = std_mpsc::channel::<HeartbeatBatch>();
+        thread::spawn(move || {
+            for (msgs, responses) in rx {
+                debug!(log, "responding to {} messages", msgs.len());
+                for msg in msgs {
+                    let id = msg.id;
+                    let messages = respond_outcome(
+                        msg,
+                        &mut *response_handler.lock().unwrap(),
+                        &options,
+                        &log,
+                    );
+                    // The receiver is gone if the connection has been closed.
+                    let _ = responses.unbounded_send((id, messages));
+                }
+            }
+        });
+
+        HeartbeatWorker { batches: tx }
+    }
+
+    /// Queue `msgs`, which have already been admitted, to be handled once the
+    /// batches before them are done, returning a receiver for the responses.
+    fn respond(
+        &self,
+        msgs: Vec<FastMessage>,
+    ) -> mpsc::UnboundedReceiver<(u32, Vec<FastMessage>)> {
+        let (tx, rx) = mpsc::unbounded();
+        // If the thread has gone the sender is dropped with the batch, which
+        // the receiver reports.
+        let _ = self.batches.send((msgs, tx));
+        rx
+    }
+}
+
+/// A stream that handles a batch of requests on a `HeartbeatWorker`, yielding
+/// the responses to each request once they are ready and, every interval,
+/// heartbeat messages for the requests still waiting for their responses.
+/// Requests rejected by `admit` are answered straight away and are never sent
+/// heartbeats.
+struct Heartbeats {
+    requests: Vec<(u32, String)>,
+    rejections: Vec<FastMessage>,
+    interval: Interval,
+    responses: mpsc::UnboundedReceiver<(u32, Vec<FastMessage>)>,
+}
+
+impl Heartbeats {
+    fn new(
+        msgs: Vec<FastMessage>,
+        worker: &HeartbeatWorker,
+        options: &TaskOptions,
+        log: &Logger,
+        interval: Duration,
+    ) -> Self {
+        let (msgs, mut rejections) = admit_all(msgs, options, log);
+        apply_hook(&mut rejections, options);
+        let requests = msgs
+            .iter()
+            .map(|msg| (msg.id, msg.data.m.name.clone()))
+            .collect();
+
+        Heartbeats {
+            requests,
+            rejections,
+            interval: Interval::new(Instant::now() + interval, interval),
+            responses: worker.respond(msgs),
+        }
+    }
+}
+
+impl Stream for Heartbeats {
+    type Item = Vec<FastMessage>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.rejections.is_empty() {
+            return Ok(Async::Ready(Some(mem::take(&mut self.rejections))));
+        }
+
+        match self.responses.poll() {
+            Ok(Async::Ready(Some((id, responses)))) => {
+                if let Some(i) = self.requests.iter().position(|r| r.0 == id) {
+                    self.requests.remove(i);
+                }
+                return Ok(Async::Ready(Some(responses)));
+            }
+            Ok(Async::Ready(None)) if self.requests.is_empty() => {
+                return Ok(Async::Ready(None));
+            }
+            Ok(Async::NotReady) => (),
+            Ok(Async::Ready(None)) | Err(()) => {
+                let msg = "response handler thread exited without responding";
+                return Err(Error::other(msg));
+            }
+        }
+
+        match self.interval.poll().map_err(Error::other)? {
+            Async::Ready(_) if !self.requests.is_empty() => {
+                let heartbeats = self
+                    .requests
+                    .iter()
+                    .map(|(id, method)| {
+                        FastMessage::data(
+                            *id,
+                            FastMessageData::new(method.clone(), json!([])),
+                        )
+                    })
+                    .collect();
+                Ok(Async::Ready(Some(heartbeats)))
+            }
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// The number of response messages from a streaming response handler that
+/// may be waiting to be written to the connection before the handler blocks.
+/// See `make_streaming_task`.
+pub const STREAMING_QUEUE_LEN: usize = 64;
+
+/// The handle through which a handler passed to `make_streaming_task` sends its
+/// response messages.
+pub struct ResponseSender {
+    queue: sink::Wait<mpsc::Sender<FastMessage>>,
+    hook: Option<Arc<ResponseHook>>,
+}
+
+impl ResponseSender {
+    /// Queue `msg` to be written to the connection, blocking while
+    /// `STREAMING_QUEUE_LEN` messages are already queued. Returns an error if
+    /// the connection has been closed.
+    pub fn send(&mut self, mut msg: FastMessage) -> Result<(), Error> {
+        if let Some(hook) = &self.hook {
+            hook(&mut msg);
+        }
+        self.queue.send(msg).map_err(|_| {
+            Error::new(ErrorKind::BrokenPipe, "connection has been closed")
+        })
+    }
+}
+
+/// A thread that handles the batches of requests received on one connection
+/// with a streaming response handler, one batch at a time. The thread exits
+/// once the `StreamingWorker` has been dropped and the batches already given
+/// to it have been handled.
+struct StreamingWorker {
+    batches: std_mpsc::Sender<(Vec<FastMessage>, ResponseSender)>,
+    hook: Option<Arc<ResponseHook>>,
+}
+
+impl StreamingWorker {
+    fn spawn<F>(
+        mut response_handler: F,
+        options: TaskOptions,
+        log: Logger,
+    ) -> Self
+    where
+        F: FnMut(
+                &FastMessage,
+                &mut ResponseSender,
+                &Logger,
+            ) -> Result<(), Error>
+            + Send
+            + 'static,
+    {
+        let (tx, rx) = std_mpsc::channel();
+        let hook = options.response_hook.clone();
+        thread::spawn(move || {
+            for (msgs, mut sender) in rx {
+                respond_streaming(
+                    msgs,
+                    &mut response_handler,
+                    &mut sender,
+                    &options,
+                    &log,
+                );
+            }
+        });
+
+        StreamingWorker { batches: tx, hook }
+    }
+
+    /// Queue `msgs` to be handled once the batches before it are done,
+    /// returning the stream of their response messages.
+    fn respond(&self, msgs: Vec<FastMessage>) -> StreamingResponses {
+        let (tx, rx) = mpsc::channel(STREAMING_QUEUE_LEN);
+        let sender = ResponseSender {
+            queue: tx.wait(),
+            hook: self.hook.clone(),
+        };
+        // The thread only exits once this worker is gone, and panics in the
+        // handler are caught, so the batch is always delivered.
+        let _ = self.batches.send((msgs, sender));
+        StreamingResponses { responses: rx }
+    }
+}
+
+/// Handle a batch of requests with a streaming response handler, sending each
+/// response message, followed by an `END` or `ERROR` message, through `sender`.
+fn respond_streaming<F>(
+    msgs: Vec<FastMessage>,
+    response_handler: &mut F,
+    sender: &mut ResponseSender,
+    options: &TaskOptions,
+    log: &Logger,
+) where
+    F: FnMut(&FastMessage, &mut ResponseSender, &Logger) -> Result<(), Error>,
+{
+    for msg in msgs {
+        let permit = match admit(&msg, options, log)
+            .and_then(|()| wait_for_permit(&msg, options, log))
+        {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                let _ = sender.send(*rejection);
+                continue;
+            }
+        };
+
+        let start = Instant::now();
+        let result =
+            call_handler(&msg, log, || response_handler(&msg, sender, log));
+        drop(permit);
+        record_duration(&msg, start.elapsed(), options, log);
+        let terminal = match result {
+            Ok(()) => FastMessage::end(msg.id, msg.data.m.name.clone()),
+            Err(err) => handler_error(&msg, &err, options),
+        };
+        // If the connection has been closed the handler will see the error
+        // when it next sends a message, so there is no need to stop early
+        // here.
+        let _ = sender.send(terminal);
+    }
+}
+
+/// The response messages to a batch of requests handled by a
+/// `StreamingWorker`, yielded as each is sent.
+struct StreamingResponses {
+    responses: mpsc::Receiver<FastMessage>,
+}
+
+impl Stream for StreamingResponses {
+    type Item = Vec<FastMessage>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.responses.poll() {
+            Ok(Async::Ready(msg)) => Ok(Async::Ready(msg.map(|msg| vec![msg]))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => Err(Error::other("response queue failed")),
+        }
+    }
+}
+
+/// A stream of decoded request batches in which a request streamed over
+/// several `DATA` messages, as described for `FastMessage::data_continued`, is
+/// yielded as a single message once its last message has been received. The
+/// stream fails, closing the connection, if a request grows beyond `max_len`
+/// bytes or more than `max_partial` requests are partially received, so that
+/// a client cannot use up the server's memory by never finishing a request.
+struct Reassemble<S> {
+    inner: S,
+    partial: HashMap<u32, (FastMessage, usize)>,
+    max_len: usize,
+    max_partial: usize,
+}
+
+impl<S> Reassemble<S> {
+    fn reassemble(
+        &mut self,
+        msgs: Vec<FastMessage>,
+    ) -> Result<Vec<FastMessage>, Error> {
+        let mut complete = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            let continued = msg.is_continued();
+            let (msg, len) = match self.partial.remove(&msg.id) {
+                Some((mut request, len)) => {
+                    request.payload = None;
+                    let len = len + json_len(&msg.data.d)?;
+                    append_values(&mut request.data.d, msg.data.d);
+                    (request, len)
+                }
+                None if continued => {
+                    let mut request = msg;
+                    request.payload = None;
+                    request.data.m.extra = None;
+                    let d = mem::replace(&mut request.data.d, json!([]));
+                    let len = json_len(&d)?;
+                    append_values(&mut request.data.d, d);
+                    (request, len)
+                }
+                None => (msg, 0),
+            };
+
+            if len > self.max_len {
+                let msg = format!(
+                    "streamed request {} exceeds the maximum length of {} \
+                     bytes",
+                    msg.id, self.max_len
+                );
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+
+            if continued {
+                if self.partial.len() >= self.max_partial {
+                    let msg = format!(
+                        "more than {} streamed requests are incomplete",
+                        self.max_partial
+                    );
+                    return Err(Error::new(ErrorKind::InvalidData, msg));
+                }
+                self.partial.insert(msg.id, (msg, len));
+            } else {
+                complete.push(msg);
+            }
+        }
+        Ok(complete)
+    }
+}
+
+/// The length in bytes of `value` encoded as JSON.
+fn json_len(value: &Value) -> Result<usize, Error> {
+    serde_json::to_vec(value)
+        .map(|encoded| encoded.len())
+        .map_err(Error::other)
+}
+
+/// Append `values` to the array `d`. A value that is not an array is appended
+/// as a single element.
+fn append_values(d: &mut Value, values: Value) {
+    if let Value::Array(d) = d {
+        match values {
+            Value::Array(values) => d.extend(values),
+            value => d.push(value),
+        }
+    }
+}
+
+impl<S> Stream for Reassemble<S>
+where
+    S: Stream<Item = Vec<FastMessage>, Error = Error>,
+{
+    type Item = Vec<FastMessage>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.inner.poll()? {
+                Async::Ready(Some(msgs)) => {
+                    let msgs = self.reassemble(msgs)?;
+                    // A batch made up only of continued messages leaves
+                    // nothing to respond to yet.
+                    if !msgs.is_empty() {
+                        return Ok(Async::Ready(Some(msgs)));
+                    }
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+}
+
+/// A stream of decoded request batches that ends if no batch is read within a
+/// timeout. The timeout starts when the stream is first polled after yielding
+/// a batch, which is once the responses to that batch have been sent, so a
+/// slow response handler does not count against it.
+struct IdleTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    delay: Option<Delay>,
+    log: Logger,
+}
+
+impl<S> Stream for IdleTimeout<S>
+where
+    S: Stream<Item = Vec<FastMessage>, Error = Error>,
+{
+    type Item = Vec<FastMessage>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Async::Ready(item) = self.inner.poll()? {
+            self.delay = None;
+            return Ok(Async::Ready(item));
+        }
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(Async::NotReady),
+        };
+        let delay = self
+            .delay
+            .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+        match delay.poll().map_err(Error::other)? {
+            Async::Ready(()) => {
+                info!(self.log, "closing idle connection";
+                      "timeout_ms" => timeout.as_secs_f64() * 1000.0);
+                Ok(Async::Ready(None))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A stream of decoded request batches that ends once a maximum number of
+/// requests has been yielded. The batch that reaches the limit is truncated to
+/// it.
+struct RequestLimit<S> {
+    inner: S,
+    remaining: Option<usize>,
+}
+
+impl<S> Stream for RequestLimit<S>
+where
+    S: Stream<Item = Vec<FastMessage>>,
+{
+    type Item = Vec<FastMessage>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.remaining == Some(0) {
+            return Ok(Async::Ready(None));
+        }
+
+        match self.inner.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(item.map(|mut msgs| {
+                if let Some(remaining) = self.remaining.as_mut() {
+                    msgs.truncate(*remaining);
+                    *remaining -= msgs.len();
+                }
+                msgs
+            }))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A sink for the write half of a `FastRpc` framed transport that queues
+/// individual messages and only hands them to the transport as a single batch
+/// when flushed, or when the encoded size of the queued messages reaches a
+/// threshold. This lets a custom server loop control when the messages it
+/// sends are written to the connection. Once the queued messages have reached
+/// the threshold, no more are accepted until the transport has taken them.
+pub struct CoalescingSink<S> {
+    inner: S,
+    pending: Vec<FastMessage>,
+    pending_bytes: usize,
+    threshold: usize,
+}
+
+impl<S> CoalescingSink<S>
+where
+    S: Sink<SinkItem = Vec<FastMessage>, SinkError = Error>,
+{
+    /// Creates a new CoalescingSink that writes queued messages to `inner`
+    /// once they would take up at least `threshold` bytes when encoded.
+    pub fn new(inner: S, threshold: usize) -> Self {
+        CoalescingSink {
+            inner,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            threshold,
+        }
+    }
+
+    /// Returns a reference to the underlying sink.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Consumes the CoalescingSink, returning the underlying sink. Any queued
+    /// messages that have not been flushed are discarded.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Sink for CoalescingSink<S>
+where
+    S: Sink<SinkItem = Vec<FastMessage>, SinkError = Error>,
+{
+    type SinkItem = FastMessage;
+    type SinkError = Error;
+
+    fn start_send(
+        &mut self,
+        msg: FastMessage,
+    ) -> StartSend<FastMessage, Error> {
+        if self.pending_bytes >= self.threshold {
+            self.poll_complete()?;
+            if !self.pending.is_empty() {
+                return Ok(AsyncSink::NotReady(msg));
+            }
+        }
+
+        self.pending_bytes += FP_HEADER_SZ + payload_len(&msg.data)?;
+        self.pending.push(msg);
+        if self.pending_bytes >= self.threshold {
+            self.poll_complete()?;
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        if !self.pending.is_empty() {
+            let batch = mem::take(&mut self.pending);
+            if let AsyncSink::NotReady(batch) = self.inner.start_send(batch)? {
+                self.pending = batch;
+                return Ok(Async::NotReady);
+            }
+            self.pending_bytes = 0;
+        }
+
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        match self.poll_complete()? {
+            Async::Ready(()) => self.inner.close(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Wrap a response handler so that it is retried when it fails with an error
+/// for which `is_retryable` returns true. The handler is retried up to
+/// `max_retries` times, sleeping for `delay` between attempts, before its
+/// error is returned. Errors for which `is_retryable` returns false are
+/// returned immediately. The delay blocks the thread running the handler,
+/// which for the tasks created by `make_task` and `make_task_with_options` is
+/// a thread of the tokio runtime, so this is only suitable for synchronous
+/// handlers and delays of a few milliseconds. Use `retrying_async` to retry
+/// the handler of a task created by `make_task_async`.
+pub fn retrying<F, P>(
+    mut response_handler: F,
+    is_retryable: P,
+    max_retries: usize,
+    delay: Duration,
+) -> impl FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error> + Send,
+    P: Fn(&Error) -> bool + Send,
+{
+    move |msg, log| {
+        let mut attempt = 0;
+        loop {
+            match response_handler(msg, log) {
+                Err(ref e) if attempt < max_retries && is_retryable(e) => {
+                    attempt += 1;
+                    warn!(log, "retrying failed request";
+                          "method" => &msg.data.m.name, "id" => msg.id,
+                          "attempt" => attempt, "err" => %e);
+                    thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Wrap a response handler for `make_task_async` so that it is retried as
+/// described for `retrying`. The returned handler can be cloned for each
+/// connection. The delay between attempts is a timer on the
+/// tokio runtime, so waiting to retry a request does not hold up others.
+pub fn retrying_async<F, R, P>(
+    response_handler: F,
+    is_retryable: P,
+    max_retries: usize,
+    delay: Duration,
+) -> impl Fn(
+    &FastMessage,
+    &Logger,
+) -> Box<dyn Future<Item = Vec<FastMessage>, Error = Error> + Send>
+       + Clone
+       + Send
+where
+    F: Fn(&FastMessage, &Logger) -> R + Send + Sync + 'static,
+    R: Future<Item = Vec<FastMessage>, Error = Error> + Send + 'static,
+    P: Fn(&Error) -> bool + Send + Sync + 'static,
+{
+    let response_handler = Arc::new(response_handler);
+    let is_retryable = Arc::new(is_retryable);
+    move |msg, log| {
+        let response_handler = Arc::clone(&response_handler);
+        let is_retryable = Arc::clone(&is_retryable);
+        let msg = msg.clone();
+        let log = log.clone();
+        Box::new(future::loop_fn(0, move |attempt| {
+            let is_retryable = Arc::clone(&is_retryable);
+            let method = msg.data.m.name.clone();
+            let id = msg.id;
+            let log = log.clone();
+            response_handler(&msg, &log).then(move |result| match result {
+                Err(ref e) if attempt < max_retries && is_retryable(e) => {
+                    warn!(log, "retrying failed request";
+                          "method" => method, "id" => id,
+                          "attempt" => attempt + 1, "err" => %e);
+                    future::Either::A(
+                        Delay::new(Instant::now() + delay)
+                            .map_err(Error::other)
+                            .map(move |()| future::Loop::Continue(attempt + 1)),
+                    )
+                }
+                result => future::Either::B(
+                    future::result(result).map(future::Loop::Break),
+                ),
+            })
+        }))
+    }
+}
+
+/// Forward a request to an upstream Fast server using `upstream` and return
+/// the upstream's `DATA` messages rewritten to carry the identifier of the
+/// original request. This allows a response handler to act as a gateway:
+///
+/// ```text
+/// let upstream = Arc::new(Mutex::new(BlockingFastClient::new(stream)));
+/// let handler = move |msg: &FastMessage, _log: &Logger| {
+///     server::relay(msg, &mut upstream.lock().unwrap())
+/// };
+/// ```
+///
+/// The `END` message for the request is added by the task as for any other
+/// handler. If the upstream responds with an `ERROR` message it is returned as
+/// an error so that an `ERROR` message is sent for the original request.
+pub fn relay(
+    request: &FastMessage,
+    upstream: &mut BlockingFastClient,
+) -> Result<Vec<FastMessage>, Error> {
+    let mut responses = Vec::new();
+    upstream.send(request.data.m.name.clone(), request.data.d.clone())?;
+    upstream.receive(|msg| {
+        responses.push(request.reply(msg.data.d.clone()));
+        Ok(())
+    })?;
+
+    Ok(responses)
+}
+
+/// A response handler that balances requests across a pool of upstream Fast
+/// servers, relaying each request to the next upstream in turn using `relay`.
+/// Clones of a `Proxy` share the upstream connections, so a single `Proxy`
+/// can be cloned for each connection accepted by the server:
+///
+/// ```text
+/// let proxy = Proxy::new(vec![upstream_a, upstream_b]);
+/// let server = ServerBuilder::new(move |msg: &FastMessage, log: &Logger| {
+///     proxy.handle(msg, log)
+/// })
+/// .build();
+/// ```
+///
+/// Connections to the upstreams are made when first needed. If relaying a
+/// request fails for any reason other than an `ERROR` response from the
+/// upstream, the connection is re-established and the request is relayed once
+/// more. A request may therefore reach an upstream twice, so a `Proxy` should
+/// only be used for idempotent methods.
+#[derive(Clone)]
+pub struct Proxy {
+    upstreams: Arc<Vec<Upstream>>,
+    next: Arc<AtomicUsize>,
+}
+
+struct Upstream {
+    addr: SocketAddr,
+    client: Mutex<Option<BlockingFastClient>>,
+}
+
+impl Proxy {
+    /// Creates a new Proxy for the upstream servers at `upstreams`.
+    pub fn new(upstreams: Vec<SocketAddr>) -> Self {
+        let upstreams = upstreams
+            .into_iter()
+            .map(|addr| Upstream {
+                addr,
+                client: Mutex::new(None),
+            })
+            .collect();
+
+        Proxy {
+            upstreams: Arc::new(upstreams),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Relay `request` to the next upstream server and return its response.
+    pub fn handle(
+        &self,
+        request: &FastMessage,
+        log: &Logger,
+    ) -> Result<Vec<FastMessage>, Error> {
+        if self.upstreams.is_empty() {
+            return Err(Error::other("no upstream servers are configured"));
+        }
+
+        let index =
+            self.next.fetch_add(1, Ordering::SeqCst) % self.upstreams.len();
+        let upstream = &self.upstreams[index];
+        let mut client = upstream.client.lock().unwrap();
+
+        if client.is_some() {
+            match relay(request, client.as_mut().unwrap()) {
+                Err(ref e) if !is_upstream_error(e) => {
+                    warn!(log, "reconnecting to upstream";
+                          "upstream" => %upstream.addr, "err" => %e);
+                }
+                result => return result,
+            }
+        }
+
+        *client = None;
+        let stream = std::net::TcpStream::connect(upstream.addr)?;
+        relay(
+            request,
+            client.get_or_insert(BlockingFastClient::new(stream)),
+        )
+    }
+}
+
+/// Returns true if `err` is an `ERROR` response from an upstream server
+/// rather than a failure to communicate with it.
+fn is_upstream_error(err: &Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<FastMessageServerError>())
+}
+
+/// A method handler registered with a `Router`.
+pub type RouteHandler = dyn Fn(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+    + Send
+    + Sync;
+
+/// A response handler that dispatches each request to the handler registered
+/// for its method name, so that a server does not need its own `match` on
+/// `msg.data.m.name`. A request for a method with no registered handler fails
+/// with the error from `FastMessageServerError::method_not_found`. Clones of a
+/// `Router` share its handlers, so a single `Router` can be cloned for each
+/// connection accepted by the server:
+///
+/// ```text
+/// let router = Router::new()
+///     .add("echo", echo_handler)
+///     .add("date", date_handler);
+/// let server = ServerBuilder::new(move |msg: &FastMessage, log: &Logger| {
+///     router.handle(msg, log)
+/// })
+/// .build();
+/// ```
+///
+/// Methods whose responses can be very large can be registered with
+/// `add_limited` to cap the size of their responses.
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: HashMap<String, Route>,
+}
+
+#[derive(Clone)]
+struct Route {
+    handler: Arc<RouteHandler>,
+    limit: ResponseLimit,
+}
+
+/// Limits on the size of the response to a request for a method registered
+/// with `Router::add_limited`. A response that exceeds either limit is
+/// replaced with a `ResponseTooLarge` error. By default there is no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResponseLimit {
+    max_frames: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl ResponseLimit {
+    /// Creates a new ResponseLimit with no limits.
+    pub fn new() -> Self {
+        ResponseLimit::default()
+    }
+
+    /// Allow at most `max_frames` response messages, not counting the `END`
+    /// message that completes the response.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = Some(max_frames);
+        self
+    }
+
+    /// Allow at most `max_bytes` bytes of JSON in the payloads of the response
+    /// messages, as they are encoded on the wire.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Returns an error if `responses` exceed this limit.
+    fn check(&self, responses: &[FastMessage]) -> Result<(), Error> {
+        if let Some(max_frames) = self.max_frames {
+            if responses.len() > max_frames {
+                let msg = format!(
+                    "response of {} messages exceeds the limit of {}",
+                    responses.len(),
+                    max_frames
+                );
+                return Err(response_too_large(&msg));
+            }
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            let mut bytes = 0;
+            for response in responses {
+                bytes += payload_len(&response.data)?;
+                if bytes > max_bytes {
+                    let msg = format!(
+                        "response exceeds the limit of {} bytes",
+                        max_bytes
+                    );
+                    return Err(response_too_large(&msg));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes `data` takes up when encoded as JSON, without
+/// keeping the encoding.
+fn payload_len(data: &FastMessageData) -> Result<usize, Error> {
+    /// A writer that only counts the bytes written to it.
+    struct ByteCount(usize);
+
+    impl io::Write for ByteCount {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut count = ByteCount(0);
+    serde_json::to_writer(&mut count, data)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(count.0)
+}
+
+fn response_too_large(msg: &str) -> Error {
+    FastMessageServerError::new("ResponseTooLarge", msg).into()
+}
+
+impl Router {
+    /// Creates a new Router with no methods registered.
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Handle requests for `method` with `handler`, replacing any handler
+    /// previously registered for it.
+    pub fn add<H>(self, method: &str, handler: H) -> Self
+    where
+        H: Fn(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.add_limited(method, ResponseLimit::new(), handler)
+    }
+
+    /// Handle requests for `method` with `handler` as for `add`, failing a
+    /// request with a `ResponseTooLarge` error instead of sending its response
+    /// if the response exceeds `limit`.
+    pub fn add_limited<H>(
+        mut self,
+        method: &str,
+        limit: ResponseLimit,
+        handler: H,
+    ) -> Self
+    where
+        H: Fn(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let route = Route {
+            handler: Arc::new(handler),
+            limit,
+        };
+        self.routes.insert(String::from(method), route);
+        self
+    }
+
+    /// Handle `request` with the handler registered for its method.
+    pub fn handle(
+        &self,
+        request: &FastMessage,
+        log: &Logger,
+    ) -> Result<Vec<FastMessage>, Error> {
+        match self.routes.get(&request.data.m.name) {
+            Some(route) => {
+                let responses = (route.handler)(request, log)?;
+                route.limit.check(&responses)?;
+                Ok(responses)
+            }
+            None => Err(FastMessageServerError::method_not_found(
+                &request.data.m.name,
+            )
+            .into()),
+        }
+    }
+}
 
-    // If no logger was provided use the slog StdLog drain by default
-    let rx_log = log
-        .cloned()
-        .unwrap_or_else(|| Logger::root(slog_stdlog::StdLog.fuse(), o!()));
+/// The messages returned by a response handler for a single request, and
+/// whether the request is complete. See `make_task_with_outcome`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandlerOutcome {
+    pub messages: Vec<FastMessage>,
+    /// Whether an `END` message should be sent after `messages`.
+    pub complete: bool,
+}
 
-    let tx_log = rx_log.clone();
-    tx.send_all(rx.and_then(move |x| {
-        debug!(rx_log, "processing fast message");
-        respond(x, &mut response_handler, &rx_log)
-    }))
-    .then(move |res| {
-        if let Err(e) = res {
-            error!(tx_log, "failed to process connection"; "err" => %e);
+impl HandlerOutcome {
+    /// Creates a new HandlerOutcome that sends `messages` followed by an `END`
+    /// message.
+    pub fn complete(messages: Vec<FastMessage>) -> Self {
+        HandlerOutcome {
+            messages,
+            complete: true,
         }
+    }
 
-        debug!(tx_log, "transmitted response to client");
-        Ok(())
-    })
+    /// Creates a new HandlerOutcome that sends `messages` without an `END`
+    /// message.
+    pub fn incomplete(messages: Vec<FastMessage>) -> Self {
+        HandlerOutcome {
+            messages,
+            complete: false,
+        }
+    }
+}
+
+impl From<Vec<FastMessage>> for HandlerOutcome {
+    fn from(messages: Vec<FastMessage>) -> Self {
+        HandlerOutcome::complete(messages)
+    }
 }
 
-fn respond<F>(
+/// Split `msgs` into the requests that may be handled and the `ERROR`
+/// messages to send for the others, as decided by `admit`.
+fn admit_all(
     msgs: Vec<FastMessage>,
+    options: &TaskOptions,
+    log: &Logger,
+) -> (Vec<FastMessage>, Vec<FastMessage>) {
+    let mut rejections = Vec::new();
+    let admitted = msgs
+        .into_iter()
+        .filter(|msg| match admit(msg, options, log) {
+            Ok(()) => true,
+            Err(rejection) => {
+                rejections.push(*rejection);
+                false
+            }
+        })
+        .collect();
+    (admitted, rejections)
+}
+
+/// Respond to `msg`, which has already been admitted, blocking while waiting
+/// for a slot from the in-flight limit.
+fn respond_outcome<F>(
+    msg: FastMessage,
+    response_handler: &mut F,
+    options: &TaskOptions,
+    log: &Logger,
+) -> Vec<FastMessage>
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<HandlerOutcome, Error>,
+{
+    let mut responses = match wait_for_permit(&msg, options, log) {
+        Ok(permit) => {
+            respond_admitted(msg, permit, response_handler, options, log)
+        }
+        Err(rejection) => vec![*rejection],
+    };
+    apply_hook(&mut responses, options);
+    responses
+}
+
+/// Call `response_handler` for `msg`, which has been admitted and holds
+/// `permit`, and return the messages to send in response.
+fn respond_admitted<F>(
+    msg: FastMessage,
+    permit: Option<InFlightPermit>,
     response_handler: &mut F,
+    options: &TaskOptions,
+    log: &Logger,
+) -> Vec<FastMessage>
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<HandlerOutcome, Error>,
+{
+    let start = Instant::now();
+    let result = call_handler(&msg, log, || response_handler(&msg, log));
+    drop(permit);
+    record_duration(&msg, start.elapsed(), options, log);
+    match result {
+        Ok(mut outcome) => {
+            debug!(log, "generated response");
+            if outcome.complete {
+                let method = msg.data.m.name.clone();
+                outcome.messages.push(FastMessage::end(msg.id, method));
+            }
+            outcome.messages
+        }
+        Err(err) => vec![handler_error(&msg, &err, options)],
+    }
+}
+
+fn apply_hook(responses: &mut [FastMessage], options: &TaskOptions) {
+    if let Some(hook) = &options.response_hook {
+        responses.iter_mut().for_each(|response| hook(response));
+    }
+}
+
+/// The response to a single request, as produced by `respond_async`.
+type AsyncResponse =
+    Box<dyn Future<Item = Vec<FastMessage>, Error = Error> + Send>;
+
+fn respond_async<F, R>(
+    msgs: Vec<FastMessage>,
+    response_handler: &Arc<Mutex<F>>,
+    options: &TaskOptions,
     log: &Logger,
 ) -> impl Future<Item = Vec<FastMessage>, Error = Error> + Send
 where
-    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error> + Send,
+    F: FnMut(&FastMessage, &Logger) -> R + Send + 'static,
+    R: Future<Item = Vec<FastMessage>, Error = Error> + Send + 'static,
 {
     debug!(log, "responding to {} messages", msgs.len());
 
-    let mut responses: Vec<FastMessage> = Vec::new();
+    let responses: Vec<AsyncResponse> = msgs
+        .into_iter()
+        .map(|msg| -> AsyncResponse {
+            if let Err(rejection) = admit(&msg, options, log) {
+                return Box::new(future::ok(vec![*rejection]));
+            }
+
+            // Wait for a slot without blocking the runtime, as the requests
+            // holding the slots may need it to complete.
+            let response_handler = Arc::clone(response_handler);
+            let options = options.clone();
+            let log = log.clone();
+            Box::new(permit_future(msg, options.clone(), log.clone()).and_then(
+                move |(msg, admitted)| match admitted {
+                    Ok(permit) => respond_admitted_async(
+                        msg,
+                        permit,
+                        &response_handler,
+                        options,
+                        log,
+                    ),
+                    Err(rejection) => Box::new(future::ok(vec![*rejection])),
+                },
+            ))
+        })
+        .collect();
 
-    for msg in msgs {
-        match response_handler(&msg, &log) {
-            Ok(mut response) => {
-                // Make sure there is room in responses to fit another response plus an
-                // end message
-                let responses_len = responses.len();
-                let response_len = response.len();
-                let responses_capacity = responses.capacity();
-                if responses_len + response_len > responses_capacity {
-                    let needed_capacity =
-                        responses_len + response_len - responses_capacity;
-                    responses.reserve(needed_capacity);
-                }
-
-                // Add all response messages for this message to the vector of
-                // all responses
-                response.drain(..).for_each(|r| {
-                    responses.push(r);
-                });
+    let options = options.clone();
+    future::join_all(responses).map(move |responses| {
+        let mut responses: Vec<FastMessage> =
+            responses.into_iter().flatten().collect();
+        apply_hook(&mut responses, &options);
+        responses
+    })
+}
+
+/// Call `response_handler` for `msg`, which has been admitted and holds
+/// `permit`, and return the future of the messages to send in response.
+fn respond_admitted_async<F, R>(
+    msg: FastMessage,
+    permit: Option<InFlightPermit>,
+    response_handler: &Mutex<F>,
+    options: TaskOptions,
+    log: Logger,
+) -> AsyncResponse
+where
+    F: FnMut(&FastMessage, &Logger) -> R,
+    R: Future<Item = Vec<FastMessage>, Error = Error> + Send + 'static,
+{
+    let start = Instant::now();
+    let response = call_handler(&msg, &log, || {
+        Ok((*response_handler.lock().unwrap())(&msg, &log))
+    });
+    let panic_log = log.clone();
+    let method = msg.data.m.name.clone();
+    let id = msg.id;
+    let response = future::result(response).and_then(move |response| {
+        AssertUnwindSafe(response)
+            .catch_unwind()
+            .then(move |result| {
+                result.unwrap_or_else(|_| {
+                    error!(panic_log, "response future panicked";
+                       "method" => method, "id" => id);
+                    Err(Error::other("response future panicked"))
+                })
+            })
+    });
+    // The future runs after call_handler has returned, so it needs its own
+    // reference to the request's span.
+    #[cfg(feature = "tracing")]
+    let response = response.instrument(request_span(&msg));
+    let response: AsyncResponse = match options.request_deadline {
+        Some(deadline) => {
+            Box::new(with_deadline(response, start + deadline, &msg, &log))
+        }
+        None => Box::new(response),
+    };
 
-                debug!(log, "generated response");
+    Box::new(response.then(move |result| {
+        drop(permit);
+        record_duration(&msg, start.elapsed(), &options, &log);
+        Ok(match result {
+            Ok(mut response) => {
                 let method = msg.data.m.name.clone();
-                responses.push(FastMessage::end(msg.id, method));
+                response.push(FastMessage::end(msg.id, method));
+                response
             }
-            Err(err) => {
-                let method = msg.data.m.name.clone();
-                let value = json!({
-                    "name": "FastError",
-                    "message": err.to_string()
-                });
+            Err(err) => vec![handler_error(&msg, &err, &options)],
+        })
+    }))
+}
+
+/// Race `response` against a timer expiring at `deadline`, failing with a
+/// `DeadlineExceeded` error if the timer wins. The response future is dropped
+/// as soon as the deadline passes.
+fn with_deadline<R>(
+    response: R,
+    deadline: Instant,
+    msg: &FastMessage,
+    log: &Logger,
+) -> impl Future<Item = Vec<FastMessage>, Error = Error> + Send
+where
+    R: Future<Item = Vec<FastMessage>, Error = Error> + Send,
+{
+    let log = log.clone();
+    let method = msg.data.m.name.clone();
+    let id = msg.id;
+    response
+        .select2(Delay::new(deadline))
+        .then(move |result| match result {
+            Ok(future::Either::A((response, _))) => Ok(response),
+            Err(future::Either::A((err, _))) => Err(err),
+            Ok(future::Either::B(((), response))) => {
+                drop(response);
+                warn!(log, "request deadline exceeded";
+                      "method" => method, "id" => id);
+                Err(FastMessageServerError::new(
+                    "DeadlineExceeded",
+                    "the request was not completed within its deadline",
+                )
+                .into())
+            }
+            Err(future::Either::B((err, _))) => Err(Error::other(err)),
+        })
+}
+
+/// Check whether `msg` may be handled. Returns the boxed `ERROR` message to
+/// send instead if the server is draining or the request fails schema
+/// validation. An admitted request must then take a slot from the in-flight
+/// limit with `poll_permit` or `wait_for_permit`.
+fn admit(
+    msg: &FastMessage,
+    options: &TaskOptions,
+    log: &Logger,
+) -> Result<(), Box<FastMessage>> {
+    if let Some(metrics) = &options.metrics {
+        metrics.on_request(&msg.data.m.name);
+    }
+
+    if options.quiesce.as_ref().is_some_and(Quiesce::is_triggered) {
+        debug!(log, "rejecting request: server draining";
+               "method" => &msg.data.m.name, "id" => msg.id);
+        return Err(Box::new(error_reply(
+            msg,
+            "ServerDraining",
+            "server is draining and not accepting new requests",
+            options,
+        )));
+    }
+
+    #[cfg(feature = "schema")]
+    if let Some(registry) = &options.schema_registry {
+        if let Err(e) = registry.validate(msg) {
+            warn!(log, "rejecting request: schema validation failed";
+                  "method" => &msg.data.m.name, "id" => msg.id,
+                  "err" => &e);
+            record_error(options, msg, ErrorCategory::Client);
+            return Err(Box::new(error_reply(
+                msg,
+                "SchemaValidationError",
+                &e,
+                options,
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Take a slot from the in-flight limit, if any, for the admitted request
+/// `msg`. Resolves to the permit to hold while it is handled, or to the boxed
+/// `ERROR` message to send instead if too many requests are in flight and the
+/// limit sheds excess requests. Otherwise the current task is woken once a
+/// slot may be free.
+fn poll_permit(
+    msg: &FastMessage,
+    options: &TaskOptions,
+    log: &Logger,
+) -> Async<Result<Option<InFlightPermit>, Box<FastMessage>>> {
+    let limit = match &options.in_flight_limit {
+        Some(limit) => limit,
+        None => return Async::Ready(Ok(None)),
+    };
+    match limit.poll_acquire() {
+        Async::Ready(Some(permit)) => Async::Ready(Ok(Some(permit))),
+        Async::Ready(None) => {
+            warn!(log, "rejecting request: server busy";
+                  "method" => &msg.data.m.name, "id" => msg.id);
+            record_error(options, msg, ErrorCategory::Server);
+            Async::Ready(Err(Box::new(error_reply(
+                msg,
+                "ServerBusy",
+                "too many requests are in flight",
+                options,
+            ))))
+        }
+        Async::NotReady => Async::NotReady,
+    }
+}
+
+/// Take a slot as for `poll_permit` in a future, which resolves to the
+/// request along with the result.
+fn permit_future(
+    msg: FastMessage,
+    options: TaskOptions,
+    log: Logger,
+) -> impl Future<
+    Item = (
+        FastMessage,
+        Result<Option<InFlightPermit>, Box<FastMessage>>,
+    ),
+    Error = Error,
+> {
+    let mut msg = Some(msg);
+    future::poll_fn(move || {
+        let admitted = match &msg {
+            Some(msg) => poll_permit(msg, &options, &log),
+            None => panic!("permit future polled after completion"),
+        };
+        Ok(admitted.map(|admitted| (msg.take().unwrap(), admitted)))
+    })
+}
+
+/// Take a slot as for `poll_permit`, blocking the current thread while waiting
+/// for one. This must not be called on a thread of the tokio runtime, which
+/// the requests holding the slots may need in order to complete.
+fn wait_for_permit(
+    msg: &FastMessage,
+    options: &TaskOptions,
+    log: &Logger,
+) -> Result<Option<InFlightPermit>, Box<FastMessage>> {
+    future::poll_fn(|| Ok::<_, ()>(poll_permit(msg, options, log)))
+        .wait()
+        .expect("waiting for a permit cannot fail")
+}
+
+/// Call a response handler for `msg`. A panic in the handler is reported to
+/// the client as an error rather than tearing down the connection. With the
+/// `tracing` feature enabled the handler is called within a `fast_request`
+/// span recording the `id` and `method` of the request.
+fn call_handler<T, H>(
+    msg: &FastMessage,
+    log: &Logger,
+    handler: H,
+) -> Result<T, Error>
+where
+    H: FnOnce() -> Result<T, Error>,
+{
+    #[cfg(feature = "tracing")]
+    let _span = request_span(msg).entered();
+    #[cfg(feature = "tracing")]
+    tracing::debug!("invoking response handler");
+
+    let result =
+        panic::catch_unwind(AssertUnwindSafe(handler)).unwrap_or_else(|_| {
+            error!(log, "response handler panicked";
+                   "method" => &msg.data.m.name, "id" => msg.id);
+            Err(Error::other("response handler panicked"))
+        });
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(ok = result.is_ok(), "response handler returned");
+    result
+}
+
+/// Returns the `fast_request` span in which `msg` is handled.
+#[cfg(feature = "tracing")]
+fn request_span(msg: &FastMessage) -> tracing::Span {
+    tracing::info_span!("fast_request", id = msg.id, method = %msg.data.m.name)
+}
+
+/// Returns the `ERROR` message responding to `msg` when the response handler
+/// fails with `err`, and reports the error to the configured metrics. If `err`
+/// wraps a `FastMessageServerError`, as an error created with
+/// `FastMessageServerError::into` does, that error is sent to the client with
+/// its own name and message. Any other error is sent as a `FastError`.
+fn handler_error(
+    msg: &FastMessage,
+    err: &Error,
+    options: &TaskOptions,
+) -> FastMessage {
+    record_error(options, msg, ErrorCategory::of(err));
+    let server_err = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<FastMessageServerError>());
+    match server_err.map(serde_json::to_value) {
+        Some(Ok(value)) => FastMessage::error(
+            msg.id,
+            FastMessageData::new(
+                msg.data.m.name.clone(),
+                options.error_format.encode(value),
+            ),
+        ),
+        _ => error_reply(msg, "FastError", &err.to_string(), options),
+    }
+}
+
+/// Returns an `ERROR` message responding to `msg` with its payload in the
+/// format configured by `options`.
+fn error_reply(
+    msg: &FastMessage,
+    name: &str,
+    message: &str,
+    options: &TaskOptions,
+) -> FastMessage {
+    let mut reply = msg.reply_error(name, message);
+    reply.data.d = options.error_format.encode(reply.data.d);
+    reply
+}
+
+fn record_duration(
+    msg: &FastMessage,
+    duration: Duration,
+    options: &TaskOptions,
+    log: &Logger,
+) {
+    if let Some(metrics) = &options.metrics {
+        metrics.on_response(&msg.data.m.name, duration);
+    }
+
+    let duration_ms = duration.as_secs_f64() * 1000.0;
+    match options.slow_request_threshold {
+        Some(threshold) if duration > threshold => {
+            warn!(log, "slow request";
+                  "method" => &msg.data.m.name, "id" => msg.id,
+                  "duration_ms" => duration_ms);
+        }
+        _ => {
+            debug!(log, "handled request";
+                   "method" => &msg.data.m.name, "id" => msg.id,
+                   "duration_ms" => duration_ms);
+        }
+    }
+}
+
+fn record_error(
+    options: &TaskOptions,
+    msg: &FastMessage,
+    category: ErrorCategory,
+) {
+    if let Some(metrics) = &options.metrics {
+        metrics.on_error(&msg.data.m.name, category);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::ErrorKind;
+
+    use crate::protocol::FastMessageStatus;
+
+    fn test_logger() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
+
+    /// Respond to `msgs` with a handler that returns its messages directly,
+    /// as the handlers passed to `make_task` do.
+    fn respond<F>(
+        msgs: Vec<FastMessage>,
+        response_handler: &mut F,
+        options: &TaskOptions,
+        log: &Logger,
+    ) -> impl Future<Item = Vec<FastMessage>, Error = Error> + Send
+    where
+        F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+            + Send,
+    {
+        let mut response_handler = |msg: &FastMessage, log: &Logger| {
+            response_handler(msg, log).map(HandlerOutcome::from)
+        };
+        let (msgs, mut rejections) = admit_all(msgs, options, log);
+        apply_hook(&mut rejections, options);
+        let mut responses: Vec<FastMessage> = msgs
+            .into_iter()
+            .flat_map(|msg| {
+                respond_outcome(msg, &mut response_handler, options, log)
+            })
+            .collect();
+        responses.append(&mut rejections);
+        future::ok(responses)
+    }
+
+    /// The level, message and keys of a log record.
+    type CapturedRecord = (slog::Level, String, Vec<String>);
+
+    /// A drain that records the level, message and keys of each log record.
+    #[derive(Clone, Default)]
+    struct CapturingDrain {
+        records: Arc<Mutex<Vec<CapturedRecord>>>,
+    }
+
+    struct KeyCollector(Vec<String>);
+
+    impl slog::Serializer for KeyCollector {
+        fn emit_arguments(
+            &mut self,
+            key: slog::Key,
+            _val: &fmt::Arguments,
+        ) -> slog::Result {
+            self.0.push(String::from(key));
+            Ok(())
+        }
+    }
+
+    impl Drain for CapturingDrain {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            _values: &slog::OwnedKVList,
+        ) -> Result<(), slog::Never> {
+            let mut keys = KeyCollector(Vec::new());
+            let _ = slog::KV::serialize(&record.kv(), record, &mut keys);
+            self.records.lock().unwrap().push((
+                record.level(),
+                record.msg().to_string(),
+                keys.0,
+            ));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retrying_handler_hides_transient_failure() {
+        let log = test_logger();
+        let mut attempts = 0;
+        let handler = |msg: &FastMessage, _log: &Logger| {
+            attempts += 1;
+            if attempts == 1 {
+                Err(Error::new(ErrorKind::Interrupted, "backend hiccup"))
+            } else {
+                Ok(vec![msg.reply(msg.data.d.clone())])
+            }
+        };
+        let mut handler = retrying(
+            handler,
+            |e| e.kind() == ErrorKind::Interrupted,
+            2,
+            Duration::from_millis(1),
+        );
 
-                let err_msg = FastMessage::error(
-                    msg.id,
-                    FastMessageData::new(method, value),
+        let request = FastMessage::data(
+            7,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        let responses =
+            respond(vec![request], &mut handler, &TaskOptions::new(), &log)
+                .wait()
+                .unwrap();
+
+        let statuses: Vec<FastMessageStatus> =
+            responses.iter().map(|r| r.status.clone()).collect();
+        assert_eq!(
+            statuses,
+            vec![FastMessageStatus::Data, FastMessageStatus::End]
+        );
+        assert_eq!(responses[0].data.d, json!(["hello"]));
+    }
+
+    #[test]
+    fn retrying_handler_gives_up_after_max_retries() {
+        let log = test_logger();
+        let mut attempts = 0;
+        let handler = |_msg: &FastMessage, _log: &Logger| {
+            attempts += 1;
+            Err(Error::new(ErrorKind::Interrupted, "backend down"))
+        };
+        let mut handler = retrying(
+            handler,
+            |e| e.kind() == ErrorKind::Interrupted,
+            2,
+            Duration::from_millis(1),
+        );
+
+        let request = FastMessage::data(
+            7,
+            FastMessageData::new(String::from("echo"), json!(["hello"])),
+        );
+        let responses =
+            respond(vec![request], &mut handler, &TaskOptions::new(), &log)
+                .wait()
+                .unwrap();
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, FastMessageStatus::Error);
+        drop(handler);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn incomplete_outcome_suppresses_end() {
+        let log = test_logger();
+        let mut handler = |msg: &FastMessage, _log: &Logger| {
+            let reply = msg.reply(msg.data.d.clone());
+            if msg.id == 1 {
+                Ok(HandlerOutcome::incomplete(vec![reply]))
+            } else {
+                Ok(HandlerOutcome::complete(vec![reply]))
+            }
+        };
+        let responses: Vec<FastMessage> = vec![1, 2]
+            .into_iter()
+            .flat_map(|id| {
+                let msg = FastMessage::data(
+                    id,
+                    FastMessageData::new(String::from("echo"), json!([id])),
                 );
-                responses.push(err_msg);
+                respond_outcome(msg, &mut handler, &TaskOptions::new(), &log)
+            })
+            .collect();
+        let statuses: Vec<(u32, FastMessageStatus)> =
+            responses.iter().map(|r| (r.id, r.status.clone())).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (1, FastMessageStatus::Data),
+                (2, FastMessageStatus::Data),
+                (2, FastMessageStatus::End),
+            ]
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema_registry_rejects_nonconforming_request() {
+        let log = test_logger();
+        let mut handler = |msg: &FastMessage, _log: &Logger| {
+            Ok(vec![msg.reply(msg.data.d.clone())])
+        };
+        let mut registry = SchemaRegistry::new();
+        registry
+            .register(
+                "get",
+                &json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {"key": {"type": "string"}},
+                        "required": ["key"]
+                    }
+                }),
+            )
+            .unwrap();
+        let options = TaskOptions::new().schema_registry(registry);
+
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(
+                    String::from("get"),
+                    json!([{"key": "a"}]),
+                ),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("get"), json!([{"key": 5}])),
+            ),
+        ];
+        let responses =
+            respond(batch, &mut handler, &options, &log).wait().unwrap();
+
+        let summary: Vec<(u32, FastMessageStatus)> =
+            responses.iter().map(|r| (r.id, r.status.clone())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, FastMessageStatus::Data),
+                (1, FastMessageStatus::End),
+                (2, FastMessageStatus::Error),
+            ]
+        );
+        assert_eq!(responses[0].data.d, json!([{"key": "a"}]));
+        assert_eq!(responses[2].data.d["name"], "SchemaValidationError");
+    }
+
+    /// A sink that records the batches given to it, and only takes them while
+    /// it is open.
+    #[derive(Default)]
+    struct GatedSink {
+        closed: bool,
+        batches: Vec<Vec<FastMessage>>,
+    }
+
+    impl Sink for GatedSink {
+        type SinkItem = Vec<FastMessage>;
+        type SinkError = Error;
+
+        fn start_send(
+            &mut self,
+            batch: Vec<FastMessage>,
+        ) -> StartSend<Vec<FastMessage>, Error> {
+            if self.closed {
+                return Ok(AsyncSink::NotReady(batch));
+            }
+            self.batches.push(batch);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn echo_messages(ids: std::ops::RangeInclusive<u32>) -> Vec<FastMessage> {
+        ids.map(|id| {
+            FastMessage::data(
+                id,
+                FastMessageData::new(String::from("echo"), json!([id])),
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn coalescing_sink_writes_on_flush() {
+        let msgs = echo_messages(1..=3);
+        let mut sink = CoalescingSink::new(GatedSink::default(), 4096);
+
+        for msg in msgs.iter().cloned() {
+            assert!(sink.start_send(msg).unwrap().is_ready());
+        }
+        assert!(sink.get_ref().batches.is_empty());
+
+        let sink = sink.flush().wait().unwrap();
+        assert_eq!(sink.into_inner().batches, vec![msgs]);
+    }
+
+    #[test]
+    fn coalescing_sink_writes_at_threshold() {
+        let msgs = echo_messages(1..=1);
+        let mut sink = CoalescingSink::new(GatedSink::default(), 1);
+
+        assert!(sink.start_send(msgs[0].clone()).unwrap().is_ready());
+        assert_eq!(sink.into_inner().batches, vec![msgs]);
+    }
+
+    #[test]
+    fn coalescing_sink_applies_backpressure() {
+        let msgs = echo_messages(1..=2);
+        let inner = GatedSink {
+            closed: true,
+            batches: Vec::new(),
+        };
+        let mut sink = CoalescingSink::new(inner, 1);
+
+        // The first message fills the queue, which the inner sink cannot take,
+        // so the second is refused until it can.
+        assert!(sink.start_send(msgs[0].clone()).unwrap().is_ready());
+        assert!(sink.start_send(msgs[1].clone()).unwrap().is_not_ready());
+
+        sink.inner.closed = false;
+        assert!(sink.start_send(msgs[1].clone()).unwrap().is_ready());
+        let batches = sink.into_inner().batches;
+        assert_eq!(batches, vec![vec![msgs[0].clone()], vec![msgs[1].clone()]]);
+    }
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        requests: Mutex<Vec<String>>,
+        responses: Mutex<Vec<(String, Duration)>>,
+        errors: Mutex<Vec<(String, ErrorCategory)>>,
+    }
+
+    impl ServerMetrics for RecordingMetrics {
+        fn on_request(&self, method: &str) {
+            self.requests.lock().unwrap().push(String::from(method));
+        }
+
+        fn on_response(&self, method: &str, duration: Duration) {
+            self.responses
+                .lock()
+                .unwrap()
+                .push((String::from(method), duration));
+        }
+
+        fn on_error(&self, method: &str, category: ErrorCategory) {
+            self.errors
+                .lock()
+                .unwrap()
+                .push((String::from(method), category));
+        }
+    }
+
+    #[test]
+    fn error_payload_format_is_configurable() {
+        let log = test_logger();
+        let mut handler =
+            |_msg: &FastMessage, _log: &Logger| Err(Error::other("boom"));
+        let request = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!([])),
+        );
+        let expected = json!({"name": "FastError", "message": "boom"});
+
+        let options = TaskOptions::new();
+        let responses =
+            respond(vec![request.clone()], &mut handler, &options, &log)
+                .wait()
+                .unwrap();
+        assert_eq!(responses[0].data.d, expected);
+
+        let options =
+            TaskOptions::new().error_format(ErrorPayloadFormat::Array);
+        let responses = respond(vec![request], &mut handler, &options, &log)
+            .wait()
+            .unwrap();
+        assert_eq!(responses[0].data.d, json!([expected]));
+    }
+
+    #[test]
+    fn response_hook_redacts_every_data_message() {
+        let log = test_logger();
+        let mut handler =
+            |msg: &FastMessage, _log: &Logger| match msg.data.m.name.as_str() {
+                "getuser" => Ok(vec![msg.reply(json!([
+                    {"name": "alice", "password": "hunter2"}
+                ]))]),
+                _ => Ok(vec![
+                    msg.reply(json!([{"password": "swordfish"}])),
+                    msg.reply(json!([{"password": "letmein"}])),
+                ]),
+            };
+        let options = TaskOptions::new().response_hook(|msg| {
+            if let Some(values) = msg.data.d.as_array_mut() {
+                for value in values.iter_mut() {
+                    if let Some(password) = value.get_mut("password") {
+                        *password = Value::Null;
+                    }
+                }
+            }
+        });
+
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("getuser"), json!([])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("listkeys"), json!([])),
+            ),
+        ];
+        let responses =
+            respond(batch, &mut handler, &options, &log).wait().unwrap();
+
+        let data: Vec<Value> = responses
+            .iter()
+            .filter(|r| r.is_data())
+            .map(|r| r.data.d.clone())
+            .collect();
+        assert_eq!(
+            data,
+            vec![
+                json!([{"name": "alice", "password": null}]),
+                json!([{"password": null}]),
+                json!([{"password": null}]),
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_requests_are_logged_as_warnings() {
+        let drain = CapturingDrain::default();
+        let log = Logger::root(drain.clone(), o!());
+        let mut handler =
+            |msg: &FastMessage, _log: &Logger| match msg.data.m.name.as_str() {
+                "slow" => {
+                    thread::sleep(Duration::from_millis(50));
+                    Ok(vec![])
+                }
+                _ => Ok(vec![]),
+            };
+        let options = TaskOptions::new()
+            .slow_request_threshold(Duration::from_millis(20));
+
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("fast"), json!([])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("slow"), json!([])),
+            ),
+        ];
+        respond(batch, &mut handler, &options, &log).wait().unwrap();
+
+        let records = drain.records.lock().unwrap();
+        let warnings: Vec<_> = records
+            .iter()
+            .filter(|(level, _, _)| *level == slog::Level::Warning)
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        let (_, msg, keys) = warnings[0];
+        assert_eq!(msg, "slow request");
+        assert_eq!(keys, &vec!["duration_ms", "id", "method"]);
+    }
+
+    #[test]
+    fn streaming_handler_is_limited_by_queue() {
+        let frames = 10_000;
+        let sent = Arc::new(AtomicUsize::new(0));
+        let handler_sent = Arc::clone(&sent);
+        let handler = move |msg: &FastMessage,
+                            sender: &mut ResponseSender,
+                            _log: &Logger| {
+            for i in 0..frames {
+                sender.send(msg.reply(json!([i])))?;
+                handler_sent.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        };
+        let request = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("yes"), json!([])),
+        );
+
+        let mut responses =
+            StreamingWorker::spawn(handler, TaskOptions::new(), test_logger())
+                .respond(vec![request])
+                .wait();
+        assert!(responses.next().unwrap().unwrap()[0].is_data());
+
+        // Give the handler time to fill the queue, at which point it must
+        // wait for messages to be taken from it.
+        thread::sleep(Duration::from_millis(100));
+        assert!(sent.load(Ordering::SeqCst) <= STREAMING_QUEUE_LEN + 2);
+
+        let rest: Vec<FastMessage> =
+            responses.flat_map(|batch| batch.unwrap()).collect();
+        assert_eq!(rest.len(), frames);
+        assert_eq!(rest.last().unwrap().status, FastMessageStatus::End);
+        assert_eq!(sent.load(Ordering::SeqCst), frames);
+    }
+
+    #[test]
+    fn streaming_batches_share_one_thread() {
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let handler_threads = Arc::clone(&threads);
+        let handler = move |msg: &FastMessage,
+                            sender: &mut ResponseSender,
+                            _log: &Logger| {
+            handler_threads.lock().unwrap().push(thread::current().id());
+            sender.send(msg.reply(json!([])))
+        };
+        let worker =
+            StreamingWorker::spawn(handler, TaskOptions::new(), test_logger());
+
+        for id in 1..=3 {
+            let request = FastMessage::data(
+                id,
+                FastMessageData::new(String::from("yes"), json!([])),
+            );
+            let responses: Vec<FastMessage> = worker
+                .respond(vec![request])
+                .wait()
+                .flat_map(|batch| batch.unwrap())
+                .collect();
+            assert_eq!(responses.len(), 2);
+        }
+
+        let threads = threads.lock().unwrap();
+        assert_eq!(threads.len(), 3);
+        assert!(threads.iter().all(|id| *id == threads[0]));
+        assert_ne!(threads[0], thread::current().id());
+    }
+
+    #[test]
+    fn heartbeats_are_only_sent_for_waiting_requests() {
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let handler_threads = Arc::clone(&threads);
+        let handler = move |msg: &FastMessage, _log: &Logger| {
+            handler_threads.lock().unwrap().push(thread::current().id());
+            if msg.data.m.name == "slow" {
+                thread::sleep(Duration::from_millis(200));
             }
+            Ok(HandlerOutcome::complete(vec![]))
+        };
+        let options = TaskOptions::new();
+        let worker = HeartbeatWorker::spawn(
+            Arc::new(Mutex::new(handler)),
+            options.clone(),
+            test_logger(),
+        );
+        let request = |id, method: &str| {
+            FastMessage::data(
+                id,
+                FastMessageData::new(String::from(method), json!([])),
+            )
+        };
+        let mut runtime =
+            tokio::runtime::current_thread::Runtime::new().unwrap();
+        let mut respond = |msgs, options: &TaskOptions| -> Vec<FastMessage> {
+            let heartbeats = future::lazy(|| {
+                Heartbeats::new(
+                    msgs,
+                    &worker,
+                    options,
+                    &test_logger(),
+                    Duration::from_millis(20),
+                )
+                .concat2()
+            });
+            runtime.block_on(heartbeats).unwrap()
+        };
+
+        // The fast request is answered straight away, so only the slow one is
+        // sent heartbeats.
+        let responses =
+            respond(vec![request(1, "fast"), request(2, "slow")], &options);
+        let heartbeats: Vec<u32> = responses
+            .iter()
+            .filter(|msg| msg.is_data())
+            .map(|msg| msg.id)
+            .collect();
+        assert!(heartbeats.len() >= 3);
+        assert!(heartbeats.iter().all(|id| *id == 2));
+        assert_eq!(responses.last().unwrap().status, FastMessageStatus::End);
+
+        // A rejected request is answered without reaching the handler and is
+        // not sent any heartbeats.
+        let quiesce = Quiesce::new();
+        quiesce.trigger();
+        let responses = respond(
+            vec![request(3, "slow")],
+            &options.clone().quiesce(quiesce),
+        );
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, FastMessageStatus::Error);
+
+        respond(vec![request(4, "fast")], &options);
+        let threads = threads.lock().unwrap();
+        assert_eq!(threads.len(), 3);
+        assert!(threads.iter().all(|id| *id == threads[0]));
+        assert_ne!(threads[0], thread::current().id());
+    }
+
+    #[test]
+    fn response_limit_counts_payload_bytes() {
+        let msg = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("get"), json!(["abcdef"])),
+        );
+        let responses = vec![msg.clone(), msg.clone()];
+        let len = serde_json::to_vec(&msg.data).unwrap().len();
+
+        assert!(ResponseLimit::new()
+            .max_bytes(2 * len)
+            .check(&responses)
+            .is_ok());
+        let err = ResponseLimit::new()
+            .max_bytes(2 * len - 1)
+            .check(&responses)
+            .unwrap_err();
+        let server_err = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<FastMessageServerError>())
+            .unwrap();
+        assert_eq!(server_err.name, "ResponseTooLarge");
+    }
+
+    #[test]
+    fn handler_error_name_is_sent() {
+        let log = test_logger();
+        let mut handler =
+            |msg: &FastMessage, _log: &Logger| match msg.data.m.name.as_str() {
+                "getobject" => Err(FastMessageServerError::new(
+                    "ObjectNotFound",
+                    "no such object",
+                )
+                .into()),
+                _ => Err(Error::other("plain failure")),
+            };
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("getobject"), json!([])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("other"), json!([])),
+            ),
+        ];
+        let responses = respond(batch, &mut handler, &TaskOptions::new(), &log)
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            responses[0].data.d,
+            json!({"name": "ObjectNotFound", "message": "no such object"})
+        );
+        assert_eq!(
+            responses[1].data.d,
+            json!({"name": "FastError", "message": "plain failure"})
+        );
+    }
+
+    #[test]
+    fn errors_are_categorized() {
+        let log = test_logger();
+        let mut handler =
+            |msg: &FastMessage, _log: &Logger| match msg.data.m.name.as_str() {
+                "echo" => Ok(vec![msg.reply(msg.data.d.clone())]),
+                "panic" => panic!("handler bug"),
+                method => Err(client_error(&format!(
+                    "Unsupported function: {}",
+                    method
+                ))),
+            };
+        let metrics = Arc::new(RecordingMetrics::default());
+        let options = TaskOptions::new().with_metrics(metrics.clone());
+
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("unknown"), json!([])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("panic"), json!([])),
+            ),
+            FastMessage::data(
+                3,
+                FastMessageData::new(String::from("echo"), json!([])),
+            ),
+        ];
+        let responses =
+            respond(batch, &mut handler, &options, &log).wait().unwrap();
+
+        let statuses: Vec<FastMessageStatus> =
+            responses.iter().map(|r| r.status.clone()).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                FastMessageStatus::Error,
+                FastMessageStatus::Error,
+                FastMessageStatus::Data,
+                FastMessageStatus::End,
+            ]
+        );
+        assert_eq!(
+            *metrics.errors.lock().unwrap(),
+            vec![
+                (String::from("unknown"), ErrorCategory::Client),
+                (String::from("panic"), ErrorCategory::Server),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_request_does_not_affect_rest_of_batch() {
+        let log = test_logger();
+        let mut handler =
+            |msg: &FastMessage, _log: &Logger| match msg.data.m.name.as_str() {
+                "echo" => Ok(vec![msg.reply(msg.data.d.clone())]),
+                _ => Err(Error::other("Unsupported function")),
+            };
+
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("echo"), json!(["one"])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("unknown"), json!([])),
+            ),
+            FastMessage::data(
+                3,
+                FastMessageData::new(String::from("echo"), json!(["three"])),
+            ),
+        ];
+        let responses = respond(batch, &mut handler, &TaskOptions::new(), &log)
+            .wait()
+            .unwrap();
+
+        let summary: Vec<(u32, FastMessageStatus)> =
+            responses.iter().map(|r| (r.id, r.status.clone())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, FastMessageStatus::Data),
+                (1, FastMessageStatus::End),
+                (2, FastMessageStatus::Error),
+                (3, FastMessageStatus::Data),
+                (3, FastMessageStatus::End),
+            ]
+        );
+        assert_eq!(responses[0].data.d, json!(["one"]));
+        assert_eq!(responses[2].data.m.name, "unknown");
+        assert_eq!(responses[3].data.d, json!(["three"]));
+    }
+
+    #[test]
+    fn router_dispatches_by_method() {
+        let router = Router::new()
+            .add("echo", |msg: &FastMessage, _log: &Logger| {
+                Ok(vec![msg.reply(msg.data.d.clone())])
+            })
+            .add("count", |msg: &FastMessage, _log: &Logger| {
+                Ok(vec![msg.reply(json!([msg
+                    .data
+                    .d
+                    .as_array()
+                    .unwrap()
+                    .len()]))])
+            });
+        let log = test_logger();
+        let request = |method: &str| {
+            FastMessage::data(
+                7,
+                FastMessageData::new(String::from(method), json!(["a", "b"])),
+            )
+        };
+
+        let responses = router.handle(&request("echo"), &log).unwrap();
+        assert_eq!(responses[0].data.d, json!(["a", "b"]));
+        let responses = router.handle(&request("count"), &log).unwrap();
+        assert_eq!(responses[0].data.d, json!([2]));
+
+        let err = router.handle(&request("missing"), &log).unwrap_err();
+        assert!(err.to_string().contains("missing"));
+
+        let responses = respond(
+            vec![request("missing")],
+            &mut |msg: &FastMessage, log: &Logger| router.handle(msg, log),
+            &TaskOptions::new(),
+            &log,
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, FastMessageStatus::Error);
+        assert_eq!(
+            responses[0].data.d["name"],
+            json!("FastError.MethodNotFound")
+        );
+    }
+
+    #[test]
+    fn metrics_report_requests_and_durations() {
+        let log = test_logger();
+        let mut handler =
+            |msg: &FastMessage, _log: &Logger| match msg.data.m.name.as_str() {
+                "sleep" => {
+                    thread::sleep(Duration::from_millis(5));
+                    Ok(vec![])
+                }
+                _ => Err(Error::other("boom")),
+            };
+        let metrics = Arc::new(RecordingMetrics::default());
+        let options = TaskOptions::new().with_metrics(metrics.clone());
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("sleep"), json!([])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("fail"), json!([])),
+            ),
+        ];
+
+        respond(batch, &mut handler, &options, &log).wait().unwrap();
+
+        assert_eq!(*metrics.requests.lock().unwrap(), vec!["sleep", "fail"]);
+        let responses = metrics.responses.lock().unwrap();
+        let methods: Vec<&str> = responses
+            .iter()
+            .map(|(method, _)| method.as_str())
+            .collect();
+        assert_eq!(methods, vec!["sleep", "fail"]);
+        assert!(responses[0].1 >= Duration::from_millis(5));
+        assert!(responses[1].1 > Duration::from_secs(0));
+        assert_eq!(
+            *metrics.errors.lock().unwrap(),
+            vec![(String::from("fail"), ErrorCategory::Server)]
+        );
+    }
+
+    /// The name and fields of each span created while a `SpanRecorder` is the
+    /// default subscriber.
+    #[cfg(feature = "tracing")]
+    type RecordedSpans = Arc<Mutex<Vec<(String, Vec<(String, String)>)>>>;
+
+    /// The message of each event recorded while a `SpanRecorder` is the
+    /// default subscriber, and the name of the span it was recorded in.
+    #[cfg(feature = "tracing")]
+    type RecordedEvents = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: RecordedSpans,
+        events: RecordedEvents,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldCollector(Vec<(String, String)>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldCollector {
+        fn record_debug(
+            &mut self,
+            field: &tracing::field::Field,
+            value: &dyn fmt::Debug,
+        ) {
+            self.0
+                .push((String::from(field.name()), format!("{:?}", value)));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+    where
+        S: tracing::Subscriber
+            + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = FieldCollector(Vec::new());
+            attrs.record(&mut fields);
+            let name = String::from(attrs.metadata().name());
+            self.spans.lock().unwrap().push((name, fields.0));
+        }
+
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = FieldCollector(Vec::new());
+            event.record(&mut fields);
+            let message = fields
+                .0
+                .into_iter()
+                .find(|(name, _)| name == "message")
+                .map(|(_, value)| value)
+                .unwrap_or_default();
+            let span = ctx.event_span(event).map(|s| String::from(s.name()));
+            self.events.lock().unwrap().push((message, span));
         }
     }
 
-    Box::new(future::ok(responses))
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn each_request_is_handled_in_a_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        let log = test_logger();
+        let mut handler = |msg: &FastMessage, _log: &Logger| {
+            Ok(vec![msg.reply(msg.data.d.clone())])
+        };
+        let batch = vec![
+            FastMessage::data(
+                1,
+                FastMessageData::new(String::from("echo"), json!([])),
+            ),
+            FastMessage::data(
+                2,
+                FastMessageData::new(String::from("date"), json!([])),
+            ),
+        ];
+
+        tracing::subscriber::with_default(subscriber, || {
+            respond(batch, &mut handler, &TaskOptions::new(), &log)
+                .wait()
+                .unwrap();
+        });
+
+        let field =
+            |name: &str, value: &str| (String::from(name), String::from(value));
+        assert_eq!(
+            *recorder.spans.lock().unwrap(),
+            vec![
+                (
+                    String::from("fast_request"),
+                    vec![field("id", "1"), field("method", "echo")]
+                ),
+                (
+                    String::from("fast_request"),
+                    vec![field("id", "2"), field("method", "date")]
+                ),
+            ]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn async_response_future_runs_in_request_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        let log = test_logger();
+        let handler =
+            Arc::new(Mutex::new(|msg: &FastMessage, _log: &Logger| {
+                let reply = msg.reply(msg.data.d.clone());
+                future::lazy(move || {
+                    tracing::info!("polling response future");
+                    Ok(vec![reply])
+                })
+            }));
+        let request = FastMessage::data(
+            1,
+            FastMessageData::new(String::from("echo"), json!([])),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            respond_async(vec![request], &handler, &TaskOptions::new(), &log)
+                .wait()
+                .unwrap();
+        });
+
+        let events = recorder.events.lock().unwrap();
+        assert!(events.contains(&(
+            String::from("polling response future"),
+            Some(String::from("fast_request"))
+        )));
+    }
 }
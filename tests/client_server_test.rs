@@ -1,6 +1,6 @@
 // Copyright 2020 Joyent, Inc.
 
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::process;
 use std::sync::{Arc, Barrier, Mutex};
@@ -32,11 +32,11 @@ fn msg_handler(
     let response: Vec<FastMessage> = vec![];
 
     match msg.data.m.name.as_str() {
-        "echo" => echo_handler(msg, response, &log),
-        _ => Err(Error::new(
-            ErrorKind::Other,
-            format!("Unsupported function: {}", msg.data.m.name),
-        )),
+        "echo" => echo_handler(msg, response, log),
+        _ => Err(Error::other(format!(
+            "Unsupported function: {}",
+            msg.data.m.name
+        ))),
     }
 }
 
@@ -104,7 +104,7 @@ fn client_server_comms() {
     let addr_str = "127.0.0.1:56652".to_string();
     let addr = addr_str.parse::<SocketAddr>().unwrap();
 
-    let mut stream = TcpStream::connect(&addr).unwrap_or_else(|e| {
+    let mut stream = TcpStream::connect(addr).unwrap_or_else(|e| {
         eprintln!("Failed to connect to server: {}", e);
         process::exit(1)
     });
@@ -0,0 +1,1514 @@
+// Copyright 2020 Joyent, Inc.
+
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::{env, fs, process};
+
+use bytes::BytesMut;
+use serde_derive::Deserialize;
+use serde_json::json;
+use slog::{o, Logger};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::timer::Delay;
+#[cfg(unix)]
+use tokio_uds::UnixListener;
+
+use fast_rpc::client::{self, BlockingFastClient, Client};
+use fast_rpc::protocol::{
+    self, FastMessage, FastMessageData, FastMessageId, FastMessageServerError,
+    FastMessageStatus, FastRpc, RandomIdAllocator,
+};
+use fast_rpc::server::{
+    self, InFlightLimit, OverloadMode, Proxy, Quiesce, ResponseLimit,
+    ResponseSender, Router, ServerBuilder, Shutdown, TaskOptions,
+};
+
+fn echo_handler(
+    msg: &FastMessage,
+    _log: &Logger,
+) -> Result<Vec<FastMessage>, Error> {
+    Ok(vec![msg.reply(msg.data.d.clone())])
+}
+
+/// Start a Fast server on an unused local port that handles each connection
+/// with `make_task_with_options` and return the address it is listening on.
+fn start_server<F>(handler: F, options: TaskOptions) -> SocketAddr
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Vec<FastMessage>, Error>
+        + Clone
+        + Send
+        + 'static,
+{
+    serve_with(move |socket| {
+        server::make_task_with_options(
+            socket,
+            handler.clone(),
+            None,
+            options.clone(),
+        )
+    })
+}
+
+/// Start a Fast server on an unused local port that handles each connection
+/// with the task returned by `make_task` and return the address it is
+/// listening on.
+fn serve_with<M, T>(mut make_task: M) -> SocketAddr
+where
+    M: FnMut(tokio::net::TcpStream) -> T + Send + 'static,
+    T: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+    let listener = TcpListener::bind(&addr).expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        tokio::run(
+            listener
+                .incoming()
+                .map_err(|e| panic!("failed to accept socket: {}", e))
+                .for_each(move |socket| {
+                    tokio::spawn(make_task(socket));
+                    Ok(())
+                }),
+        )
+    });
+
+    addr
+}
+
+#[test]
+fn connection_closed_after_max_requests() {
+    let addr = start_server(echo_handler, TaskOptions::new().max_requests(2));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+
+    for i in 0..2 {
+        let mut responses = Vec::new();
+        client::send(
+            String::from("echo"),
+            json!([i]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .and_then(|_| {
+            client::receive(&mut stream, |msg| {
+                responses.push(msg.data.d.clone());
+                Ok(())
+            })
+        })
+        .unwrap();
+        assert_eq!(responses, vec![json!([i])]);
+    }
+
+    // The server closes the connection once the second request completes, so
+    // the third request is never answered.
+    let result = client::send(
+        String::from("echo"),
+        json!([2]),
+        &mut msg_id,
+        &mut stream,
+    )
+    .and_then(|_| client::receive(&mut stream, |_| Ok(())));
+    assert!(result.is_err());
+}
+
+#[test]
+fn idle_connection_is_closed_after_timeout() {
+    let addr = start_server(
+        echo_handler,
+        TaskOptions::new().idle_timeout(Duration::from_millis(200)),
+    );
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    // Nothing is sent, so the server closes the connection once the timeout
+    // has passed.
+    let start = Instant::now();
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn responses_are_sent_before_later_requests_are_handled() {
+    // The handler for the second request waits until the client has received
+    // the response to the first one.
+    let (received_tx, received_rx) = mpsc::channel::<()>();
+    let received_rx = Arc::new(Mutex::new(received_rx));
+    let handler = move |msg: &FastMessage, _log: &Logger| {
+        if msg.data.d == json!(["second"]) {
+            let _ = received_rx
+                .lock()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5));
+        }
+        Ok(vec![msg.reply(msg.data.d.clone())])
+    };
+    let addr = start_server(handler, TaskOptions::new());
+    let mut stream = TcpStream::connect(addr).unwrap();
+
+    // Send both requests in a single write so that they are read as one batch.
+    let mut codec = FastRpc::new();
+    let mut buf = BytesMut::new();
+    for (id, value) in [(1, "first"), (2, "second")] {
+        let request = FastMessage::data(
+            id,
+            FastMessageData::new(String::from("echo"), json!([value])),
+        );
+        codec.encode(vec![request], &mut buf).unwrap();
+    }
+    stream.write_all(&buf).unwrap();
+
+    let mut responses = Vec::new();
+    client::receive_timeout(
+        &mut stream,
+        |msg| {
+            responses.push(msg.data.d.clone());
+            Ok(())
+        },
+        Duration::from_secs(2),
+    )
+    .unwrap();
+    assert_eq!(responses, vec![json!(["first"])]);
+
+    received_tx.send(()).unwrap();
+    let mut responses = Vec::new();
+    client::receive(&mut stream, |msg| {
+        responses.push(msg.data.d.clone());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(responses, vec![json!(["second"])]);
+}
+
+#[test]
+fn streaming_handler_data_arrives_before_handler_finishes() {
+    // The handler waits to produce its second DATA message until the client
+    // has received the first.
+    let (received_tx, received_rx) = mpsc::channel::<()>();
+    let received_rx = Arc::new(Mutex::new(received_rx));
+    let handler =
+        move |msg: &FastMessage, sender: &mut ResponseSender, _log: &Logger| {
+            sender.send(msg.reply(json!(["first"])))?;
+            received_rx
+                .lock()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5))
+                .map_err(Error::other)?;
+            sender.send(msg.reply(json!(["second"])))
+        };
+    let addr = serve_with(move |socket| {
+        server::make_streaming_task(
+            socket,
+            handler.clone(),
+            None,
+            TaskOptions::new(),
+        )
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+    client::send(String::from("echo"), json!([]), &mut msg_id, &mut stream)
+        .unwrap();
+
+    // Read the first DATA frame on its own while the handler is still
+    // running.
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let first = protocol::read_message(&mut stream).unwrap();
+    assert_eq!(first.status, FastMessageStatus::Data);
+    assert_eq!(first.data.d, json!(["first"]));
+
+    received_tx.send(()).unwrap();
+    let second = protocol::read_message(&mut stream).unwrap();
+    assert_eq!(second.data.d, json!(["second"]));
+    let end = protocol::read_message(&mut stream).unwrap();
+    assert_eq!(end.status, FastMessageStatus::End);
+}
+
+#[test]
+fn streaming_handler_waits_for_slow_reader() {
+    let frames = 2000;
+    let sent = Arc::new(AtomicUsize::new(0));
+    let handler_sent = Arc::clone(&sent);
+    let addr = serve_with(move |socket| {
+        let sent = Arc::clone(&handler_sent);
+        server::make_streaming_task(
+            socket,
+            move |msg: &FastMessage,
+                  sender: &mut ResponseSender,
+                  _log: &Logger| {
+                let value = "x".repeat(16 * 1024);
+                for _ in 0..frames {
+                    sender.send(msg.reply(json!([value])))?;
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(())
+            },
+            None,
+            TaskOptions::new(),
+        )
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::send(
+        String::from("yes"),
+        json!([]),
+        &mut FastMessageId::new(),
+        &mut stream,
+    )
+    .unwrap();
+
+    // Without reading anything, the handler can only get as far ahead as the
+    // queue and the socket buffers allow, far short of the whole response.
+    thread::sleep(Duration::from_millis(500));
+    let in_flight = sent.load(Ordering::SeqCst);
+    assert!(in_flight > 0);
+    assert!(in_flight < frames / 2, "{} messages in flight", in_flight);
+
+    let mut received = 0;
+    client::receive(&mut stream, |_| {
+        received += 1;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(received, frames);
+    assert_eq!(sent.load(Ordering::SeqCst), frames);
+}
+
+#[test]
+fn large_request_is_sent_completely() {
+    let addr = start_server(echo_handler, TaskOptions::new());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+
+    // Much larger than the socket send buffer, so that a single write cannot
+    // send the whole frame.
+    let payload = json!(["x".repeat(8 * 1024 * 1024)]);
+    let sent = client::send(
+        String::from("echo"),
+        payload.clone(),
+        &mut msg_id,
+        &mut stream,
+    )
+    .unwrap();
+    assert!(sent > 8 * 1024 * 1024);
+
+    let mut responses = Vec::new();
+    client::receive(&mut stream, |msg| {
+        responses.push(msg.data.d.clone());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(responses, vec![payload]);
+}
+
+#[test]
+fn gateway_relays_response_from_backend() {
+    let backend_addr = start_server(echo_handler, TaskOptions::new());
+
+    // Use random message ids upstream so that they differ from the ids used
+    // by the client of the gateway.
+    let upstream = BlockingFastClient::with_id_allocator(
+        TcpStream::connect(backend_addr).unwrap(),
+        Box::new(RandomIdAllocator::new()),
+    );
+    let upstream = Arc::new(Mutex::new(upstream));
+    let gateway_addr = start_server(
+        move |msg: &FastMessage, _log: &Logger| {
+            server::relay(msg, &mut upstream.lock().unwrap())
+        },
+        TaskOptions::new(),
+    );
+
+    let mut stream = TcpStream::connect(gateway_addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+    let mut responses = Vec::new();
+    client::send(
+        String::from("echo"),
+        json!(["relayed"]),
+        &mut msg_id,
+        &mut stream,
+    )
+    .and_then(|_| {
+        client::receive(&mut stream, |msg| {
+            responses.push((msg.id, msg.data.d.clone()));
+            Ok(())
+        })
+    })
+    .unwrap();
+
+    assert_eq!(responses, vec![(0, json!(["relayed"]))]);
+}
+
+#[test]
+fn builder_configured_server_round_trip() {
+    let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+    let listener = TcpListener::bind(&addr).expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+    let server = ServerBuilder::new(echo_handler)
+        .logger(Logger::root(slog::Discard, o!()))
+        .options(TaskOptions::new().max_requests(1))
+        .build();
+    thread::spawn(move || tokio::run(server.serve_listener(listener)));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+    let mut responses = Vec::new();
+    client::send(
+        String::from("echo"),
+        json!(["built"]),
+        &mut msg_id,
+        &mut stream,
+    )
+    .and_then(|_| {
+        client::receive(&mut stream, |msg| {
+            responses.push(msg.data.d.clone());
+            Ok(())
+        })
+    })
+    .unwrap();
+    assert_eq!(responses, vec![json!(["built"])]);
+
+    // The connection is closed after the single allowed request.
+    let result = client::send(
+        String::from("echo"),
+        json!(["again"]),
+        &mut msg_id,
+        &mut stream,
+    )
+    .and_then(|_| client::receive(&mut stream, |_| Ok(())));
+    assert!(result.is_err());
+}
+
+#[test]
+fn connections_beyond_limit_wait_for_a_slot() {
+    let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+    let listener = TcpListener::bind(&addr).expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+    let server = ServerBuilder::new(echo_handler)
+        .logger(Logger::root(slog::Discard, o!()))
+        .max_connections(1, OverloadMode::Queue)
+        .build();
+    thread::spawn(move || tokio::run(server.serve_listener(listener)));
+
+    let call = |stream: &mut TcpStream, value| {
+        let mut msg_id = FastMessageId::new();
+        let mut responses = Vec::new();
+        client::send(String::from("echo"), json!([value]), &mut msg_id, stream)
+            .and_then(|_| {
+                client::receive(stream, |msg| {
+                    responses.push(msg.data.d.clone());
+                    Ok(())
+                })
+            })
+            .map(|_| responses)
+    };
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    assert_eq!(call(&mut first, 1).unwrap(), vec![json!([1])]);
+
+    // The second connection is not served while the first is open.
+    let mut second = TcpStream::connect(addr).unwrap();
+    second
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    assert!(call(&mut second, 2).is_err());
+
+    // Closing the first connection frees the slot, and the request already
+    // sent on the second connection is then answered.
+    drop(first);
+    second.set_read_timeout(None).unwrap();
+    let mut responses = Vec::new();
+    client::receive(&mut second, |msg| {
+        responses.push(msg.data.d.clone());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(responses, vec![json!([2])]);
+}
+
+#[test]
+fn heartbeats_sent_while_handler_is_slow() {
+    let slow_handler = |msg: &FastMessage, _log: &Logger| {
+        thread::sleep(Duration::from_millis(500));
+        Ok(vec![msg.reply(json!(["done"]))])
+    };
+    let addr = start_server(
+        slow_handler,
+        TaskOptions::new().heartbeat_interval(Duration::from_millis(50)),
+    );
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+    let mut responses = Vec::new();
+    client::send(String::from("slow"), json!([]), &mut msg_id, &mut stream)
+        .and_then(|_| {
+            client::receive(&mut stream, |msg| {
+                responses.push(msg.data.d.clone());
+                Ok(())
+            })
+        })
+        .unwrap();
+
+    let (result, heartbeats) = responses.split_last().unwrap();
+    assert_eq!(result, &json!(["done"]));
+    assert!(!heartbeats.is_empty());
+    assert!(heartbeats.iter().all(|d| d == &json!([])));
+}
+
+#[test]
+fn proxy_balances_and_reconnects() {
+    // Each upstream tags its responses and closes every connection after a
+    // single request, as if it had restarted, so the proxy must reconnect for
+    // every request after the first to each upstream.
+    let upstreams: Vec<SocketAddr> = ["a", "b"]
+        .iter()
+        .map(|tag| {
+            start_server(
+                move |msg: &FastMessage, _log: &Logger| {
+                    Ok(vec![msg.reply(json!([msg.data.d[0], tag]))])
+                },
+                TaskOptions::new().max_requests(1),
+            )
+        })
+        .collect();
+
+    let proxy = Proxy::new(upstreams);
+    let proxy_addr = start_server(
+        move |msg: &FastMessage, log: &Logger| proxy.handle(msg, log),
+        TaskOptions::new(),
+    );
+
+    let mut stream = TcpStream::connect(proxy_addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+    let mut responses = Vec::new();
+    for i in 0..4 {
+        client::send(
+            String::from("echo"),
+            json!([i]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .and_then(|_| {
+            client::receive(&mut stream, |msg| {
+                responses.push(msg.data.d.clone());
+                Ok(())
+            })
+        })
+        .unwrap();
+    }
+
+    assert_eq!(
+        responses,
+        vec![
+            json!([0, "a"]),
+            json!([1, "b"]),
+            json!([2, "a"]),
+            json!([3, "b"]),
+        ]
+    );
+}
+
+#[test]
+fn streamed_request_is_reassembled() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&requests);
+    let handler = move |msg: &FastMessage, log: &Logger| {
+        recorded.lock().unwrap().push(msg.data.d.clone());
+        echo_handler(msg, log)
+    };
+    let addr = start_server(handler, TaskOptions::new());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+
+    let items: Vec<_> = (0..100).map(|i| json!({ "key": i })).collect();
+    let sent = client::send_stream(
+        String::from("putobjects"),
+        stream::iter_ok(items.clone()),
+        64,
+        &mut msg_id,
+        &mut stream,
+    )
+    .unwrap();
+    // Sending the items in several frames takes more bytes than sending them
+    // all in one.
+    let single = FastMessage::data(
+        0,
+        FastMessageData::new(String::from("putobjects"), json!(items)),
+    );
+    let mut single_frame = BytesMut::new();
+    FastRpc::new()
+        .encode(vec![single], &mut single_frame)
+        .unwrap();
+    assert!(sent > single_frame.len());
+
+    let mut responses = Vec::new();
+    client::receive(&mut stream, |msg| {
+        responses.push(msg.data.d.clone());
+        Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(responses, vec![json!(items)]);
+    assert_eq!(*requests.lock().unwrap(), vec![json!(items)]);
+}
+
+#[test]
+fn oversized_streamed_request_closes_connection() {
+    let addr =
+        start_server(echo_handler, TaskOptions::new().max_reassembled_len(100));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+
+    // Each frame is within the limit, but together they exceed it.
+    let items: Vec<_> = (0..100).map(|i| json!({ "key": i })).collect();
+    client::send_stream(
+        String::from("putobjects"),
+        stream::iter_ok(items),
+        5,
+        &mut msg_id,
+        &mut stream,
+    )
+    .unwrap();
+
+    let result = client::receive(&mut stream, |_| Ok(()));
+    assert!(result.is_err());
+}
+
+#[test]
+fn too_many_partial_requests_close_connection() {
+    let addr =
+        start_server(echo_handler, TaskOptions::new().max_partial_requests(2));
+    let mut stream = TcpStream::connect(addr).unwrap();
+
+    // Start three streamed requests without finishing any of them.
+    let mut buf = BytesMut::new();
+    for id in 1..=3 {
+        let msg = FastMessage::data_continued(
+            id,
+            FastMessageData::new(String::from("putobjects"), json!([id])),
+        );
+        FastRpc::new().encode(vec![msg], &mut buf).unwrap();
+    }
+    stream.write_all(&buf).unwrap();
+
+    let mut read_buf = [0; 16];
+    assert_eq!(stream.read(&mut read_buf).unwrap(), 0);
+}
+
+#[test]
+fn quiesced_server_rejects_new_requests() {
+    let quiesce = Quiesce::new();
+    let addr =
+        start_server(echo_handler, TaskOptions::new().quiesce(quiesce.clone()));
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut client = BlockingFastClient::new(stream);
+
+    let mut responses = Vec::new();
+    client
+        .send(String::from("echo"), json!(["before"]))
+        .unwrap();
+    client
+        .receive(|msg| {
+            responses.push(msg.data.d.clone());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(responses, vec![json!(["before"])]);
+
+    quiesce.trigger();
+    client.send(String::from("echo"), json!(["after"])).unwrap();
+    let err = client.receive(|_| Ok(())).unwrap_err();
+    assert!(err.to_string().starts_with("ServerDraining"), "{}", err);
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Item {
+    id: u64,
+    name: String,
+}
+
+/// A handler for `yes` requests, which take a count of DATA messages to
+/// respond with, each carrying two items.
+fn yes_items_handler(
+    msg: &FastMessage,
+    _log: &Logger,
+) -> Result<Vec<FastMessage>, Error> {
+    let count = msg.data.d[0]["count"].as_u64().unwrap_or(0);
+    match msg.data.m.name.as_str() {
+        "yes" => Ok((0..count)
+            .map(|i| {
+                msg.reply(json!([
+                    { "id": 2 * i, "name": format!("item{}", 2 * i) },
+                    { "id": 2 * i + 1, "name": format!("item{}", 2 * i + 1) },
+                ]))
+            })
+            .collect()),
+        method => Err(Error::other(format!("Unsupported: {}", method))),
+    }
+}
+
+#[test]
+fn typed_stream_yields_each_data_element() {
+    let addr = start_server(yes_items_handler, TaskOptions::new());
+
+    let call = tokio::net::TcpStream::connect(&addr).and_then(|stream| {
+        let client = Client::new(stream);
+        client
+            .call_stream_typed::<Item>(
+                String::from("yes"),
+                json!([{ "count": 50 }]),
+            )
+            .collect()
+    });
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let items = runtime.block_on(call).unwrap();
+
+    assert_eq!(items.len(), 100);
+    for (i, item) in items.iter().enumerate() {
+        let id = i as u64;
+        assert_eq!(
+            *item,
+            Item {
+                id,
+                name: format!("item{}", id)
+            }
+        );
+    }
+}
+
+#[test]
+fn typed_stream_ends_with_server_error() {
+    let addr = start_server(yes_items_handler, TaskOptions::new());
+
+    let call = tokio::net::TcpStream::connect(&addr).and_then(|stream| {
+        let client = Client::new(stream);
+        client
+            .call_stream_typed::<Item>(String::from("no"), json!([]))
+            .collect()
+    });
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let err = runtime.block_on(call).unwrap_err();
+
+    let server_err = client::server_error(&err).unwrap();
+    assert_eq!(server_err.message, "Unsupported: no");
+}
+
+/// Start a Fast server that responds to each request with its own arguments
+/// after `delay`, without blocking the runtime in the meantime.
+fn start_delayed_echo_server(delay: Duration) -> SocketAddr {
+    serve_with(move |socket| {
+        server::make_task_async(
+            socket,
+            move |msg: &FastMessage, _log: &Logger| {
+                let reply = msg.reply(msg.data.d.clone());
+                Delay::new(Instant::now() + delay)
+                    .map_err(Error::other)
+                    .map(move |()| vec![reply])
+            },
+            None,
+            TaskOptions::new(),
+        )
+    })
+}
+
+#[test]
+fn cancel_all_fails_pending_calls() {
+    let addr = start_delayed_echo_server(Duration::from_secs(10));
+
+    let start = Instant::now();
+    let calls = tokio::net::TcpStream::connect(&addr).and_then(|stream| {
+        let client = Client::new(stream);
+        let calls: Vec<_> = (0..3)
+            .map(|i| {
+                client
+                    .call(String::from("echo"), json!([i]))
+                    .then(Ok::<_, Error>)
+            })
+            .collect();
+        assert_eq!(client.cancel_all(), 3);
+        assert_eq!(client.cancel_all(), 0);
+        future::join_all(calls)
+    });
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let results = runtime.block_on(calls).unwrap();
+
+    assert_eq!(results.len(), 3);
+    for result in results {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        assert!(err.to_string().starts_with("Cancelled"), "{}", err);
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn inflight_ids_lists_calls_awaiting_responses() {
+    // Reply to each request after the number of milliseconds it asks for,
+    // one request at a time.
+    let addr = start_server(
+        |msg: &FastMessage, log: &Logger| {
+            let delay = msg.data.d[0].as_u64().unwrap_or(0);
+            thread::sleep(Duration::from_millis(delay));
+            echo_handler(msg, log)
+        },
+        TaskOptions::new(),
+    );
+
+    let calls = tokio::net::TcpStream::connect(&addr).and_then(|stream| {
+        let client = Client::new(stream);
+        let fast = client.call(String::from("echo"), json!([100]));
+        let slow = client.call(String::from("echo"), json!([1000]));
+        let ids = client.inflight_ids();
+        assert_eq!(ids.len(), 2);
+
+        fast.and_then(move |_| {
+            let remaining = client.inflight_ids();
+            assert_eq!(remaining.len(), 1);
+            assert!(ids.contains(&remaining[0]));
+            slow.map(move |_| client.inflight_ids())
+        })
+    });
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert!(runtime.block_on(calls).unwrap().is_empty());
+}
+
+#[test]
+fn client_routes_overlapping_calls_by_id() {
+    // Reply to the requests in the reverse of the order they were sent, so
+    // that each call only gets its own response if the client routes them by
+    // id rather than in the order they were made.
+    let addr = serve_with(|socket| {
+        let (tx, rx) = FastRpc::new().framed(socket).split();
+        rx.map(stream::iter_ok)
+            .flatten()
+            .take(3)
+            .collect()
+            .and_then(move |requests| {
+                let responses = requests
+                    .iter()
+                    .rev()
+                    .flat_map(|msg: &FastMessage| {
+                        match msg.data.m.name.as_str() {
+                            "echo" => vec![
+                                msg.reply(msg.data.d.clone()),
+                                FastMessage::end(
+                                    msg.id,
+                                    msg.data.m.name.clone(),
+                                ),
+                            ],
+                            method => {
+                                let err = FastMessageServerError::new(
+                                    "FastError",
+                                    &format!("Unsupported: {}", method),
+                                );
+                                vec![FastMessage::error(
+                                    msg.id,
+                                    FastMessageData::new(
+                                        method.to_string(),
+                                        serde_json::to_value(err).unwrap(),
+                                    ),
+                                )]
+                            }
+                        }
+                    })
+                    .collect();
+                tx.send(responses)
+            })
+            .map(|_| ())
+            .map_err(|e| panic!("test server failed: {}", e))
+    });
+
+    let calls = tokio::net::TcpStream::connect(&addr).and_then(|stream| {
+        let client = Client::new(stream);
+        let first = client.call(String::from("echo"), json!(["first"]));
+        let second = client.call(String::from("echo"), json!(["second"]));
+        let failed = client.call(String::from("fail"), json!([]));
+        first.join3(second, failed.then(Ok))
+    });
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (first, second, failed) = runtime.block_on(calls).unwrap();
+
+    let data = |msgs: Vec<FastMessage>| -> Vec<serde_json::Value> {
+        msgs.into_iter().map(|msg| msg.data.d).collect()
+    };
+    assert_eq!(data(first), vec![json!(["first"])]);
+    assert_eq!(data(second), vec![json!(["second"])]);
+    let err = failed.unwrap_err();
+    let server_err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<FastMessageServerError>())
+        .unwrap();
+    assert_eq!(server_err.message, "Unsupported: fail");
+}
+
+#[test]
+fn shutdown_delivers_outstanding_responses() {
+    let (started_tx, started_rx) = mpsc::channel();
+    let started_tx = Arc::new(Mutex::new(started_tx));
+    let handler = move |msg: &FastMessage, log: &Logger| {
+        started_tx.lock().unwrap().send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        echo_handler(msg, log)
+    };
+    let shutdown = Shutdown::new();
+    let addr =
+        start_server(handler, TaskOptions::new().shutdown(shutdown.clone()));
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut client = BlockingFastClient::new(stream);
+
+    client
+        .send(String::from("echo"), json!(["inflight"]))
+        .unwrap();
+    started_rx.recv().unwrap();
+    shutdown.trigger();
+
+    let mut responses = Vec::new();
+    client
+        .receive(|msg| {
+            responses.push(msg.data.d.clone());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(responses, vec![json!(["inflight"])]);
+
+    // The connection is closed once the outstanding response has been sent.
+    let _ = client.send(String::from("echo"), json!(["after"]));
+    assert!(client.receive(|_| Ok(())).is_err());
+}
+
+#[test]
+fn async_handler_responses_are_framed() {
+    let addr = serve_with(|socket| {
+        server::make_task_async(
+            socket,
+            |msg: &FastMessage, _log: &Logger| {
+                let reply = msg.reply(msg.data.d.clone());
+                let delay = Duration::from_millis(if msg.data.d[0] == 0 {
+                    100
+                } else {
+                    10
+                });
+                Delay::new(Instant::now() + delay)
+                    .map_err(Error::other)
+                    .map(move |()| vec![reply])
+            },
+            None,
+            TaskOptions::new(),
+        )
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut msg_id = FastMessageId::new();
+
+    // Send both requests before reading either response so that the slower
+    // first request is still pending while the second is handled.
+    for i in 0..2 {
+        client::send(
+            String::from("echo"),
+            json!([i]),
+            &mut msg_id,
+            &mut stream,
+        )
+        .unwrap();
+    }
+    // Both responses may arrive in one read, so keep what follows the first
+    // for the next call.
+    let mut buf = Vec::new();
+    for i in 0..2 {
+        let mut responses = Vec::new();
+        client::receive_buffered(&mut stream, &mut buf, |msg| {
+            responses.push(msg.data.d.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(responses, vec![json!([i])]);
+    }
+}
+
+#[test]
+fn retrying_async_handler_hides_transient_failure() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let handler_attempts = Arc::clone(&attempts);
+    let handler = move |msg: &FastMessage, _log: &Logger| {
+        if handler_attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            future::err(Error::new(ErrorKind::Interrupted, "backend hiccup"))
+        } else {
+            future::ok(vec![msg.reply(msg.data.d.clone())])
+        }
+    };
+    let handler = server::retrying_async(
+        handler,
+        |e| e.kind() == ErrorKind::Interrupted,
+        2,
+        Duration::from_millis(50),
+    );
+    let addr = serve_with(move |socket| {
+        server::make_task_async(
+            socket,
+            handler.clone(),
+            None,
+            TaskOptions::new(),
+        )
+    });
+    let mut client = BlockingFastClient::new(TcpStream::connect(addr).unwrap());
+
+    let mut statuses = Vec::new();
+    client.send(String::from("echo"), json!(["hello"])).unwrap();
+    client
+        .receive(|msg| {
+            statuses.push((msg.status.clone(), msg.data.d.clone()));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(statuses, vec![(FastMessageStatus::Data, json!(["hello"]))]);
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+}
+
+/// Sets its flag when dropped.
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn request_deadline_drops_slow_response_future() {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let handler_dropped = Arc::clone(&dropped);
+    let options =
+        TaskOptions::new().request_deadline(Duration::from_millis(100));
+    let addr = serve_with(move |socket| {
+        let dropped = Arc::clone(&handler_dropped);
+        server::make_task_async(
+            socket,
+            move |msg: &FastMessage, _log: &Logger| {
+                let flag = DropFlag(Arc::clone(&dropped));
+                let reply = msg.reply(msg.data.d.clone());
+                Delay::new(Instant::now() + Duration::from_secs(10))
+                    .map_err(Error::other)
+                    .map(move |()| {
+                        drop(flag);
+                        vec![reply]
+                    })
+            },
+            None,
+            options.clone(),
+        )
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+
+    let start = Instant::now();
+    client::send(
+        String::from("sleep"),
+        json!([]),
+        &mut FastMessageId::new(),
+        &mut stream,
+    )
+    .unwrap();
+    let err = client::receive(&mut stream, |_| Ok(())).unwrap_err();
+    let server_err = client::server_error(&err).unwrap();
+    assert_eq!(server_err.name, "DeadlineExceeded");
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn queue_mode_delays_async_requests_over_limit() {
+    let delay = Duration::from_millis(100);
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let (handler_running, handler_max) =
+        (Arc::clone(&running), Arc::clone(&max_running));
+    let options = TaskOptions::new()
+        .in_flight_limit(InFlightLimit::new(1, OverloadMode::Queue));
+    let addr = serve_with(move |socket| {
+        let running = Arc::clone(&handler_running);
+        let max_running = Arc::clone(&handler_max);
+        server::make_task_async(
+            socket,
+            move |msg: &FastMessage, _log: &Logger| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                let running = Arc::clone(&running);
+                let reply = msg.reply(msg.data.d.clone());
+                Delay::new(Instant::now() + delay)
+                    .map_err(Error::other)
+                    .map(move |()| {
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        vec![reply]
+                    })
+            },
+            None,
+            options.clone(),
+        )
+    });
+
+    // Requests in the same batch and on other connections all wait their
+    // turn rather than being rejected or blocking the runtime.
+    let start = Instant::now();
+    let clients: Vec<_> = (0..2)
+        .map(|c| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let mut msg_id = FastMessageId::new();
+                for i in 0..2 {
+                    client::send(
+                        String::from("echo"),
+                        json!([c, i]),
+                        &mut msg_id,
+                        &mut stream,
+                    )
+                    .unwrap();
+                }
+                let mut buf = Vec::new();
+                (0..2)
+                    .map(|_| {
+                        let mut responses = Vec::new();
+                        client::receive_buffered(&mut stream, &mut buf, |msg| {
+                            responses.push(msg.data.d.clone());
+                            Ok(())
+                        })
+                        .map(|_| responses)
+                    })
+                    .collect::<Result<Vec<_>, Error>>()
+            })
+        })
+        .collect();
+
+    for (c, client) in clients.into_iter().enumerate() {
+        let responses = client.join().unwrap().unwrap();
+        assert_eq!(responses, vec![vec![json!([c, 0])], vec![json!([c, 1])]]);
+    }
+    assert!(start.elapsed() >= delay * 4);
+    assert_eq!(max_running.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn shed_mode_rejects_requests_over_limit() {
+    let delay = Duration::from_millis(500);
+    let options = TaskOptions::new()
+        .in_flight_limit(InFlightLimit::new(1, OverloadMode::Shed));
+    let addr = serve_with(move |socket| {
+        server::make_task_async(
+            socket,
+            move |msg: &FastMessage, _log: &Logger| {
+                let reply = msg.reply(msg.data.d.clone());
+                Delay::new(Instant::now() + delay)
+                    .map_err(Error::other)
+                    .map(move |()| vec![reply])
+            },
+            None,
+            options.clone(),
+        )
+    });
+
+    // Send a request on each of several connections at once. Only one of them
+    // fits within the limit, and the others must be turned away without
+    // waiting for it to finish.
+    let start = Instant::now();
+    let clients: Vec<_> = (0..3)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                client::send(
+                    String::from("echo"),
+                    json!([i]),
+                    &mut FastMessageId::new(),
+                    &mut stream,
+                )
+                .unwrap();
+                let result = client::receive(&mut stream, |_| Ok(()));
+                (result, start.elapsed())
+            })
+        })
+        .collect();
+
+    let mut handled = 0;
+    let mut shed = 0;
+    for client in clients {
+        let (result, elapsed) = client.join().unwrap();
+        match result {
+            Ok(_) => {
+                assert!(elapsed >= delay);
+                handled += 1;
+            }
+            Err(err) => {
+                let server_err = err
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<FastMessageServerError>())
+                    .unwrap();
+                assert_eq!(server_err.name, "ServerBusy");
+                assert!(elapsed < delay / 2);
+                shed += 1;
+            }
+        }
+    }
+    assert_eq!((handled, shed), (1, 2));
+}
+
+#[test]
+fn handler_state_is_shared_between_connections() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let state = Arc::clone(&counter);
+    let addr = serve_with(move |socket| {
+        server::make_task_with_state(
+            socket,
+            Arc::clone(&state),
+            |msg: &FastMessage, count: &AtomicUsize, _log: &Logger| {
+                let count = count.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(vec![msg.reply(json!([count]))])
+            },
+            None,
+        )
+    });
+
+    let mut expected = 1;
+    for _ in 0..2 {
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = BlockingFastClient::new(stream);
+        for _ in 0..2 {
+            let mut responses = Vec::new();
+            client
+                .send(String::from("count"), json!([]))
+                .and_then(|_| {
+                    client.receive(|msg| {
+                        responses.push(msg.data.d.clone());
+                        Ok(())
+                    })
+                })
+                .unwrap();
+            assert_eq!(responses, vec![json!([expected])]);
+            expected += 1;
+        }
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 4);
+}
+
+#[cfg(unix)]
+#[test]
+fn echo_over_unix_socket() {
+    let path = env::temp_dir()
+        .join(format!("fast-rpc-server-test-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).expect("failed to bind");
+
+    thread::spawn(move || {
+        tokio::run(
+            listener
+                .incoming()
+                .map_err(|e| panic!("failed to accept socket: {}", e))
+                .for_each(|socket| {
+                    tokio::spawn(server::make_task_generic(
+                        socket,
+                        echo_handler,
+                        None,
+                        TaskOptions::new(),
+                    ));
+                    Ok(())
+                }),
+        )
+    });
+
+    let call = client::connect_unix(path.clone()).and_then(|client| {
+        client.call(String::from("echo"), json!(["over", "uds"]))
+    });
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let responses = runtime.block_on(call).unwrap();
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].data.d, json!(["over", "uds"]));
+}
+
+#[test]
+fn unknown_method_is_reported_as_method_not_found() {
+    let router = Router::new().add("echo", echo_handler);
+    let addr = start_server(
+        move |msg: &FastMessage, log: &Logger| router.handle(msg, log),
+        TaskOptions::new(),
+    );
+    let mut client = BlockingFastClient::new(TcpStream::connect(addr).unwrap());
+
+    let err = client
+        .send(String::from("missing"), json!([]))
+        .and_then(|_| client.receive(|_| Ok(())))
+        .unwrap_err();
+    let server_err = client::server_error(&err).unwrap();
+    assert_eq!(server_err.name, "FastError.MethodNotFound");
+    assert_eq!(server_err.message, "unsupported method: missing");
+}
+
+#[test]
+fn router_caps_responses_per_method() {
+    let router = Router::new()
+        .add_limited(
+            "echo",
+            ResponseLimit::new().max_frames(100).max_bytes(1024 * 1024),
+            echo_handler,
+        )
+        .add_limited(
+            "yes",
+            ResponseLimit::new().max_frames(5),
+            yes_items_handler,
+        );
+    let addr = start_server(
+        move |msg: &FastMessage, log: &Logger| router.handle(msg, log),
+        TaskOptions::new(),
+    );
+    let mut client = BlockingFastClient::new(TcpStream::connect(addr).unwrap());
+
+    let mut responses = Vec::new();
+    let payload = json!(["x".repeat(64 * 1024)]);
+    client.send(String::from("echo"), payload.clone()).unwrap();
+    client
+        .receive(|msg| {
+            responses.push(msg.data.d.clone());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(responses, vec![payload]);
+
+    let mut frames = 0;
+    client
+        .send(String::from("yes"), json!([{ "count": 5 }]))
+        .unwrap();
+    client
+        .receive(|_| {
+            frames += 1;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(frames, 5);
+
+    let err = client
+        .send(String::from("yes"), json!([{ "count": 6 }]))
+        .and_then(|_| client.receive(|_| Ok(())))
+        .unwrap_err();
+    let server_err = client::server_error(&err).unwrap();
+    assert_eq!(server_err.name, "ResponseTooLarge");
+}
+
+#[test]
+fn pool_shares_connections_between_concurrent_calls() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    let addr = serve_with(move |socket| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        server::make_task(socket, echo_handler, None)
+    });
+    let pool = client::Pool::new(addr, 2).unwrap();
+
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for j in 0..5 {
+                    let mut conn = pool.get().unwrap();
+                    let responses =
+                        conn.call(String::from("echo"), json!([i, j])).unwrap();
+                    assert_eq!(responses.len(), 1);
+                    assert_eq!(responses[0].data.d, json!([i, j]));
+                }
+            })
+        })
+        .collect();
+    threads.into_iter().for_each(|t| t.join().unwrap());
+
+    // All 40 calls were made over at most two connections.
+    assert!(pool.open() <= 2);
+    assert!(accepted.load(Ordering::SeqCst) <= 2);
+}
+
+#[test]
+fn pool_does_not_reuse_failed_connections() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    let addr = serve_with(move |socket| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        server::make_task_with_options(
+            socket,
+            echo_handler,
+            None,
+            TaskOptions::new().max_requests(1),
+        )
+    });
+    let pool = client::Pool::new(addr, 1).unwrap();
+
+    // The server closes each connection after one request, so the second call
+    // on a connection fails and the connection is discarded.
+    let mut conn = pool.get().unwrap();
+    conn.call(String::from("echo"), json!([1])).unwrap();
+    assert!(conn.call(String::from("echo"), json!([2])).is_err());
+    drop(conn);
+    assert_eq!(pool.open(), 0);
+
+    let mut conn = pool.get().unwrap();
+    let responses = conn.call(String::from("echo"), json!([3])).unwrap();
+    assert_eq!(responses[0].data.d, json!([3]));
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pool_discards_idle_connections_closed_by_server() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    let addr = serve_with(move |socket| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        server::make_task_with_options(
+            socket,
+            echo_handler,
+            None,
+            TaskOptions::new().idle_timeout(Duration::from_millis(100)),
+        )
+    });
+    let pool = client::Pool::new(addr, 1).unwrap();
+    pool.get()
+        .unwrap()
+        .call(String::from("echo"), json!([1]))
+        .unwrap();
+
+    // The server closes the idle pooled connection, so the next call is made
+    // on a new one.
+    thread::sleep(Duration::from_millis(500));
+    let responses = pool
+        .get()
+        .unwrap()
+        .call(String::from("echo"), json!([2]))
+        .unwrap();
+    assert_eq!(responses[0].data.d, json!([2]));
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pool_of_zero_connections_is_rejected() {
+    let addr = "127.0.0.1:2030".parse::<SocketAddr>().unwrap();
+    let err = client::Pool::new(addr, 0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// A stream that counts the bytes read from and written to the stream it
+/// wraps.
+struct CountingStream {
+    inner: tokio::net::TcpStream,
+    read: Arc<AtomicUsize>,
+    written: Arc<AtomicUsize>,
+}
+
+impl std::io::Read for CountingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+impl std::io::Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.fetch_add(n, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsyncRead for CountingStream {}
+
+impl AsyncWrite for CountingStream {
+    fn shutdown(&mut self) -> Poll<(), std::io::Error> {
+        AsyncWrite::shutdown(&mut self.inner)
+    }
+}
+
+/// Echo a large, highly compressible payload through a client that negotiates
+/// compression with a server started with `options`, returning the number of
+/// bytes the client wrote and read.
+fn echo_with_negotiation(options: TaskOptions) -> (usize, usize) {
+    let addr = start_server(echo_handler, options);
+    let read = Arc::new(AtomicUsize::new(0));
+    let written = Arc::new(AtomicUsize::new(0));
+    let (client_read, client_written) =
+        (Arc::clone(&read), Arc::clone(&written));
+    let payload = json!(["x".repeat(1024 * 1024)]);
+    let args = payload.clone();
+
+    let call = tokio::net::TcpStream::connect(&addr)
+        .and_then(move |inner| {
+            Client::with_negotiated_compression(CountingStream {
+                inner,
+                read: client_read,
+                written: client_written,
+            })
+        })
+        .and_then(move |client| client.call(String::from("echo"), args));
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let responses = runtime.block_on(call).unwrap();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].data.d, payload);
+
+    (written.load(Ordering::SeqCst), read.load(Ordering::SeqCst))
+}
+
+#[test]
+fn negotiated_compression_compresses_frames_both_ways() {
+    let (written, read) =
+        echo_with_negotiation(TaskOptions::new().negotiate_compression(true));
+    assert!(written < 64 * 1024, "{} bytes written", written);
+    assert!(read < 64 * 1024, "{} bytes read", read);
+}
+
+#[test]
+fn frames_are_uncompressed_without_negotiation_support() {
+    let (written, read) = echo_with_negotiation(TaskOptions::new());
+    assert!(written > 1024 * 1024, "{} bytes written", written);
+    assert!(read > 1024 * 1024, "{} bytes read", read);
+}
+
+/// Send a request whose JSON payload would not survive being parsed and
+/// encoded again to a server started with `options` whose handler answers with
+/// `echo_reply`, returning the request frame and the response bytes read in
+/// its place.
+fn echo_raw_payload(options: TaskOptions) -> (BytesMut, Vec<u8>) {
+    let addr = start_server(
+        |msg: &FastMessage, _log: &Logger| Ok(vec![msg.echo_reply()]),
+        options,
+    );
+    let mut request = FastMessage::data(
+        1,
+        FastMessageData::new(String::from("echo"), json!(null)),
+    );
+    let raw =
+        r#"{"m": {"name": "echo", "uts": 1}, "d": [1.50, {"b": 1, "a": 2}]}"#;
+    request.payload = Some(raw.as_bytes().to_vec());
+    let mut frame = BytesMut::new();
+    FastRpc::new().encode(vec![request], &mut frame).unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&frame).unwrap();
+    let mut response = vec![0; frame.len()];
+    stream.read_exact(&mut response).unwrap();
+    (frame, response)
+}
+
+#[test]
+fn echo_reply_copies_raw_payload() {
+    let (frame, response) =
+        echo_raw_payload(TaskOptions::new().raw_payloads(true));
+    assert_eq!(&response[..], &frame[..]);
+
+    let (frame, response) = echo_raw_payload(TaskOptions::new());
+    assert_ne!(&response[..], &frame[..]);
+}
@@ -0,0 +1,203 @@
+// Copyright 2020 Joyent, Inc.
+
+#![cfg(feature = "tls")]
+
+use std::fs::File;
+use std::io::{BufReader, Error};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use serde_json::{json, Value};
+use slog::Logger;
+use tokio::codec::Decoder;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, ClientConfig, NoClientAuth, RootCertStore,
+    ServerConfig,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use fast_rpc::protocol::{FastMessage, FastMessageData, FastRpc};
+use fast_rpc::server::{self, PeerIdentity, TaskOptions};
+
+fn echo_handler(
+    msg: &FastMessage,
+    _log: &Logger,
+) -> Result<Vec<FastMessage>, Error> {
+    Ok(vec![msg.reply(msg.data.d.clone())])
+}
+
+fn open_fixture(name: &str) -> BufReader<File> {
+    let path = [env!("CARGO_MANIFEST_DIR"), "/tests/tls/", name].concat();
+    BufReader::new(File::open(path).expect("failed to open fixture"))
+}
+
+/// Start a Fast server on an unused local port that accepts TLS connections
+/// using the self-signed certificate for localhost in tests/tls and return
+/// the address it is listening on. If `require_client_cert` is set, clients
+/// must present a certificate signed by the test client CA. The `whoami`
+/// method returns the identity of the client.
+fn start_tls_server(require_client_cert: bool) -> SocketAddr {
+    let certs = pemfile::certs(&mut open_fixture("localhost.pem")).unwrap();
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut open_fixture("localhost.key"))
+            .unwrap();
+    let client_auth = if require_client_cert {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add_pem_file(&mut open_fixture("client-ca.pem"))
+            .unwrap();
+        AllowAnyAuthenticatedClient::new(roots)
+    } else {
+        NoClientAuth::new()
+    };
+    let mut config = ServerConfig::new(client_auth);
+    config.set_single_cert(certs, keys.remove(0)).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+    let listener = TcpListener::bind(&addr).expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        tokio::run(
+            listener
+                .incoming()
+                .map_err(|e| panic!("failed to accept socket: {}", e))
+                .for_each(move |socket| {
+                    let task = acceptor
+                        .accept(socket)
+                        .map_err(|e| eprintln!("TLS handshake failed: {}", e))
+                        .and_then(|socket| {
+                            let identity = server::peer_identity(&socket);
+                            let handler =
+                                move |msg: &FastMessage, log: &Logger| match msg
+                                    .data
+                                    .m
+                                    .name
+                                    .as_str()
+                                {
+                                    "whoami" => {
+                                        Ok(vec![msg.reply(json!([identity
+                                            .as_ref()
+                                            .map(identity_json)]))])
+                                    }
+                                    _ => echo_handler(msg, log),
+                                };
+                            server::make_task_tls(
+                                socket,
+                                handler,
+                                None,
+                                TaskOptions::new(),
+                            )
+                        });
+                    tokio::spawn(task);
+                    Ok(())
+                }),
+        )
+    });
+
+    addr
+}
+
+fn identity_json(identity: &PeerIdentity) -> Value {
+    json!({
+        "common_name": identity.common_name,
+        "dns_names": identity.dns_names,
+    })
+}
+
+/// Returns a client configuration that trusts the test CA, presenting the
+/// client certificate in tests/tls if `with_cert` is set.
+fn client_config(with_cert: bool) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_pem_file(&mut open_fixture("ca.pem"))
+        .unwrap();
+    if with_cert {
+        let certs = pemfile::certs(&mut open_fixture("client.pem")).unwrap();
+        let mut keys =
+            pemfile::pkcs8_private_keys(&mut open_fixture("client.key"))
+                .unwrap();
+        config.set_single_client_cert(certs, keys.remove(0));
+    }
+    config
+}
+
+/// Call `method` with `args` on the server at `addr` over a TLS connection
+/// made with `config`, returning the DATA messages of the response.
+fn call(
+    addr: SocketAddr,
+    config: ClientConfig,
+    method: &str,
+    args: Value,
+) -> Result<Vec<FastMessage>, Error> {
+    let connector = TlsConnector::from(Arc::new(config));
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    let request =
+        FastMessage::data(1, FastMessageData::new(String::from(method), args));
+    let call = TcpStream::connect(&addr)
+        .and_then(move |stream| connector.connect(domain, stream))
+        .and_then(move |stream| {
+            FastRpc::new().framed(stream).send(vec![request])
+        })
+        .and_then(|framed| {
+            framed
+                .map(stream::iter_ok)
+                .flatten()
+                .take_while(|msg| Ok(!msg.is_terminal()))
+                .collect()
+        });
+
+    Runtime::new().unwrap().block_on(call)
+}
+
+#[test]
+fn echo_over_tls() {
+    let addr = start_tls_server(false);
+
+    let responses =
+        call(addr, client_config(false), "echo", json!(["secret"])).unwrap();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].id, 1);
+    assert_eq!(responses[0].data.d, json!(["secret"]));
+}
+
+#[test]
+fn client_certificate_identity_is_available_to_handler() {
+    let addr = start_tls_server(true);
+
+    let responses =
+        call(addr, client_config(true), "whoami", json!([])).unwrap();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(
+        responses[0].data.d,
+        json!([{
+            "common_name": "fast-client",
+            "dns_names": ["fast-client.example.com"],
+        }])
+    );
+}
+
+#[test]
+fn client_without_certificate_is_rejected() {
+    let addr = start_tls_server(true);
+
+    assert!(call(addr, client_config(false), "echo", json!([])).is_err());
+}
+
+#[test]
+fn identity_is_absent_without_client_auth() {
+    let addr = start_tls_server(false);
+
+    let responses =
+        call(addr, client_config(true), "whoami", json!([])).unwrap();
+    assert_eq!(responses[0].data.d, json!([null]));
+}